@@ -0,0 +1,70 @@
+//! Benchmarks of the row-parallel `Processing` and `Distortion` operations on a 12MP image, each one run on a single thread
+//! and on the whole processing thread pool to show the speedup.
+//!
+//! Run with `cargo bench`, optionally limiting the pool with `IMAGE_THREADS`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::{Rgb, RgbImage};
+use rayon::ThreadPoolBuilder;
+use rust101_project::core::THREAD_BUDGET_VAR;
+use rust101_project::images::distortion::{Axis, Distortion};
+use rust101_project::images::processing::{Processing, TrimOptions};
+
+// 4000x3000 = 12MP, the size of a typical phone camera photo
+const WIDTH: u32 = 4000;
+const HEIGHT: u32 = 3000;
+
+fn test_image() -> RgbImage {
+    RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        // Gradient with a black frame around it, so that trim has something to cut off
+        if x < 100 || y < 100 || x >= WIDTH - 100 || y >= HEIGHT - 100 {
+            Rgb([0, 0, 0])
+        } else {
+            Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+        }
+    })
+}
+
+fn thread_counts() -> Vec<usize> {
+    let budget = std::env::var(THREAD_BUDGET_VAR)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+
+    if budget > 1 {
+        vec![1, budget]
+    } else {
+        vec![1]
+    }
+}
+
+fn bench_processing(c: &mut Criterion) {
+    let image = test_image();
+    let mut group = c.benchmark_group("processing_12mp");
+    group.sample_size(10);
+
+    for threads in thread_counts() {
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+
+        group.bench_with_input(BenchmarkId::new("negative_basic", threads), &threads, |b, _| {
+            let mut buf = image.clone();
+            b.iter(|| pool.install(|| Processing::negative_basic(&mut buf)))
+        });
+        group.bench_with_input(BenchmarkId::new("wobble", threads), &threads, |b, _| {
+            b.iter(|| pool.install(|| Distortion::wobble(&image, 100, Axis::Horizontal, 42)))
+        });
+        group.bench_with_input(BenchmarkId::new("rotate", threads), &threads, |b, _| {
+            b.iter(|| pool.install(|| Processing::rotate(&image, 30.0)))
+        });
+        group.bench_with_input(BenchmarkId::new("crop_image", threads), &threads, |b, _| {
+            b.iter(|| pool.install(|| Processing::crop_image(&image, 500, 500, 3000, 2000)))
+        });
+        group.bench_with_input(BenchmarkId::new("remove_borders", threads), &threads, |b, _| {
+            b.iter(|| pool.install(|| Processing::remove_borders(&image, &TrimOptions::default())))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_processing);
+criterion_main!(benches);
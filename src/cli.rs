@@ -0,0 +1,53 @@
+use crate::images::ops::{OpRegistry, PipelineStep};
+use crate::images::{load_image_buffer, save_image_buffer};
+
+/// Usage of the command line, the output format is given by the extension of the output file.
+pub const USAGE: &str = "usage:
+  rust101-project                                          serve the HTTP API
+  rust101-project ops                                      list the operations and their parameters
+  rust101-project <op> <input> <output> [name=value ...]   apply an operation to an image file
+  rust101-project pipeline <steps.json> <input> <output>   apply the steps of a pipeline, as for POST /pipeline";
+
+/// Run the operations of the registry on image files, as given by the command line arguments.
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let ops = OpRegistry::with_builtins();
+
+    match args {
+        [command] if command == "ops" => {
+            for schema in ops.schemas() {
+                println!("{}: {}", schema.name, schema.description);
+                for param in schema.params {
+                    let required = if param.required { ", required" } else { "" };
+                    println!("    {} ({:?}{required}): {}", param.name, param.kind, param.description);
+                }
+            }
+            Ok(())
+        }
+        [command, steps, input, output] if command == "pipeline" => {
+            let mut steps: Vec<PipelineStep> = serde_json::from_str(&std::fs::read_to_string(steps)?)?;
+            for (i, seed) in ops.seed_pipeline(&mut steps)?.into_iter().enumerate() {
+                if let Some(seed) = seed {
+                    println!("step {} seed={seed}", i + 1);
+                }
+            }
+
+            let image = load_image_buffer(input)?;
+            let mut frames = ops.run_pipeline(vec![image], &steps)?;
+            save_image_buffer(output, frames.remove(0))
+        }
+        [name, input, output, params @ ..] => {
+            let pairs = params
+                .iter()
+                .map(|param| param.split_once('=').ok_or_else(|| anyhow::anyhow!("parameter {param} is not name=value")))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let mut params = ops.parse_params(name, pairs)?;
+            if let Some(seed) = ops.seed(name, &mut params)? {
+                println!("seed={seed}");
+            }
+
+            let image = load_image_buffer(input)?;
+            save_image_buffer(output, ops.apply(name, &image, &params)?)
+        }
+        _ => anyhow::bail!("{USAGE}"),
+    }
+}
@@ -2,12 +2,39 @@ use std::{fs::File, io::Cursor};
 
 use reqwest::Client;
 
+use crate::images::hashing::HashIndex;
+
 /// Boxed trait obj error type for nn module
 pub type DynError = Box<dyn std::error::Error>;
 
 /// Bounding box defined as `[x_top_left, y_top_left, x_bottom_right, y_bottom_right]`.
 pub type Bbox = [f32; 4];
 
+/// Detected object with **relative** coordinates, as the `Bbox` of the face models.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection {
+    pub bbox: Bbox,
+    pub confidence: f32,
+    /// Relative `[x, y]` points of interest (e.g. eyes, nose and mouth corners), empty when the model has none.
+    pub landmarks: Vec<[f32; 2]>,
+}
+
+impl From<(Bbox, f32)> for Detection {
+    fn from((bbox, confidence): (Bbox, f32)) -> Self {
+        Self {
+            bbox,
+            confidence,
+            landmarks: Vec::new(),
+        }
+    }
+}
+
+/// Environment variable holding the number of threads used for image processing.
+pub const THREAD_BUDGET_VAR: &str = "IMAGE_THREADS";
+
+/// Environment variable holding the path of the file the near-duplicate hash index is persisted to.
+pub const HASH_INDEX_VAR: &str = "HASH_INDEX";
+
 pub async fn download_file(client: &Client, url: &str, filepath: impl AsRef<std::path::Path>) -> Result<(), DynError> {
     let resp = client.get(url).send().await?;
 
@@ -17,3 +44,31 @@ pub async fn download_file(client: &Client, url: &str, filepath: impl AsRef<std:
 
     Ok(())
 }
+
+/// Initialize the global rayon pool shared by every request, so that concurrent requests queue their
+/// work on a fixed number of threads instead of each one grabbing all the cpus.
+/// The size is read from `IMAGE_THREADS`, falling back to the number of available cpus.
+pub fn init_thread_pool() -> anyhow::Result<usize> {
+    let threads = match std::env::var(THREAD_BUDGET_VAR) {
+        Ok(value) => value.parse::<usize>()?,
+        Err(_) => std::thread::available_parallelism()?.get(),
+    };
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("processing-{i}"))
+        .build_global()?;
+
+    println!("Initialized processing thread pool with {threads} threads");
+
+    Ok(threads)
+}
+
+/// Open the hash index persisted to the file given by `HASH_INDEX`, or an index kept only in memory
+/// when the variable is not set.
+pub fn open_hash_index() -> anyhow::Result<HashIndex> {
+    match std::env::var(HASH_INDEX_VAR) {
+        Ok(path) => HashIndex::open(path),
+        Err(_) => Ok(HashIndex::default()),
+    }
+}
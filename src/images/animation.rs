@@ -0,0 +1,262 @@
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+    },
+    guess_format, AnimationDecoder, Delay, DynamicImage, Frame, Frames, ImageFormat,
+};
+use std::{io::Cursor, str::FromStr};
+
+use super::conversion::Conversion;
+use super::get_image_as_bytes;
+
+/// Container format of an animation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnimationFormat {
+    Gif,
+    Apng,
+}
+
+impl AnimationFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            AnimationFormat::Gif => "image/gif",
+            AnimationFormat::Apng => "image/apng",
+        }
+    }
+}
+
+impl FromStr for AnimationFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "gif" => Ok(AnimationFormat::Gif),
+            "apng" | "png" => Ok(AnimationFormat::Apng),
+            other => anyhow::bail!("unknown animation format {other}"),
+        }
+    }
+}
+
+/// Single frame of an animation, shown for `delay_ms` milliseconds.
+#[derive(Debug, Clone)]
+pub struct AnimationFrame {
+    pub image: DynamicImage,
+    pub delay_ms: u32,
+}
+
+/// Sequence of frames decoded from an animated GIF or APNG, or the single frame of a still image.
+/// Animated frames are full RGBA8 canvases, the partial frames of the file already composed.
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    /// Format of the decoded animation, `None` for still images.
+    pub format: Option<AnimationFormat>,
+}
+
+impl Animation {
+    /// Largest number of pixels of all the frames together, about 400 MB of RGBA8 canvases.
+    pub const MAX_PIXELS: u64 = 100_000_000;
+
+    /// Decode all the frames of an animated GIF or APNG, other images are decoded as a single frame by
+    /// `Conversion::decode`, which falls back to the extension of `file_name` for formats without a signature.
+    /// Animations of more than `MAX_PIXELS` pixels in all their frames are rejected.
+    pub fn decode(data: &[u8], file_name: Option<&str>) -> anyhow::Result<Self> {
+        let frames = match guess_format(data) {
+            Ok(ImageFormat::Gif) => Some((GifDecoder::new(Cursor::new(data))?.into_frames(), AnimationFormat::Gif)),
+            Ok(ImageFormat::Png) => {
+                let decoder = PngDecoder::new(Cursor::new(data))?;
+                decoder.is_apng().then(|| (decoder.apng().into_frames(), AnimationFormat::Apng))
+            }
+            _ => None,
+        };
+
+        match frames {
+            Some((frames, format)) => Self::from_frames(frames, format, Self::MAX_PIXELS),
+            None => Ok(Self::still(Conversion::decode(data, file_name)?.0)),
+        }
+    }
+
+    // The frames are decoded one by one, so a long animation fails as soon as it exceeds `max_pixels`
+    fn from_frames(frames: Frames, format: AnimationFormat, max_pixels: u64) -> anyhow::Result<Self> {
+        let mut decoded = Vec::new();
+        let mut pixels = 0u64;
+        for frame in frames {
+            let frame = frame?;
+            pixels += frame.buffer().width() as u64 * frame.buffer().height() as u64;
+            if pixels > max_pixels {
+                anyhow::bail!("animation has more than {max_pixels} pixels in all its frames");
+            }
+
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            decoded.push(AnimationFrame {
+                delay_ms: numerator / denominator.max(1),
+                image: DynamicImage::ImageRgba8(frame.into_buffer()),
+            });
+        }
+        if decoded.is_empty() {
+            anyhow::bail!("animation has no frames");
+        }
+
+        Ok(Self {
+            frames: decoded,
+            format: Some(format),
+        })
+    }
+
+    pub fn still(image: DynamicImage) -> Self {
+        Self {
+            frames: vec![AnimationFrame { image, delay_ms: 0 }],
+            format: None,
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    /// Apply an operation to every frame, keeping the delays.
+    pub fn map<F>(self, mut op: F) -> anyhow::Result<Self>
+    where
+        F: FnMut(DynamicImage) -> anyhow::Result<DynamicImage>,
+    {
+        let frames = self
+            .frames
+            .into_iter()
+            .map(|frame| {
+                Ok(AnimationFrame {
+                    image: op(frame.image)?,
+                    delay_ms: frame.delay_ms,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self { frames, format: self.format })
+    }
+
+    /// Apply an operation to all the frames at once, for the operations which treat them alike. The delays
+    /// are kept, so the operation has to return as many frames as it is given.
+    pub fn map_frames<F>(self, op: F) -> anyhow::Result<Self>
+    where
+        F: FnOnce(Vec<DynamicImage>) -> anyhow::Result<Vec<DynamicImage>>,
+    {
+        let delays: Vec<u32> = self.frames.iter().map(|frame| frame.delay_ms).collect();
+        let images = op(self.frames.into_iter().map(|frame| frame.image).collect())?;
+        if images.len() != delays.len() {
+            anyhow::bail!("the animation has {} frames, not {}", delays.len(), images.len());
+        }
+
+        let frames = images
+            .into_iter()
+            .zip(delays)
+            .map(|(image, delay_ms)| AnimationFrame { image, delay_ms })
+            .collect();
+        Ok(Self { frames, format: self.format })
+    }
+
+    /// Encode the animation in `format`, by default the format it was decoded from, and return it with
+    /// its content type. Still images are encoded as PNG. GIF colors are quantized to a palette of
+    /// 256 colors per frame, `gif_speed` (1 - 30) trades the quality of the palettes for speed.
+    pub fn encode(self, format: Option<AnimationFormat>, gif_speed: i32) -> anyhow::Result<(Vec<u8>, &'static str)> {
+        let format = match (self.is_animated(), format.or(self.format)) {
+            (true, Some(format)) => format,
+            (true, None) => AnimationFormat::Gif,
+            (false, _) => {
+                let frame = self.frames.into_iter().next().map(|frame| frame.image).unwrap_or_default();
+                return Ok((get_image_as_bytes(frame)?, "image/png"));
+            }
+        };
+
+        // Frames are written as whole canvases, which have to be of the same size
+        let (width, height) = (self.frames[0].image.width(), self.frames[0].image.height());
+        if let Some(frame) = self.frames.iter().find(|frame| (frame.image.width(), frame.image.height()) != (width, height)) {
+            anyhow::bail!("frames differ in size, {}x{} and {}x{}", width, height, frame.image.width(), frame.image.height());
+        }
+
+        let mut bytes = Vec::new();
+        match format {
+            AnimationFormat::Gif => {
+                let mut encoder = GifEncoder::new_with_speed(&mut bytes, gif_speed.clamp(1, 30));
+                encoder.set_repeat(Repeat::Infinite)?;
+                encoder.encode_frames(
+                    self.frames
+                        .into_iter()
+                        .map(|frame| Frame::from_parts(frame.image.into_rgba8(), 0, 0, Delay::from_numer_denom_ms(frame.delay_ms, 1))),
+                )?;
+            }
+            AnimationFormat::Apng => {
+                let mut encoder = png::Encoder::new(&mut bytes, width, height);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(self.frames.len() as u32, 0)?;
+
+                let mut writer = encoder.write_header()?;
+                for frame in self.frames {
+                    writer.set_frame_delay(frame.delay_ms.min(u16::MAX as u32) as u16, 1000)?;
+                    writer.write_image_data(frame.image.into_rgba8().as_raw())?;
+                }
+                writer.finish()?;
+            }
+        }
+
+        Ok((bytes, format.content_type()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn animation(frames: u32) -> Animation {
+        Animation {
+            frames: (0..frames)
+                .map(|i| AnimationFrame {
+                    image: DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 6, Rgba([(i * 60) as u8, 0, 0, 255]))),
+                    delay_ms: 40 + i * 10,
+                })
+                .collect(),
+            format: None,
+        }
+    }
+
+    #[test]
+    fn frames_and_delays_survive_encoding() {
+        for format in [AnimationFormat::Gif, AnimationFormat::Apng] {
+            let (bytes, _) = animation(3).encode(Some(format), 10).unwrap();
+            let decoded = Animation::decode(&bytes, None).unwrap();
+
+            assert_eq!(decoded.format, Some(format));
+            assert_eq!(decoded.frames.iter().map(|frame| frame.delay_ms).collect::<Vec<_>>(), vec![40, 50, 60]);
+            assert_eq!(decoded.frames[2].image.to_rgba8().get_pixel(0, 0), &Rgba([120, 0, 0, 255]));
+        }
+    }
+
+    #[test]
+    fn still_images_are_a_single_frame() {
+        let (bytes, content_type) = animation(1).encode(Some(AnimationFormat::Gif), 10).unwrap();
+        let decoded = Animation::decode(&bytes, None).unwrap();
+
+        assert_eq!(content_type, "image/png");
+        assert_eq!((decoded.frames.len(), decoded.format), (1, None));
+    }
+
+    #[test]
+    fn formats_without_a_signature_are_told_by_the_file_name() {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::new(5, 4)).write_to(&mut Cursor::new(&mut bytes), ImageFormat::Tga).unwrap();
+
+        assert!(Animation::decode(&bytes, None).is_err());
+        assert_eq!(Animation::decode(&bytes, Some("scan.tga")).unwrap().frames[0].image.width(), 5);
+    }
+
+    #[test]
+    fn animations_beyond_the_pixel_limit_are_rejected() {
+        let (bytes, _) = animation(3).encode(Some(AnimationFormat::Gif), 10).unwrap();
+        let frames = || GifDecoder::new(Cursor::new(&bytes)).unwrap().into_frames();
+
+        assert!(Animation::from_frames(frames(), AnimationFormat::Gif, 3 * 8 * 6).is_ok());
+        assert!(Animation::from_frames(frames(), AnimationFormat::Gif, 3 * 8 * 6 - 1).is_err());
+    }
+}
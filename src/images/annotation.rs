@@ -0,0 +1,246 @@
+use image::Pixel;
+use rayon::prelude::*;
+
+use super::compositing::{blend_pixel, BlendMode, Compositing};
+use super::processing::{rgba_of, set_rgba, Image};
+use super::text::{Text, TextStyle};
+use crate::core::Detection;
+
+/// Look of the detection annotations, colors are RGBA.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnotationOptions {
+    pub color: [u8; 4],
+    /// Line width of the boxes in pixels.
+    pub thickness: u32,
+    /// Opacity of the box fill in the box color, 0.0 (hollow) - 1.0.
+    pub fill_opacity: f32,
+    /// Radius of the box corners in pixels.
+    pub corner_radius: f32,
+    /// Label drawn above every box, with `{n}` replaced by the number of the detection, `{confidence}`
+    /// by the confidence in percent and `{score}` by the raw confidence. No labels when empty.
+    pub label: String,
+    /// Prefix the labels with `#{n}`.
+    pub numbering: bool,
+    /// Font size of the labels, relative to the image height when `None`.
+    pub label_size: Option<f32>,
+    pub landmark_color: [u8; 4],
+}
+
+impl Default for AnnotationOptions {
+    fn default() -> Self {
+        Self {
+            color: [255, 0, 255, 255],
+            thickness: 1,
+            fill_opacity: 0.0,
+            corner_radius: 0.0,
+            label: "{confidence}%".to_string(),
+            numbering: false,
+            label_size: None,
+            landmark_color: [0, 255, 0, 255],
+        }
+    }
+}
+
+impl AnnotationOptions {
+    /// Text of the label of the `n`-th (from 1) detection.
+    pub fn label_for(&self, n: usize, confidence: f32) -> String {
+        let label = self
+            .label
+            .replace("{n}", &n.to_string())
+            .replace("{confidence}", &format!("{:.2}", confidence * 100.0))
+            .replace("{score}", &format!("{confidence:.3}"));
+
+        match (self.numbering, label.is_empty()) {
+            (true, true) => format!("#{n}"),
+            (true, false) => format!("#{n} {label}"),
+            (false, _) => label,
+        }
+    }
+}
+
+pub struct Annotation {}
+
+impl Annotation {
+    /// Draw the detections with their labels and landmarks on the image, the relative detection
+    /// coordinates are scaled to the size of the image.
+    pub fn draw<P>(frame: &mut Image<P>, detections: &[Detection], options: &AnnotationOptions)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = (frame.width() as f32, frame.height() as f32);
+        let color = options.color.map(|c| c as f32 / 255.0);
+        let label_style = TextStyle {
+            size: options.label_size.unwrap_or((height / 40.0).max(12.0)),
+            color: [255, 255, 255, 255],
+            background: Some(options.color),
+            padding: 2,
+            ..Default::default()
+        };
+
+        for (i, detection) in detections.iter().enumerate() {
+            // Coordinates of top-left and bottom-right points
+            // Coordinate frame basis is on the top left corner
+            let bbox = detection.bbox;
+            let (x_tl, y_tl) = (bbox[0] * width, bbox[1] * height);
+            let (x_br, y_br) = (bbox[2] * width, bbox[3] * height);
+
+            Self::draw_box(frame, (x_tl, y_tl, x_br, y_br), color, options);
+
+            for landmark in detection.landmarks.iter() {
+                let radius = (options.thickness as f32 + 1.0).max(2.0);
+                Self::draw_dot(frame, (landmark[0] * width, landmark[1] * height), radius, options.landmark_color.map(|c| c as f32 / 255.0));
+            }
+
+            let text = options.label_for(i + 1, detection.confidence);
+            if text.is_empty() {
+                continue;
+            }
+
+            // Label sits on top of the box, or inside of it when the box touches the top edge
+            let label = Text::render(&text, &label_style, None);
+            let label_height = label.height() as i64;
+            let label_y = if y_tl as i64 >= label_height { y_tl as i64 - label_height } else { y_tl as i64 };
+            Compositing::draw(frame, &label, x_tl as i64, label_y, 1.0, BlendMode::Normal);
+        }
+    }
+
+    // Draw a (rounded) rectangle outline of the given thickness inside of the box, with a translucent fill.
+    // Pixels are covered by the signed distance to the rounded rectangle, which antialiases the edges.
+    fn draw_box<P>(frame: &mut Image<P>, (x0, y0, x1, y1): (f32, f32, f32, f32), color: [f32; 4], options: &AnnotationOptions)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (cx, cy) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+        let (half_w, half_h) = (((x1 - x0) / 2.0).max(0.0), ((y1 - y0) / 2.0).max(0.0));
+        let radius = options.corner_radius.clamp(0.0, half_w.min(half_h));
+        let thickness = options.thickness as f32;
+        let fill_opacity = options.fill_opacity.clamp(0.0, 1.0);
+
+        let distance = |x: f32, y: f32| {
+            let qx = (x - cx).abs() - half_w + radius;
+            let qy = (y - cy).abs() - half_h + radius;
+            qx.max(0.0).hypot(qy.max(0.0)) + qx.max(qy).min(0.0) - radius
+        };
+
+        let rows = (y0.floor().max(0.0) as u32, (y1.ceil() + 1.0).min(frame.height() as f32) as u32);
+        let columns = (x0.floor().max(0.0) as u32, (x1.ceil() + 1.0).min(frame.width() as f32) as u32);
+        Self::shade(frame, rows, columns, |x, y| {
+            let d = distance(x, y);
+            let inside = (0.5 - d).clamp(0.0, 1.0);
+            let stroke = inside * (d + thickness + 0.5).clamp(0.0, 1.0);
+            let fill = (0.5 - d - thickness).clamp(0.0, 1.0) * fill_opacity;
+            (stroke + fill).min(1.0) * color[3]
+        }, color);
+    }
+
+    fn draw_dot<P>(frame: &mut Image<P>, (cx, cy): (f32, f32), radius: f32, color: [f32; 4])
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let rows = ((cy - radius).floor().max(0.0) as u32, ((cy + radius).ceil() + 1.0).min(frame.height() as f32) as u32);
+        let columns = ((cx - radius).floor().max(0.0) as u32, ((cx + radius).ceil() + 1.0).min(frame.width() as f32) as u32);
+        Self::shade(frame, rows, columns, |x, y| (radius + 0.5 - (x - cx).hypot(y - cy)).clamp(0.0, 1.0) * color[3], color);
+    }
+
+    // Blend `color` over the pixels in the given row and column ranges, with the opacity `coverage` gives
+    // for the pixel centers
+    fn shade<P, F>(frame: &mut Image<P>, rows: (u32, u32), columns: (u32, u32), coverage: F, color: [f32; 4])
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+        F: Fn(f32, f32) -> f32 + Sync,
+    {
+        if rows.0 >= rows.1 || columns.0 >= columns.1 {
+            return;
+        }
+
+        let channels = P::CHANNEL_COUNT as usize;
+        let row_len = frame.width() as usize * channels;
+        let opaque = [color[0], color[1], color[2], 1.0];
+
+        frame
+            .par_chunks_mut(row_len)
+            .enumerate()
+            .skip(rows.0 as usize)
+            .take((rows.1 - rows.0) as usize)
+            .for_each(|(y, row)| {
+                for x in columns.0..columns.1 {
+                    let opacity = coverage(x as f32 + 0.5, y as f32 + 0.5);
+                    if opacity > 0.0 {
+                        let px = P::from_slice_mut(&mut row[x as usize * channels..(x as usize + 1) * channels]);
+                        set_rgba(px, blend_pixel(rgba_of(px), opaque, opacity, BlendMode::Normal));
+                    }
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn face() -> Detection {
+        Detection {
+            bbox: [0.2, 0.2, 0.6, 0.6],
+            confidence: 0.9876,
+            landmarks: vec![[0.4, 0.4]],
+        }
+    }
+
+    #[test]
+    fn labels_are_filled_in_and_numbered() {
+        let options = AnnotationOptions {
+            label: "face {n}: {confidence}% ({score})".to_string(),
+            numbering: true,
+            ..Default::default()
+        };
+        assert_eq!(options.label_for(3, 0.9876), "#3 face 3: 98.76% (0.988)");
+
+        let options = AnnotationOptions {
+            label: String::new(),
+            ..options
+        };
+        assert_eq!(options.label_for(2, 0.5), "#2");
+        assert_eq!(AnnotationOptions::default().label_for(1, 0.5), "50.00%");
+    }
+
+    #[test]
+    fn boxes_are_drawn_inside_of_their_edges() {
+        let options = AnnotationOptions {
+            thickness: 2,
+            label: String::new(),
+            ..Default::default()
+        };
+        let mut frame = RgbImage::new(100, 100);
+        Annotation::draw(&mut frame, &[face()], &options);
+
+        let magenta = Rgb([255, 0, 255]);
+        assert_eq!(frame.get_pixel(20, 30), &magenta);
+        assert_eq!(frame.get_pixel(21, 30), &magenta);
+        assert_eq!(frame.get_pixel(59, 30), &magenta);
+        assert_eq!(frame.get_pixel(19, 30), &Rgb([0, 0, 0]));
+        assert_eq!(frame.get_pixel(30, 30), &Rgb([0, 0, 0]));
+        // the landmark is a dot of its own color
+        assert_eq!(frame.get_pixel(40, 40), &Rgb([0, 255, 0]));
+        // nothing above the box without a label
+        assert!((0..20).all(|y| frame.get_pixel(30, y) == &Rgb([0, 0, 0])));
+    }
+
+    #[test]
+    fn fill_and_label_are_drawn() {
+        let options = AnnotationOptions {
+            fill_opacity: 1.0,
+            ..Default::default()
+        };
+        let mut frame = RgbImage::new(100, 100);
+        Annotation::draw(&mut frame, &[face()], &options);
+
+        assert_eq!(frame.get_pixel(30, 50), &Rgb([255, 0, 255]));
+        assert!((0..20).any(|y| frame.get_pixel(25, y) != &Rgb([0, 0, 0])));
+    }
+}
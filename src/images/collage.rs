@@ -0,0 +1,292 @@
+use image::{imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+use std::str::FromStr;
+
+use super::compositing::{BlendMode, Compositing};
+use super::processing::Processing;
+use super::text::{Align, Text, TextRect, TextStyle, VAlign};
+use super::Upload;
+use crate::dynamic_map;
+
+/// How an image is fitted into its cell.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fit {
+    /// Scaled to fit whole inside of the cell, the rest of the cell shows the background.
+    Contain,
+    /// Scaled to cover the whole cell, cropping the overflow around the center.
+    Cover,
+    /// Scaled to the size of the cell, ignoring the aspect ratio.
+    Stretch,
+}
+
+impl FromStr for Fit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "contain" => Ok(Fit::Contain),
+            "cover" => Ok(Fit::Cover),
+            "stretch" => Ok(Fit::Stretch),
+            other => anyhow::bail!("unknown fit {other}"),
+        }
+    }
+}
+
+/// Arrangement of the images on the collage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// Rows of equally sized cells, `None` columns makes the grid as square as possible.
+    Grid { columns: Option<u32> },
+    /// Columns of cell width, every image keeps its aspect ratio and goes to the shortest column.
+    Masonry { columns: u32 },
+    /// One large image on the left and two stacked on the right.
+    Feature,
+    /// One wide image on top and the rest in a row below it.
+    Hero,
+}
+
+/// Options of `Collage::build`, sizes in pixels, colors RGBA.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollageOptions {
+    pub layout: Layout,
+    pub cell_width: u32,
+    pub cell_height: u32,
+    /// Space between the cells and around the collage.
+    pub gutter: u32,
+    pub background: [u8; 4],
+    pub fit: Fit,
+    /// Draw the file names of the uploads at the bottom of their cells.
+    pub captions: bool,
+    pub caption_size: f32,
+}
+
+impl Default for CollageOptions {
+    fn default() -> Self {
+        Self {
+            layout: Layout::Grid { columns: None },
+            cell_width: 300,
+            cell_height: 300,
+            gutter: 10,
+            background: [255, 255, 255, 255],
+            fit: Fit::Contain,
+            captions: false,
+            caption_size: 14.0,
+        }
+    }
+}
+
+/// Placement of a single image on the collage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cell {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub struct Collage {}
+
+impl Collage {
+    /// Largest collage in pixels, about 400 MB as RGBA.
+    pub const MAX_PIXELS: u64 = 100_000_000;
+
+    /// Lay the images out into a single RGBA image.
+    pub fn build(uploads: &[Upload], options: &CollageOptions) -> anyhow::Result<RgbaImage> {
+        let (cells, width, height) = Self::layout(uploads, options)?;
+        println!("Building {}x{} collage of {} images", width, height, cells.len());
+
+        let mut canvas = RgbaImage::from_pixel(width, height, Rgba(options.background));
+        let caption_style = TextStyle {
+            size: options.caption_size,
+            background: Some([0, 0, 0, 160]),
+            padding: 3,
+            align: Align::Center,
+            ..Default::default()
+        };
+
+        for (upload, cell) in uploads.iter().zip(cells) {
+            let fitted = Self::fit(&upload.image, cell.width, cell.height, options.fit)?;
+            // smaller images (with `contain`) are centered in their cell
+            let x = cell.x + (cell.width - fitted.width().min(cell.width)) / 2;
+            let y = cell.y + (cell.height - fitted.height().min(cell.height)) / 2;
+            Compositing::draw(&mut canvas, &fitted.to_rgba32f(), x as i64, y as i64, 1.0, BlendMode::Normal);
+
+            if let (true, Some(file_name)) = (options.captions, &upload.file_name) {
+                let rect = TextRect {
+                    x: cell.x as i64,
+                    y: cell.y as i64,
+                    width: cell.width,
+                    height: cell.height,
+                };
+                Text::draw(&mut canvas, file_name, &caption_style, rect, VAlign::Bottom);
+            }
+        }
+
+        Ok(canvas)
+    }
+
+    /// Cells of the images and the size of the whole collage, which may not exceed `MAX_PIXELS`.
+    pub fn layout(uploads: &[Upload], options: &CollageOptions) -> anyhow::Result<(Vec<Cell>, u32, u32)> {
+        if uploads.is_empty() {
+            anyhow::bail!("no images to put on the collage");
+        }
+
+        let n = uploads.len() as u32;
+        let (cell_w, cell_h, gutter) = (options.cell_width.max(1), options.cell_height.max(1), options.gutter);
+
+        match options.layout {
+            Layout::Grid { columns } => {
+                let columns = columns.unwrap_or_else(|| (n as f32).sqrt().ceil() as u32).clamp(1, n);
+                let slots = (0..n).map(|i| (i % columns, i / columns, 1, 1)).collect();
+                Self::unit_layout(slots, columns, n.div_ceil(columns), cell_w, cell_h, gutter)
+            }
+            Layout::Feature => {
+                if n > 3 {
+                    anyhow::bail!("the feature template holds 3 images, got {n}");
+                }
+                Self::unit_layout(vec![(0, 0, 2, 2), (2, 0, 1, 1), (2, 1, 1, 1)], 3, 2, cell_w, cell_h, gutter)
+            }
+            Layout::Hero => {
+                let columns = (n - 1).max(1);
+                let mut slots = vec![(0, 0, columns, 2)];
+                slots.extend((0..n - 1).map(|i| (i, 2, 1, 1)));
+                Self::unit_layout(slots, columns, if n > 1 { 3 } else { 2 }, cell_w, cell_h, gutter)
+            }
+            Layout::Masonry { columns } => {
+                let columns = columns.clamp(1, n);
+                let width = Self::canvas_side(columns, cell_w, gutter);
+                // the columns only grow, the canvas is too large as soon as one of them is
+                let max_height = Self::MAX_PIXELS / width.max(1);
+                let mut column_heights = vec![gutter as u64; columns as usize];
+                let mut cells = Vec::with_capacity(n as usize);
+
+                for upload in uploads {
+                    let (w, h) = (upload.image.width().max(1), upload.image.height().max(1));
+                    let height = ((cell_w as u64 * h as u64) / w as u64).max(1);
+                    let (column, top) = column_heights.iter().enumerate().min_by_key(|(_, top)| **top).map(|(c, top)| (c, *top)).unwrap();
+                    if top + height + gutter as u64 > max_height {
+                        anyhow::bail!("the collage would be larger than {} pixels", Self::MAX_PIXELS);
+                    }
+
+                    cells.push(Cell {
+                        x: gutter + column as u32 * (cell_w + gutter),
+                        y: top as u32,
+                        width: cell_w,
+                        height: height as u32,
+                    });
+                    column_heights[column] = top + height + gutter as u64;
+                }
+
+                let height = *column_heights.iter().max().unwrap();
+                Ok((cells, width as u32, height as u32))
+            }
+        }
+    }
+
+    // Width of `count` cells of `cell` pixels with the gutters between and around them
+    fn canvas_side(count: u32, cell: u32, gutter: u32) -> u64 {
+        gutter as u64 + count as u64 * (cell as u64 + gutter as u64)
+    }
+
+    // Cells given as `(column, row, column span, row span)` slots of a `columns` x `rows` grid of units
+    fn unit_layout(slots: Vec<(u32, u32, u32, u32)>, columns: u32, rows: u32, cell_w: u32, cell_h: u32, gutter: u32) -> anyhow::Result<(Vec<Cell>, u32, u32)> {
+        let (width, height) = (Self::canvas_side(columns, cell_w, gutter), Self::canvas_side(rows, cell_h, gutter));
+        if width.saturating_mul(height) > Self::MAX_PIXELS {
+            anyhow::bail!("the collage would be larger than {} pixels", Self::MAX_PIXELS);
+        }
+
+        let cells = slots
+            .into_iter()
+            .map(|(column, row, column_span, row_span)| Cell {
+                x: gutter + column * (cell_w + gutter),
+                y: gutter + row * (cell_h + gutter),
+                width: column_span * cell_w + (column_span - 1) * gutter,
+                height: row_span * cell_h + (row_span - 1) * gutter,
+            })
+            .collect();
+
+        Ok((cells, width as u32, height as u32))
+    }
+
+    /// Scale an image into a `width` x `height` cell.
+    pub fn fit(image: &DynamicImage, width: u32, height: u32, fit: Fit) -> anyhow::Result<DynamicImage> {
+        match fit {
+            Fit::Contain => Ok(image.resize(width, height, FilterType::Triangle)),
+            Fit::Stretch => Ok(image.resize_exact(width, height, FilterType::Triangle)),
+            Fit::Cover => {
+                let scale = f32::max(width as f32 / image.width() as f32, height as f32 / image.height() as f32);
+                let scaled_w = ((image.width() as f32 * scale).ceil() as u32).max(width);
+                let scaled_h = ((image.height() as f32 * scale).ceil() as u32).max(height);
+                let scaled = image.resize_exact(scaled_w, scaled_h, FilterType::Triangle);
+
+                let (x, y) = ((scaled_w - width) / 2, (scaled_h - height) / 2);
+                dynamic_map!(ref scaled, buf => Processing::crop_image(buf, x, y, width, height).map(DynamicImage::from))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbaImage};
+
+    use super::*;
+
+    fn uploads(sizes: &[(u32, u32)]) -> Vec<Upload> {
+        sizes
+            .iter()
+            .map(|&(w, h)| Upload {
+                name: "image".to_string(),
+                image: DynamicImage::ImageRgba8(RgbaImage::new(w, h)),
+                file_name: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn grid_is_as_square_as_possible() {
+        let options = CollageOptions {
+            cell_width: 100,
+            cell_height: 50,
+            gutter: 10,
+            ..Default::default()
+        };
+        let (cells, width, height) = Collage::layout(&uploads(&[(10, 10); 5]), &options).unwrap();
+
+        assert_eq!((width, height), (10 + 3 * 110, 10 + 2 * 60));
+        assert_eq!((cells[4].x, cells[4].y), (120, 70));
+    }
+
+    #[test]
+    fn masonry_keeps_the_aspect_ratio_in_the_shortest_column() {
+        let options = CollageOptions {
+            layout: Layout::Masonry { columns: 2 },
+            cell_width: 100,
+            gutter: 0,
+            ..Default::default()
+        };
+        let (cells, width, height) = Collage::layout(&uploads(&[(10, 30), (10, 10), (20, 10)]), &options).unwrap();
+
+        assert_eq!(
+            cells.iter().map(|cell| (cell.x, cell.y, cell.height)).collect::<Vec<_>>(),
+            vec![(0, 0, 300), (100, 0, 100), (100, 100, 50)]
+        );
+        assert_eq!((width, height), (200, 300));
+    }
+
+    #[test]
+    fn oversized_collages_are_rejected() {
+        let tall = CollageOptions {
+            layout: Layout::Masonry { columns: 1 },
+            cell_width: 10_000,
+            ..Default::default()
+        };
+        assert!(Collage::layout(&uploads(&[(1, 100_000)]), &tall).is_err());
+
+        let wide = CollageOptions {
+            cell_width: u32::MAX,
+            cell_height: u32::MAX,
+            ..Default::default()
+        };
+        assert!(Collage::layout(&uploads(&[(1, 1); 4]), &wide).is_err());
+    }
+}
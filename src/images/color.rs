@@ -0,0 +1,348 @@
+use image::{ImageBuffer, Luma, Pixel, Primitive};
+use rayon::prelude::*;
+use std::str::FromStr;
+
+use super::processing::{denormalized, rgba_of, set_rgba, Image};
+
+/// Color space of the channels taken out of the images by `Color::channel`, converted from the sRGB the
+/// images are encoded in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    Srgb,
+    /// sRGB without its transfer function, proportional to the light.
+    Linear,
+    Hsv,
+    Hsl,
+    /// CIELAB with the D65 white point.
+    Lab,
+    /// Full range BT.601, as in JPEG.
+    YCbCr,
+    /// Luminance of the sRGB values with the BT.709 weights.
+    Gray,
+}
+
+impl FromStr for ColorSpace {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "srgb" | "rgb" => Ok(ColorSpace::Srgb),
+            "linear" => Ok(ColorSpace::Linear),
+            "hsv" => Ok(ColorSpace::Hsv),
+            "hsl" => Ok(ColorSpace::Hsl),
+            "lab" => Ok(ColorSpace::Lab),
+            "ycbcr" => Ok(ColorSpace::YCbCr),
+            "gray" | "grey" => Ok(ColorSpace::Gray),
+            other => anyhow::bail!("unknown color space {other}"),
+        }
+    }
+}
+
+impl ColorSpace {
+    /// Names of the channels in their order in `scaled`.
+    pub fn channels(self) -> &'static [&'static str] {
+        match self {
+            ColorSpace::Srgb | ColorSpace::Linear => &["r", "g", "b"],
+            ColorSpace::Hsv => &["h", "s", "v"],
+            ColorSpace::Hsl => &["h", "s", "l"],
+            ColorSpace::Lab => &["l", "a", "b"],
+            ColorSpace::YCbCr => &["y", "cb", "cr"],
+            ColorSpace::Gray => &["y"],
+        }
+    }
+
+    /// Index of the channel of the name, e.g. `l` of Lab.
+    pub fn channel(self, name: &str) -> anyhow::Result<usize> {
+        let name = name.trim().to_ascii_lowercase();
+        let channels = self.channels();
+        channels
+            .iter()
+            .position(|&channel| channel == name)
+            .ok_or_else(|| anyhow::anyhow!("{self:?} has no channel {name}, only {}", channels.join(", ")))
+    }
+
+    /// Channels of the sRGB color (0.0 - 1.0) in this space, each scaled to 0.0 - 1.0 to be shown as an
+    /// image: the hue is divided by 360, L of Lab by 100, a and b of Lab (-128 - 127) and the chroma of YCbCr
+    /// are centered on 0.5.
+    pub fn scaled(self, rgb: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorSpace::Srgb => rgb,
+            ColorSpace::Linear => rgb.map(srgb_to_linear),
+            ColorSpace::Hsv => {
+                let [h, s, v] = rgb_to_hsv(rgb);
+                [h / 360.0, s, v]
+            }
+            ColorSpace::Hsl => {
+                let [h, s, l] = rgb_to_hsl(rgb);
+                [h / 360.0, s, l]
+            }
+            ColorSpace::Lab => {
+                let [l, a, b] = rgb_to_lab(rgb);
+                [l / 100.0, (a + 128.0) / 255.0, (b + 128.0) / 255.0]
+            }
+            ColorSpace::YCbCr => {
+                let (y, cb, cr) = rgb_to_ycbcr(rgb);
+                [y, cb + 0.5, cr + 0.5]
+            }
+            ColorSpace::Gray => [luminance(rgb); 3],
+        }
+    }
+}
+
+/// Estimate of the color of the light a photo was taken in, which `Color::white_balance` removes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WhiteBalance {
+    /// The average of the scene is taken as gray, suited to scenes with a variety of colors.
+    GrayWorld,
+    /// The brightest colors are taken as white, the `percentile` (0 - 100) of every channel rather than its
+    /// maximum so that a few clipped highlights do not count.
+    WhitePatch { percentile: f32 },
+}
+
+pub struct Color {}
+
+impl Color {
+    /// Grayscale image of one channel (see `ColorSpace::channels`) of the image in the color space, scaled as
+    /// by `ColorSpace::scaled`.
+    pub fn channel<P, T>(buf: &Image<P>, space: ColorSpace, channel: usize) -> ImageBuffer<Luma<T>, Vec<T>>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+        T: Primitive + Send + Sync,
+        Luma<T>: Pixel<Subpixel = T>,
+    {
+        let values = buf.par_chunks_exact(P::CHANNEL_COUNT as usize).map(|px| {
+            let [r, g, b, _] = rgba_of(P::from_slice(px));
+            denormalized(space.scaled([r, g, b])[channel])
+        });
+
+        ImageBuffer::from_raw(buf.width(), buf.height(), values.collect()).expect("one value per pixel")
+    }
+
+    /// Correct the color cast of the light in linear light, returning the gains of the red, green and blue
+    /// channels. The gains are brought towards 1.0 by `strength` below 1.0, and the transparent pixels are
+    /// left out of the estimate.
+    pub fn white_balance<P>(buf: &mut Image<P>, method: WhiteBalance, strength: f32) -> [f32; 3]
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let linear: Vec<[f32; 3]> = buf
+            .par_chunks_exact(P::CHANNEL_COUNT as usize)
+            .filter_map(|px| {
+                let [r, g, b, a] = rgba_of(P::from_slice(px));
+                (a > 0.0).then(|| [r, g, b].map(srgb_to_linear))
+            })
+            .collect();
+        if linear.is_empty() {
+            return [1.0; 3];
+        }
+
+        // the color of the light in every channel, then the gains turning it neutral
+        let gains = match method {
+            WhiteBalance::GrayWorld => {
+                let mean = [0, 1, 2].map(|c| linear.iter().map(|px| px[c] as f64).sum::<f64>() as f32 / linear.len() as f32);
+                let gray = mean.iter().sum::<f32>() / 3.0;
+                mean.map(|c| if c > f32::EPSILON { gray / c } else { 1.0 })
+            }
+            WhiteBalance::WhitePatch { percentile } => {
+                let rank = ((percentile.clamp(0.0, 100.0) / 100.0 * (linear.len() - 1) as f32).round() as usize).min(linear.len() - 1);
+                [0, 1, 2].map(|c| {
+                    let mut values: Vec<f32> = linear.iter().map(|px| px[c]).collect();
+                    let (_, white, _) = values.select_nth_unstable_by(rank, f32::total_cmp);
+                    if *white > f32::EPSILON {
+                        1.0 / *white
+                    } else {
+                        1.0
+                    }
+                })
+            }
+        };
+        // a channel nearly missing from the image would otherwise be blown up with its noise
+        let strength = strength.clamp(0.0, 1.0);
+        let gains = gains.map(|gain| 1.0 + (gain.clamp(0.25, 4.0) - 1.0) * strength);
+
+        let channels = P::CHANNEL_COUNT as usize;
+        let row_len = buf.width() as usize * channels;
+        buf.par_chunks_mut(row_len).for_each(|row| {
+            for px in row.chunks_exact_mut(channels) {
+                let px = P::from_slice_mut(px);
+                let [r, g, b, a] = rgba_of(px);
+                let [r, g, b] = [r, g, b].map(srgb_to_linear);
+                let [r, g, b] = [r * gains[0], g * gains[1], b * gains[2]].map(|c| linear_to_srgb(c.clamp(0.0, 1.0)));
+                set_rgba(px, [r, g, b, a]);
+            }
+        });
+
+        gains
+    }
+}
+
+/// sRGB transfer function decoded, from the encoded values to linear light (0.0 - 1.0).
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// sRGB transfer function, from linear light to the encoded values (0.0 - 1.0).
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Luminance of the sRGB values with the BT.709 weights, as the grayscale images are made.
+pub fn luminance([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// sRGB (0.0 - 1.0) to full range YCbCr with the BT.601 weights, the chroma centered on 0.0.
+pub fn rgb_to_ycbcr([r, g, b]: [f32; 3]) -> (f32, f32, f32) {
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    (y, (b - y) * 0.564, (r - y) * 0.713)
+}
+
+/// Inverse of `rgb_to_ycbcr`, out of gamut colors are clamped.
+pub fn ycbcr_to_rgb(y: f32, cb: f32, cr: f32) -> [f32; 3] {
+    let r = y + cr / 0.713;
+    let b = y + cb / 0.564;
+    let g = (y - 0.299 * r - 0.114 * b) / 0.587;
+    [r, g, b].map(|c| c.clamp(0.0, 1.0))
+}
+
+/// sRGB (0.0 - 1.0) to hue (0 - 360 degrees, 0 for grays), saturation and value (0.0 - 1.0).
+pub fn rgb_to_hsv(rgb: [f32; 3]) -> [f32; 3] {
+    let (max, min) = (rgb[0].max(rgb[1]).max(rgb[2]), rgb[0].min(rgb[1]).min(rgb[2]));
+    let saturation = if max > 0.0 { (max - min) / max } else { 0.0 };
+    [hue(rgb, max, min), saturation, max]
+}
+
+/// sRGB (0.0 - 1.0) to hue (0 - 360 degrees, 0 for grays), saturation and lightness (0.0 - 1.0).
+pub fn rgb_to_hsl(rgb: [f32; 3]) -> [f32; 3] {
+    let (max, min) = (rgb[0].max(rgb[1]).max(rgb[2]), rgb[0].min(rgb[1]).min(rgb[2]));
+    let lightness = (max + min) / 2.0;
+    let saturation = if max > min { (max - min) / (1.0 - (2.0 * lightness - 1.0).abs()) } else { 0.0 };
+    [hue(rgb, max, min), saturation.min(1.0), lightness]
+}
+
+fn hue([r, g, b]: [f32; 3], max: f32, min: f32) -> f32 {
+    let chroma = max - min;
+    if chroma <= 0.0 {
+        return 0.0;
+    }
+
+    let sector = if max == r {
+        ((g - b) / chroma).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / chroma + 2.0
+    } else {
+        (r - g) / chroma + 4.0
+    };
+    sector * 60.0
+}
+
+// sRGB (0.0 - 1.0) to CIELAB with the D65 white point, through linear RGB and XYZ
+// (http://www.brucelindbloom.com/index.html?Math.html)
+pub fn rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(srgb_to_linear);
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f32| if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// CIELAB (D65) to 8-bit sRGB, out of gamut colors are clamped.
+pub fn lab_to_rgb(lab: [f32; 3]) -> [u8; 3] {
+    let fy = (lab[0] + 16.0) / 116.0;
+    let (fx, fz) = (fy + lab[1] / 500.0, fy - lab[2] / 200.0);
+    let f_inverse = |t: f32| if t.powi(3) > 216.0 / 24389.0 { t.powi(3) } else { (116.0 * t - 16.0) * 27.0 / 24389.0 };
+    let (x, y, z) = (f_inverse(fx) * 0.95047, f_inverse(fy), f_inverse(fz) * 1.08883);
+
+    let r = 3.2406 * x - 1.5372 * y - 0.4986 * z;
+    let g = -0.9689 * x + 1.8758 * y + 0.0415 * z;
+    let b = 0.0557 * x - 0.2040 * y + 1.0570 * z;
+
+    [r, g, b].map(|c| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn close(a: [f32; 3], b: [f32; 3], tolerance: f32) -> bool {
+        a.iter().zip(b).all(|(a, b)| (a - b).abs() <= tolerance)
+    }
+
+    #[test]
+    fn conversions_give_the_reference_values() {
+        assert!(close(rgb_to_hsv([1.0, 0.5, 0.0]), [30.0, 1.0, 1.0], 1e-4));
+        assert!(close(rgb_to_hsl([0.0, 0.0, 0.5]), [240.0, 1.0, 0.25], 1e-4));
+        assert!(close(rgb_to_hsv([0.4, 0.4, 0.4]), [0.0, 0.0, 0.4], 1e-4));
+        // Lab of white, and of sRGB red (http://www.brucelindbloom.com)
+        assert!(close(rgb_to_lab([1.0, 1.0, 1.0]), [100.0, 0.0, 0.0], 0.05));
+        assert!(close(rgb_to_lab([1.0, 0.0, 0.0]), [53.24, 80.09, 67.20], 0.1));
+        assert!((srgb_to_linear(0.5) - 0.214).abs() < 1e-3);
+    }
+
+    #[test]
+    fn conversions_round_trip() {
+        for rgb in [[255u8, 0, 0], [12, 200, 99], [128, 128, 128], [3, 7, 250]] {
+            let normalized = rgb.map(|c| c as f32 / 255.0);
+            assert_eq!(lab_to_rgb(rgb_to_lab(normalized)), rgb);
+
+            let (y, cb, cr) = rgb_to_ycbcr(normalized);
+            assert!(close(ycbcr_to_rgb(y, cb, cr), normalized, 2e-3), "{rgb:?}");
+            assert!((linear_to_srgb(srgb_to_linear(normalized[1])) - normalized[1]).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn channels_are_taken_by_name_and_scaled() {
+        assert_eq!(ColorSpace::Lab.channel("L").unwrap(), 0);
+        assert_eq!(ColorSpace::YCbCr.channel("cr").unwrap(), 2);
+        assert!(ColorSpace::Hsv.channel("l").is_err());
+
+        let image = RgbImage::from_pixel(2, 2, Rgb([255, 255, 255]));
+        let l: ImageBuffer<Luma<u8>, Vec<u8>> = Color::channel(&image, ColorSpace::Lab, 0);
+        assert_eq!(l.get_pixel(1, 1), &Luma([255]));
+        let a: ImageBuffer<Luma<u16>, Vec<u16>> = Color::channel(&image, ColorSpace::Lab, 1);
+        assert!(a.get_pixel(0, 0)[0].abs_diff(32896) < 64);
+    }
+
+    #[test]
+    fn white_balance_removes_the_cast() {
+        // a scene of gray patches under a warm light
+        let warm = |level: f32| Rgb([level, level * 0.8, level * 0.6].map(|c| (linear_to_srgb(c) * 255.0).round() as u8));
+        let mut image = RgbImage::from_fn(30, 10, |x, _| warm(0.2 + x as f32 / 40.0));
+
+        let gains = Color::white_balance(&mut image, WhiteBalance::GrayWorld, 1.0);
+        assert!(gains[0] < 1.0 && gains[2] > 1.0, "{gains:?}");
+        assert!(image.pixels().all(|px| px[0].abs_diff(px[1]) <= 2 && px[1].abs_diff(px[2]) <= 2));
+
+        let mut image = RgbImage::from_fn(30, 10, |x, _| warm(0.2 + x as f32 / 40.0));
+        let original = image.clone();
+        assert_eq!(Color::white_balance(&mut image, WhiteBalance::WhitePatch { percentile: 99.0 }, 0.0), [1.0; 3]);
+        assert_eq!(image, original);
+    }
+
+    #[test]
+    fn transparent_pixels_are_left_out_of_the_estimate() {
+        let mut image = RgbaImage::from_fn(10, 10, |x, _| match x < 5 {
+            true => Rgba([128, 128, 128, 255]),
+            false => Rgba([255, 0, 0, 0]),
+        });
+
+        let gains = Color::white_balance(&mut image, WhiteBalance::GrayWorld, 1.0);
+        assert!(close(gains, [1.0; 3], 1e-4), "{gains:?}");
+    }
+}
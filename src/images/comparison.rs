@@ -0,0 +1,270 @@
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, GenericImageView, ImageBuffer, Luma, Rgba, Rgba32FImage, RgbaImage,
+};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fmt;
+
+use super::processing::{color_distance, gaussian_blur};
+
+/// Options of the `Comparison` functions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareOptions {
+    /// Smallest `color_distance` (or alpha difference on the 0 - 255 scale) of a pixel that counts as changed.
+    pub threshold: f32,
+    /// Compare images of different sizes by scaling the second one to the size of the first one.
+    pub resize: bool,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 10.0,
+            resize: false,
+        }
+    }
+}
+
+/// Similarity of two images, computed on their RGBA colors.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Metrics {
+    pub width: u32,
+    pub height: u32,
+    /// Mean squared error of the channels on the 0 - 255 scale.
+    pub mse: f64,
+    /// Peak signal-to-noise ratio in dB, `None` for identical images.
+    pub psnr: Option<f64>,
+    /// Mean structural similarity of the luminance, 1.0 for identical images.
+    pub ssim: f64,
+    /// Share of the pixels changed by more than the threshold, in percent.
+    pub changed_percent: f64,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let psnr = self.psnr.map(|psnr| format!("{psnr:.2}")).unwrap_or_else(|| "inf".to_string());
+        write!(f, "mse={:.4}; psnr={psnr}; ssim={:.5}; changed_percent={:.3}", self.mse, self.ssim, self.changed_percent)
+    }
+}
+
+pub struct Comparison {}
+
+impl Comparison {
+    /// Compare the second image against the first (reference) one.
+    pub fn metrics(reference: &DynamicImage, image: &DynamicImage, options: &CompareOptions) -> anyhow::Result<Metrics> {
+        let (reference, image) = Self::prepare(reference, image, options)?;
+        let (width, height) = reference.dimensions();
+
+        let squared_error: f64 = reference
+            .as_raw()
+            .par_iter()
+            .zip(image.as_raw().par_iter())
+            .map(|(a, b)| {
+                let difference = ((a - b) * 255.0) as f64;
+                difference * difference
+            })
+            .sum();
+        let mse = squared_error / reference.as_raw().len() as f64;
+        let psnr = (mse > 0.0).then(|| 10.0 * (255.0 * 255.0 / mse).log10());
+
+        let differences = Self::differences(&reference, &image);
+        let changed = differences.par_iter().filter(|&&difference| difference > options.threshold).count();
+
+        Ok(Metrics {
+            width,
+            height,
+            mse,
+            psnr,
+            ssim: ssim(&reference, &image),
+            changed_percent: 100.0 * changed as f64 / differences.len() as f64,
+        })
+    }
+
+    /// Heatmap of the differences over a dimmed grayscale copy of the reference, changed pixels are
+    /// colored from red (at the threshold) to yellow (completely different).
+    pub fn heatmap(reference: &DynamicImage, image: &DynamicImage, options: &CompareOptions) -> anyhow::Result<RgbaImage> {
+        let (reference, image) = Self::prepare(reference, image, options)?;
+        let differences = Self::differences(&reference, &image);
+        let width = reference.width();
+
+        Ok(RgbaImage::from_fn(width, reference.height(), |x, y| {
+            let difference = differences[(y * width + x) as usize];
+            if difference > options.threshold {
+                heat((difference - options.threshold) / (MAX_DIFFERENCE - options.threshold).max(1.0))
+            } else {
+                let [r, g, b, a] = reference.get_pixel(x, y).0;
+                let luma = (0.2126 * r + 0.7152 * g + 0.0722 * b) * a;
+                let dimmed = (64.0 + luma * 96.0) as u8;
+                Rgba([dimmed, dimmed, dimmed, 255])
+            }
+        }))
+    }
+
+    /// The reference and the image next to each other, with the changed pixels tinted red on both.
+    pub fn side_by_side(reference: &DynamicImage, image: &DynamicImage, options: &CompareOptions) -> anyhow::Result<RgbaImage> {
+        let (reference, image) = Self::prepare(reference, image, options)?;
+        let differences = Self::differences(&reference, &image);
+        let (width, height) = reference.dimensions();
+        const GAP: u32 = 8;
+
+        let mut canvas = RgbaImage::from_pixel(2 * width + GAP, height, Rgba([255, 255, 255, 255]));
+        for (offset, side) in [(0, &reference), (width + GAP, &image)] {
+            for (x, y, px) in side.enumerate_pixels() {
+                let [r, g, b, a] = px.0.map(|c| c.clamp(0.0, 1.0));
+                // transparent areas are shown over white
+                let mut rgb = [r, g, b].map(|c| c * a + (1.0 - a));
+                if differences[(y * width + x) as usize] > options.threshold {
+                    rgb = [0.5 + rgb[0] * 0.5, rgb[1] * 0.4, rgb[2] * 0.4];
+                }
+                canvas.put_pixel(x + offset, y, Rgba([rgb[0], rgb[1], rgb[2], 1.0].map(|c| (c * 255.0).round() as u8)));
+            }
+        }
+
+        Ok(canvas)
+    }
+
+    // Both images as float RGBA of the same size
+    fn prepare(reference: &DynamicImage, image: &DynamicImage, options: &CompareOptions) -> anyhow::Result<(Rgba32FImage, Rgba32FImage)> {
+        let (width, height) = reference.dimensions();
+        if width == 0 || height == 0 {
+            anyhow::bail!("provided image is empty");
+        }
+
+        let image = match (image.dimensions() == (width, height), options.resize) {
+            (true, _) => image.to_rgba32f(),
+            (false, true) => imageops::resize(&image.to_rgba32f(), width, height, FilterType::Triangle),
+            (false, false) => anyhow::bail!("images differ in size, {}x{} and {}x{}", width, height, image.width(), image.height()),
+        };
+
+        Ok((reference.to_rgba32f(), image))
+    }
+
+    // Difference of every pixel, the larger of the color distance and the alpha difference (0 - 255 scale)
+    fn differences(reference: &Rgba32FImage, image: &Rgba32FImage) -> Vec<f32> {
+        reference
+            .as_raw()
+            .par_chunks_exact(4)
+            .zip(image.as_raw().par_chunks_exact(4))
+            .map(|(a, b)| {
+                let rgb = |px: &[f32]| [px[0] * 255.0, px[1] * 255.0, px[2] * 255.0];
+                color_distance(rgb(a), rgb(b)).max((a[3] - b[3]).abs() * 255.0)
+            })
+            .collect()
+    }
+}
+
+/// Largest possible `color_distance`.
+const MAX_DIFFERENCE: f32 = 441.67;
+
+// Red to yellow ramp for 0.0 - 1.0
+fn heat(value: f32) -> Rgba<u8> {
+    let value = value.clamp(0.0, 1.0);
+    Rgba([255, (value * 255.0) as u8, 0, 255])
+}
+
+type LumaImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+// Mean SSIM of the luminance over gaussian windows (sigma 1.5), as in Wang et al. 2004
+// (https://www.cns.nyu.edu/pub/eero/wang03-reprint.pdf)
+fn ssim(reference: &Rgba32FImage, image: &Rgba32FImage) -> f64 {
+    const C1: f32 = 0.01 * 0.01;
+    const C2: f32 = 0.03 * 0.03;
+    const SIGMA: f32 = 1.5;
+
+    let luma = |buf: &Rgba32FImage| {
+        LumaImage::from_fn(buf.width(), buf.height(), |x, y| {
+            let [r, g, b, a] = buf.get_pixel(x, y).0;
+            Luma([(0.2126 * r + 0.7152 * g + 0.0722 * b) * a])
+        })
+    };
+    let (x, y) = (luma(reference), luma(image));
+    let product = |a: &LumaImage, b: &LumaImage| LumaImage::from_fn(a.width(), a.height(), |px, py| Luma([a.get_pixel(px, py)[0] * b.get_pixel(px, py)[0]]));
+
+    let blur = |buf: &LumaImage| gaussian_blur(buf, SIGMA);
+    let (mu_x, mu_y) = (blur(&x), blur(&y));
+    let (xx, yy, xy) = (blur(&product(&x, &x)), blur(&product(&y, &y)), blur(&product(&x, &y)));
+
+    let total: f64 = (0..mu_x.as_raw().len())
+        .into_par_iter()
+        .map(|i| {
+            let (mx, my) = (mu_x.as_raw()[i], mu_y.as_raw()[i]);
+            let variance_x = xx.as_raw()[i] - mx * mx;
+            let variance_y = yy.as_raw()[i] - my * my;
+            let covariance = xy.as_raw()[i] - mx * my;
+
+            (((2.0 * mx * my + C1) * (2.0 * covariance + C2)) / ((mx * mx + my * my + C1) * (variance_x + variance_y + C2))) as f64
+        })
+        .sum();
+
+    total / mu_x.as_raw().len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use super::*;
+
+    // Smooth pattern, shifted right by `shift` pixels
+    fn pattern(shift: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            let x = x + 64 - shift;
+            Rgb([(x * 4 % 256) as u8, (y * 4) as u8, ((x + y) * 2 % 256) as u8])
+        }))
+    }
+
+    #[test]
+    fn identical_images_are_equal() {
+        let metrics = Comparison::metrics(&pattern(0), &pattern(0), &CompareOptions::default()).unwrap();
+
+        assert_eq!((metrics.mse, metrics.psnr, metrics.changed_percent), (0.0, None, 0.0));
+        assert!((metrics.ssim - 1.0).abs() < 1e-6, "ssim {}", metrics.ssim);
+    }
+
+    #[test]
+    fn shifted_image_is_less_similar_the_further_it_moves() {
+        let options = CompareOptions::default();
+        let near = Comparison::metrics(&pattern(0), &pattern(1), &options).unwrap();
+        let far = Comparison::metrics(&pattern(0), &pattern(8), &options).unwrap();
+
+        assert!(near.mse > 0.0 && near.mse < far.mse);
+        assert!(near.psnr.unwrap() > far.psnr.unwrap());
+        assert!(near.ssim < 1.0 && near.ssim > far.ssim);
+        assert!(far.changed_percent > 0.0);
+    }
+
+    #[test]
+    fn psnr_follows_from_the_mse() {
+        let gray = |value| DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([value; 3])));
+        let metrics = Comparison::metrics(&gray(100), &gray(110), &CompareOptions::default()).unwrap();
+
+        // the alpha channels are equal, so 3 of the 4 channels differ by 10
+        assert!((metrics.mse - 75.0).abs() < 1e-3);
+        assert!((metrics.psnr.unwrap() - 10.0 * (255.0f64 * 255.0 / 75.0).log10()).abs() < 1e-3);
+        assert_eq!(metrics.changed_percent, 100.0);
+    }
+
+    #[test]
+    fn flat_images_differ_in_brightness_only() {
+        let gray = |value| DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([value; 3])));
+        let metrics = Comparison::metrics(&gray(100), &gray(110), &CompareOptions::default()).unwrap();
+
+        // neither image varies, so only the luminance term of SSIM is left
+        let (x, y) = (100.0 / 255.0, 110.0 / 255.0);
+        let luminance = (2.0 * x * y + 0.01 * 0.01) / (x * x + y * y + 0.01 * 0.01);
+        assert!((metrics.ssim - luminance).abs() < 1e-5, "ssim {} instead of {luminance}", metrics.ssim);
+    }
+
+    #[test]
+    fn sizes_must_match_unless_resized() {
+        let small = DynamicImage::ImageRgb8(RgbImage::new(32, 32));
+        assert!(Comparison::metrics(&pattern(0), &small, &CompareOptions::default()).is_err());
+
+        let options = CompareOptions {
+            resize: true,
+            ..Default::default()
+        };
+        assert_eq!(Comparison::metrics(&pattern(0), &small, &options).unwrap().width, 64);
+    }
+}
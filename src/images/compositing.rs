@@ -0,0 +1,387 @@
+use image::{
+    imageops::{self, FilterType},
+    DynamicImage, Pixel, Rgba32FImage,
+};
+use rayon::prelude::*;
+use std::str::FromStr;
+
+use super::processing::{rgba_of, set_rgba, BorderColor, Image, Processing, TrimOptions};
+
+/// How the colors of an overlay are mixed with the colors underneath it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    Add,
+    Difference,
+}
+
+impl BlendMode {
+    // Separable blend functions of a backdrop channel `cb` and a source channel `cs`, both 0.0 - 1.0,
+    // as defined in https://www.w3.org/TR/compositing-1/#blending
+    fn blend(&self, cb: f32, cs: f32) -> f32 {
+        match self {
+            BlendMode::Normal => cs,
+            BlendMode::Multiply => cb * cs,
+            BlendMode::Screen => cb + cs - cb * cs,
+            BlendMode::Overlay => {
+                if cb <= 0.5 {
+                    2.0 * cb * cs
+                } else {
+                    1.0 - 2.0 * (1.0 - cb) * (1.0 - cs)
+                }
+            }
+            BlendMode::Darken => cb.min(cs),
+            BlendMode::Lighten => cb.max(cs),
+            BlendMode::Add => (cb + cs).min(1.0),
+            BlendMode::Difference => (cb - cs).abs(),
+        }
+    }
+}
+
+impl FromStr for BlendMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "normal" => Ok(BlendMode::Normal),
+            "multiply" => Ok(BlendMode::Multiply),
+            "screen" => Ok(BlendMode::Screen),
+            "overlay" => Ok(BlendMode::Overlay),
+            "darken" => Ok(BlendMode::Darken),
+            "lighten" => Ok(BlendMode::Lighten),
+            "add" => Ok(BlendMode::Add),
+            "difference" => Ok(BlendMode::Difference),
+            other => anyhow::bail!("unknown blend mode {other}"),
+        }
+    }
+}
+
+/// Point of the base image an overlay is aligned to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// Top left corner of a `size` sized rectangle aligned to the anchor within `outer`, kept `margin`
+    /// pixels away from the aligned edges.
+    pub fn place(&self, outer: (u32, u32), size: (u32, u32), margin: u32) -> (i64, i64) {
+        let (outer_w, outer_h) = (outer.0 as i64, outer.1 as i64);
+        let (w, h) = (size.0 as i64, size.1 as i64);
+        let margin = margin as i64;
+
+        let (left, center_x, right) = (margin, (outer_w - w) / 2, outer_w - w - margin);
+        let (top, center_y, bottom) = (margin, (outer_h - h) / 2, outer_h - h - margin);
+
+        match self {
+            Anchor::TopLeft => (left, top),
+            Anchor::Top => (center_x, top),
+            Anchor::TopRight => (right, top),
+            Anchor::Left => (left, center_y),
+            Anchor::Center => (center_x, center_y),
+            Anchor::Right => (right, center_y),
+            Anchor::BottomLeft => (left, bottom),
+            Anchor::Bottom => (center_x, bottom),
+            Anchor::BottomRight => (right, bottom),
+        }
+    }
+}
+
+impl FromStr for Anchor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('_', "-").as_str() {
+            "top-left" => Ok(Anchor::TopLeft),
+            "top" => Ok(Anchor::Top),
+            "top-right" => Ok(Anchor::TopRight),
+            "left" => Ok(Anchor::Left),
+            "center" => Ok(Anchor::Center),
+            "right" => Ok(Anchor::Right),
+            "bottom-left" => Ok(Anchor::BottomLeft),
+            "bottom" => Ok(Anchor::Bottom),
+            "bottom-right" => Ok(Anchor::BottomRight),
+            other => anyhow::bail!("unknown anchor {other}"),
+        }
+    }
+}
+
+/// Placement of an overlay on the base image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Anchor(Anchor),
+    /// Top left corner in pixels, may lie outside of the base image.
+    At(i64, i64),
+}
+
+/// Options of `Compositing::overlay`, by default the overlay is drawn as it is in the bottom right corner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlayOptions {
+    pub position: Position,
+    /// Distance from the edges when placed by an anchor, in pixels.
+    pub margin: u32,
+    /// Scale of the overlay relative to its own size.
+    pub scale: f32,
+    /// 0.0 - 1.0, multiplies the alpha of the overlay.
+    pub opacity: f32,
+    /// Rotation of the overlay in degrees.
+    pub rotation: f32,
+    /// Repeat the overlay over the whole base image with this spacing between the tiles, in pixels.
+    pub tile: Option<u32>,
+    pub blend: BlendMode,
+}
+
+impl Default for OverlayOptions {
+    fn default() -> Self {
+        Self {
+            position: Position::Anchor(Anchor::BottomRight),
+            margin: 0,
+            scale: 1.0,
+            opacity: 1.0,
+            rotation: 0.0,
+            tile: None,
+            blend: BlendMode::Normal,
+        }
+    }
+}
+
+pub struct Compositing {}
+
+impl Compositing {
+    /// Largest overlay in pixels after scaling and rotating it, about 1.6 GB as float RGBA.
+    pub const MAX_PIXELS: u64 = 100_000_000;
+
+    /// Draw an overlay (e.g. a watermark logo) over the base image, keeping the pixel type of the base.
+    pub fn overlay<P>(base: &mut Image<P>, overlay: &DynamicImage, options: &OverlayOptions) -> anyhow::Result<()>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let overlay = Self::transform(overlay, options.scale, options.rotation)?;
+
+        let (x, y) = match options.position {
+            Position::Anchor(anchor) => anchor.place(base.dimensions(), overlay.dimensions(), options.margin),
+            Position::At(x, y) => (x, y),
+        };
+
+        match options.tile {
+            Some(spacing) => Self::draw_tiled(base, &overlay, x, y, spacing, options.opacity, options.blend),
+            None => Self::draw(base, &overlay, x, y, options.opacity, options.blend),
+        }
+
+        Ok(())
+    }
+
+    /// Scale and rotate an image into float RGBA, ready to be drawn. A rotated image is trimmed to the
+    /// bounds of its rotated content.
+    pub fn transform(image: &DynamicImage, scale: f32, rotation: f32) -> anyhow::Result<Rgba32FImage> {
+        if !scale.is_finite() || scale <= 0.0 {
+            anyhow::bail!("scale has to be a positive number, got {scale}");
+        }
+
+        // The size is checked before the scaled and the rotated images are allocated, both being about as
+        // large as the diagonal when rotated
+        let width = (image.width() as f64 * scale as f64).round().max(1.0);
+        let height = (image.height() as f64 * scale as f64).round().max(1.0);
+        let pixels = match rotation % 360.0 != 0.0 {
+            true => width * width + height * height,
+            false => width * height,
+        };
+        if pixels > Self::MAX_PIXELS as f64 {
+            anyhow::bail!("the overlay scaled by {scale} would be larger than {} pixels", Self::MAX_PIXELS);
+        }
+
+        let mut transformed = image.to_rgba32f();
+        if scale != 1.0 {
+            transformed = imageops::resize(&transformed, width as u32, height as u32, FilterType::Triangle);
+        }
+
+        if rotation % 360.0 != 0.0 {
+            // `Processing::rotate` keeps the canvas size, so the image is centered on a canvas as large as its
+            // diagonal first to keep the corners
+            let (width, height) = transformed.dimensions();
+            let diagonal = ((width as f32).hypot(height as f32).ceil()) as u32;
+            let mut padded = Rgba32FImage::new(diagonal, diagonal);
+            imageops::replace(&mut padded, &transformed, ((diagonal - width) / 2) as i64, ((diagonal - height) / 2) as i64);
+
+            let rotated = Processing::rotate(&padded, rotation);
+            let trim = TrimOptions {
+                color: BorderColor::Transparent,
+                ..Default::default()
+            };
+            transformed = Processing::remove_borders(&rotated, &trim)?;
+        }
+
+        Ok(transformed)
+    }
+
+    /// Draw an image over the base with its top left corner at `(x, y)`, clipped to the base.
+    pub fn draw<P>(base: &mut Image<P>, overlay: &Rgba32FImage, x: i64, y: i64, opacity: f32, blend: BlendMode)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = (overlay.width() as i64, overlay.height() as i64);
+        Self::composite(base, overlay, opacity, blend, |bx, by| {
+            let (ox, oy) = (bx - x, by - y);
+            (ox >= 0 && oy >= 0 && ox < width && oy < height).then_some((ox as u32, oy as u32))
+        });
+    }
+
+    /// Draw an image repeatedly over the whole base, in a grid of tiles `spacing` pixels apart with one
+    /// tile at `(x, y)`.
+    pub fn draw_tiled<P>(base: &mut Image<P>, overlay: &Rgba32FImage, x: i64, y: i64, spacing: u32, opacity: f32, blend: BlendMode)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = (overlay.width() as i64, overlay.height() as i64);
+        let (period_x, period_y) = (width + spacing as i64, height + spacing as i64);
+        Self::composite(base, overlay, opacity, blend, |bx, by| {
+            let (ox, oy) = ((bx - x).rem_euclid(period_x), (by - y).rem_euclid(period_y));
+            (ox < width && oy < height).then_some((ox as u32, oy as u32))
+        });
+    }
+
+    // Blend every base pixel with the overlay pixel `locate` maps it to, in parallel over the base rows
+    fn composite<P, F>(base: &mut Image<P>, overlay: &Rgba32FImage, opacity: f32, blend: BlendMode, locate: F)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+        F: Fn(i64, i64) -> Option<(u32, u32)> + Sync,
+    {
+        let channels = P::CHANNEL_COUNT as usize;
+        let row_len = base.width() as usize * channels;
+        let opacity = opacity.clamp(0.0, 1.0);
+
+        base.par_chunks_mut(row_len).enumerate().for_each(|(y, row)| {
+            for (x, px) in row.chunks_exact_mut(channels).enumerate() {
+                if let Some((ox, oy)) = locate(x as i64, y as i64) {
+                    let px = P::from_slice_mut(px);
+                    let blended = blend_pixel(rgba_of(px), overlay.get_pixel(ox, oy).0, opacity, blend);
+                    set_rgba(px, blended);
+                }
+            }
+        });
+    }
+}
+
+/// Composite a source pixel over a backdrop pixel, both RGBA on the 0.0 - 1.0 scale, with the source alpha
+/// multiplied by `opacity` (https://www.w3.org/TR/compositing-1/#generalformula).
+pub fn blend_pixel(backdrop: [f32; 4], source: [f32; 4], opacity: f32, blend: BlendMode) -> [f32; 4] {
+    let ab = backdrop[3];
+    let a_s = source[3].clamp(0.0, 1.0) * opacity;
+    let ao = a_s + ab * (1.0 - a_s);
+    if ao <= 0.0 {
+        return [0.0; 4];
+    }
+
+    let mut out = [0.0, 0.0, 0.0, ao];
+    for c in 0..3 {
+        let (cb, cs) = (backdrop[c], source[c].clamp(0.0, 1.0));
+        let mixed = (1.0 - ab) * cs + ab * blend.blend(cb, cs);
+        out[c] = (a_s * mixed + ab * cb * (1.0 - a_s)) / ao;
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn logo(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba([255, 0, 0, 255])))
+    }
+
+    #[test]
+    fn anchors_keep_the_margin_from_the_aligned_edges() {
+        assert_eq!(Anchor::TopLeft.place((100, 50), (20, 10), 5), (5, 5));
+        assert_eq!(Anchor::Center.place((100, 50), (20, 10), 5), (40, 20));
+        assert_eq!(Anchor::BottomRight.place((100, 50), (20, 10), 5), (75, 35));
+        assert_eq!("bottom_left".parse::<Anchor>().unwrap(), Anchor::BottomLeft);
+    }
+
+    #[test]
+    fn blend_modes_follow_the_compositing_formulas() {
+        let (gray, white) = ([0.5, 0.5, 0.5, 1.0], [1.0, 1.0, 1.0, 1.0]);
+
+        assert_eq!(blend_pixel(gray, [1.0, 0.0, 0.0, 1.0], 1.0, BlendMode::Normal), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(blend_pixel(gray, [1.0, 0.0, 0.0, 1.0], 0.5, BlendMode::Normal), [0.75, 0.25, 0.25, 1.0]);
+        assert_eq!(blend_pixel(white, [0.5, 0.2, 0.0, 1.0], 1.0, BlendMode::Multiply), [0.5, 0.2, 0.0, 1.0]);
+        assert_eq!(blend_pixel(gray, [0.5, 0.5, 0.5, 1.0], 1.0, BlendMode::Screen), [0.75, 0.75, 0.75, 1.0]);
+        // over a transparent backdrop the source is drawn as it is
+        assert_eq!(blend_pixel([0.0; 4], [0.2, 0.4, 0.6, 0.5], 1.0, BlendMode::Multiply), [0.2, 0.4, 0.6, 0.5]);
+    }
+
+    #[test]
+    fn overlay_is_placed_and_clipped() {
+        let mut base = RgbImage::from_pixel(40, 30, Rgb([0, 0, 255]));
+        let options = OverlayOptions {
+            margin: 2,
+            ..Default::default()
+        };
+        Compositing::overlay(&mut base, &logo(10, 5), &options).unwrap();
+
+        assert_eq!(base.get_pixel(28, 23), &Rgb([255, 0, 0]));
+        assert_eq!(base.get_pixel(37, 27), &Rgb([255, 0, 0]));
+        assert_eq!(base.get_pixel(27, 23), &Rgb([0, 0, 255]));
+        assert_eq!(base.get_pixel(38, 28), &Rgb([0, 0, 255]));
+
+        // partly outside of the base, only the part within is drawn
+        let mut base = RgbImage::from_pixel(40, 30, Rgb([0, 0, 255]));
+        let options = OverlayOptions {
+            position: Position::At(-5, -2),
+            ..Default::default()
+        };
+        Compositing::overlay(&mut base, &logo(10, 5), &options).unwrap();
+        assert_eq!(base.get_pixel(4, 2), &Rgb([255, 0, 0]));
+        assert_eq!(base.get_pixel(5, 0), &Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn tiles_repeat_with_the_spacing() {
+        let mut base = RgbImage::from_pixel(20, 10, Rgb([0, 0, 0]));
+        let options = OverlayOptions {
+            position: Position::At(0, 0),
+            tile: Some(3),
+            ..Default::default()
+        };
+        Compositing::overlay(&mut base, &logo(2, 2), &options).unwrap();
+
+        let red: Vec<u32> = (0..20).filter(|&x| base.get_pixel(x, 0) == &Rgb([255, 0, 0])).collect();
+        assert_eq!(red, vec![0, 1, 5, 6, 10, 11, 15, 16]);
+        assert_eq!(base.get_pixel(0, 5), &Rgb([255, 0, 0]));
+        assert_eq!(base.get_pixel(0, 2), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn transform_scales_and_turns_the_overlay() {
+        let scaled = Compositing::transform(&logo(10, 4), 0.5, 0.0).unwrap();
+        assert_eq!(scaled.dimensions(), (5, 2));
+
+        let turned = Compositing::transform(&logo(10, 4), 1.0, 90.0).unwrap();
+        assert_eq!(turned.dimensions(), (4, 10));
+        assert!(Compositing::transform(&logo(10, 4), 0.0, 0.0).is_err());
+        assert!(Compositing::transform(&logo(10, 4), f32::NAN, 0.0).is_err());
+        assert!(Compositing::transform(&logo(10, 4), f32::INFINITY, 0.0).is_err());
+        assert!(Compositing::transform(&logo(2000, 1000), 200.0, 0.0).is_err());
+        // 100 million pixels when scaled, but about twice as many when rotated
+        assert!(Compositing::transform(&logo(10, 10), 900.0, 45.0).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+use image::{
+    codecs::png::{CompressionType, FilterType, PngEncoder},
+    codecs::pnm::{PnmSubtype, SampleEncoding},
+    guess_format, load_from_memory_with_format, DynamicImage, ImageFormat, ImageOutputFormat, Rgb, Rgba,
+};
+use jpeg_encoder::SamplingFactor;
+use rayon::prelude::*;
+use std::{io::Cursor, str::FromStr};
+
+use super::color::{linear_to_srgb, srgb_to_linear};
+use super::encodable;
+
+/// Chroma subsampling of JPEG, how many pixels share their color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Subsampling {
+    /// Every pixel has its own color, the sharpest colored edges and the largest files.
+    S444,
+    /// Colors shared by pairs of pixels side by side.
+    S422,
+    /// Colors shared by blocks of 2x2 pixels, what most cameras do.
+    S420,
+}
+
+impl FromStr for Subsampling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().replace(':', "").as_str() {
+            "444" => Ok(Subsampling::S444),
+            "422" => Ok(Subsampling::S422),
+            "420" => Ok(Subsampling::S420),
+            other => anyhow::bail!("unknown chroma subsampling {other}"),
+        }
+    }
+}
+
+/// Output format with the options of its encoder.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Encoding {
+    /// `quality` 1 - 100.
+    Jpeg {
+        quality: u8,
+        progressive: bool,
+        subsampling: Subsampling,
+    },
+    Png {
+        compression: CompressionType,
+        filter: FilterType,
+    },
+    /// Lossless when `quality` (0 - 100) is `None`.
+    WebP {
+        quality: Option<f32>,
+    },
+    /// `quality` 1 - 100, `speed` 1 (slowest, smallest) - 10, encoded in pure Rust with rav1e.
+    Avif {
+        quality: f32,
+        speed: u8,
+    },
+    /// Formats without options, written by `image` (BMP, GIF, ICO, OpenEXR, PNM, QOI, TGA, TIFF, farbfeld).
+    Other(ImageFormat),
+}
+
+impl Encoding {
+    /// Encoding of the format with the default options.
+    pub fn of(format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::Jpeg => Encoding::Jpeg {
+                quality: 85,
+                progressive: false,
+                subsampling: Subsampling::S420,
+            },
+            ImageFormat::Png => Encoding::Png {
+                compression: CompressionType::Default,
+                filter: FilterType::Adaptive,
+            },
+            ImageFormat::WebP => Encoding::WebP { quality: None },
+            ImageFormat::Avif => Encoding::Avif { quality: 80.0, speed: 6 },
+            format => Encoding::Other(format),
+        }
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        match self {
+            Encoding::Jpeg { .. } => ImageFormat::Jpeg,
+            Encoding::Png { .. } => ImageFormat::Png,
+            Encoding::WebP { .. } => ImageFormat::WebP,
+            Encoding::Avif { .. } => ImageFormat::Avif,
+            Encoding::Other(format) => *format,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self.format() {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::WebP => "image/webp",
+            ImageFormat::Avif => "image/avif",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Bmp => "image/bmp",
+            ImageFormat::Ico => "image/x-icon",
+            ImageFormat::Tiff => "image/tiff",
+            ImageFormat::Qoi => "image/qoi",
+            ImageFormat::OpenExr => "image/x-exr",
+            ImageFormat::Tga => "image/x-tga",
+            ImageFormat::Pnm => "image/x-portable-anymap",
+            ImageFormat::Farbfeld => "image/x-farbfeld",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+/// Operator compressing the unbounded brightness of HDR images into the displayable range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMap {
+    /// Everything brighter than white is white.
+    Clamp,
+    /// `x / (1 + x)`, keeps the shadows and rolls off the highlights
+    /// (https://www-old.cs.utah.edu/docs/techreports/2002/pdf/UUCS-02-001.pdf).
+    Reinhard,
+    /// Filmic curve of the Academy Color Encoding System, with more contrast than Reinhard
+    /// (https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/).
+    Aces,
+}
+
+impl FromStr for ToneMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "clamp" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "aces" => Ok(ToneMap::Aces),
+            other => anyhow::bail!("unknown tone mapping {other}"),
+        }
+    }
+}
+
+pub struct Conversion {}
+
+impl Conversion {
+    /// Decode an image of any supported format, from its contents or, for the formats without a signature
+    /// (TGA), from the extension of its file name.
+    pub fn decode(data: &[u8], file_name: Option<&str>) -> anyhow::Result<(DynamicImage, ImageFormat)> {
+        let format = match (guess_format(data), file_name) {
+            (Ok(format), _) => format,
+            (Err(_), Some(name)) => ImageFormat::from_path(name).map_err(|_| anyhow::anyhow!("unknown image format of {name}"))?,
+            (Err(err), None) => return Err(err.into()),
+        };
+
+        Ok((load_from_memory_with_format(data, format)?, format))
+    }
+
+    /// Whether images of the format hold linear light beyond white, which needs tone mapping to be displayed.
+    pub fn is_hdr(format: ImageFormat) -> bool {
+        matches!(format, ImageFormat::Hdr | ImageFormat::OpenExr)
+    }
+
+    /// Map the linear light of an HDR image, scaled by `exposure` stops, to sRGB in the 0.0 - 1.0 range.
+    pub fn tone_map(image: &DynamicImage, operator: ToneMap, exposure: f32) -> DynamicImage {
+        let scale = 2f32.powf(exposure);
+        let map = |c: f32| {
+            let c = (c * scale).max(0.0);
+            let c = match operator {
+                ToneMap::Clamp => c,
+                ToneMap::Reinhard => c / (1.0 + c),
+                ToneMap::Aces => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+            };
+            linear_to_srgb(c.clamp(0.0, 1.0))
+        };
+
+        let mut mapped = image.to_rgba32f();
+        mapped.par_chunks_mut(4).for_each(|px| {
+            for c in &mut px[..3] {
+                *c = map(*c);
+            }
+        });
+
+        match image.color().has_alpha() {
+            true => DynamicImage::ImageRgba32F(mapped),
+            false => DynamicImage::ImageRgb32F(DynamicImage::ImageRgba32F(mapped).into_rgb32f()),
+        }
+    }
+
+    /// Encode the image, converted to the color types the format can hold (e.g. JPEG has neither alpha
+    /// nor 16-bit samples, farbfeld is always 16-bit RGBA).
+    pub fn encode(image: DynamicImage, encoding: &Encoding) -> anyhow::Result<Vec<u8>> {
+        let (width, height) = (image.width(), image.height());
+        let mut bytes = Vec::new();
+
+        match *encoding {
+            Encoding::Jpeg {
+                quality,
+                progressive,
+                subsampling,
+            } => {
+                let (width, height) = (u16::try_from(width)?, u16::try_from(height)?);
+                let mut encoder = jpeg_encoder::Encoder::new(&mut bytes, quality.clamp(1, 100));
+                encoder.set_progressive(progressive);
+                encoder.set_sampling_factor(match subsampling {
+                    Subsampling::S444 => SamplingFactor::F_1_1,
+                    Subsampling::S422 => SamplingFactor::F_2_1,
+                    Subsampling::S420 => SamplingFactor::F_2_2,
+                });
+
+                match image.color().has_color() {
+                    true => encoder.encode(image.into_rgb8().as_raw(), width, height, jpeg_encoder::ColorType::Rgb)?,
+                    false => encoder.encode(image.into_luma8().as_raw(), width, height, jpeg_encoder::ColorType::Luma)?,
+                }
+            }
+            Encoding::Png { compression, filter } => {
+                encodable(image).write_with_encoder(PngEncoder::new_with_quality(&mut bytes, compression, filter))?;
+            }
+            Encoding::WebP { quality } => {
+                let has_alpha = image.color().has_alpha();
+                let pixels = if has_alpha {
+                    image.into_rgba8().into_raw()
+                } else {
+                    image.into_rgb8().into_raw()
+                };
+                let encoder = match has_alpha {
+                    true => webp::Encoder::from_rgba(&pixels, width, height),
+                    false => webp::Encoder::from_rgb(&pixels, width, height),
+                };
+
+                // `encode` and `encode_lossless` panic when libwebp fails, e.g. on images over 16383 pixels on a side
+                let encoded = match quality {
+                    Some(quality) => encoder.encode_simple(false, quality.clamp(0.0, 100.0)),
+                    None => encoder.encode_simple(true, 75.0),
+                };
+                bytes.extend_from_slice(&encoded.map_err(|err| anyhow::anyhow!("WebP encoding failed: {err:?}"))?);
+            }
+            Encoding::Avif { quality, speed } => {
+                let encoder = ravif::Encoder::new()
+                    .with_quality(quality.clamp(1.0, 100.0))
+                    .with_alpha_quality(quality.clamp(1.0, 100.0))
+                    .with_speed(speed.clamp(1, 10));
+                let (width, height) = (width as usize, height as usize);
+
+                let encoded = match image.color().has_alpha() {
+                    true => {
+                        let pixels: Vec<ravif::RGBA8> = image.into_rgba8().pixels().map(|&Rgba([r, g, b, a])| ravif::RGBA8::new(r, g, b, a)).collect();
+                        encoder.encode_rgba(ravif::Img::new(&pixels[..], width, height))?
+                    }
+                    false => {
+                        let pixels: Vec<ravif::RGB8> = image.into_rgb8().pixels().map(|&Rgb([r, g, b])| ravif::RGB8::new(r, g, b)).collect();
+                        encoder.encode_rgb(ravif::Img::new(&pixels[..], width, height))?
+                    }
+                };
+                bytes = encoded.avif_file;
+            }
+            Encoding::Other(format) => {
+                let has_alpha = image.color().has_alpha();
+                let image = match format {
+                    ImageFormat::OpenExr => to_linear(image),
+                    ImageFormat::Tiff => encodable(image),
+                    ImageFormat::Farbfeld => DynamicImage::ImageRgba16(image.into_rgba16()),
+                    ImageFormat::Pnm => {
+                        // binary PPM, which more tools read than the PAM `image` writes by default
+                        let pixmap = ImageOutputFormat::Pnm(PnmSubtype::Pixmap(SampleEncoding::Binary));
+                        DynamicImage::ImageRgb8(image.into_rgb8()).write_to(&mut Cursor::new(&mut bytes), pixmap)?;
+                        return Ok(bytes);
+                    }
+                    _ if has_alpha => DynamicImage::ImageRgba8(image.into_rgba8()),
+                    _ => DynamicImage::ImageRgb8(image.into_rgb8()),
+                };
+                image.write_to(&mut Cursor::new(&mut bytes), format)?;
+            }
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// PNG compression from `fast`, `default` or `best`.
+pub fn parse_png_compression(s: &str) -> anyhow::Result<CompressionType> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "fast" => Ok(CompressionType::Fast),
+        "default" => Ok(CompressionType::Default),
+        "best" => Ok(CompressionType::Best),
+        other => anyhow::bail!("unknown PNG compression {other}"),
+    }
+}
+
+/// PNG row filter from `none`, `sub`, `up`, `avg`, `paeth` or `adaptive` (the best of them for every row).
+pub fn parse_png_filter(s: &str) -> anyhow::Result<FilterType> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "none" => Ok(FilterType::NoFilter),
+        "sub" => Ok(FilterType::Sub),
+        "up" => Ok(FilterType::Up),
+        "avg" => Ok(FilterType::Avg),
+        "paeth" => Ok(FilterType::Paeth),
+        "adaptive" => Ok(FilterType::Adaptive),
+        other => anyhow::bail!("unknown PNG filter {other}"),
+    }
+}
+
+// Float image in linear light for OpenEXR, integer images being sRGB encoded while float ones are kept
+// as they are
+fn to_linear(image: DynamicImage) -> DynamicImage {
+    let is_float = matches!(image, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+    let has_alpha = image.color().has_alpha();
+    let mut linear = image.into_rgba32f();
+    if !is_float {
+        linear.par_chunks_mut(4).for_each(|px| {
+            for c in &mut px[..3] {
+                *c = srgb_to_linear(*c);
+            }
+        });
+    }
+
+    match has_alpha {
+        true => DynamicImage::ImageRgba32F(linear),
+        false => DynamicImage::ImageRgb32F(DynamicImage::ImageRgba32F(linear).into_rgb32f()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, ImageBuffer, Luma, Rgb32FImage, RgbImage, RgbaImage};
+
+    use super::*;
+
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(16, 12, |x, y| Rgba([(x * 16) as u8, (y * 20) as u8, 90, (255 - x * 8) as u8]))
+    }
+
+    fn round_trip(image: DynamicImage, encoding: &Encoding) -> DynamicImage {
+        let bytes = Conversion::encode(image, encoding).unwrap();
+        let (decoded, format) = Conversion::decode(&bytes, None).unwrap();
+        assert_eq!(format, encoding.format());
+        decoded
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        assert_eq!(
+            ["444", "4:2:2", " 420 "].map(|s| s.parse::<Subsampling>().unwrap()),
+            [Subsampling::S444, Subsampling::S422, Subsampling::S420]
+        );
+        assert!("411".parse::<Subsampling>().is_err());
+        assert_eq!("ACES".parse::<ToneMap>().unwrap(), ToneMap::Aces);
+        assert!("filmic".parse::<ToneMap>().is_err());
+        assert_eq!(parse_png_compression("best").unwrap(), CompressionType::Best);
+        assert_eq!(parse_png_filter("Paeth").unwrap(), FilterType::Paeth);
+        assert!(parse_png_filter("median").is_err());
+    }
+
+    #[test]
+    fn encodings_default_to_their_format() {
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP, ImageFormat::Avif, ImageFormat::Tiff] {
+            assert_eq!(Encoding::of(format).format(), format);
+        }
+        assert_eq!(Encoding::of(ImageFormat::WebP), Encoding::WebP { quality: None });
+        assert_eq!(Encoding::of(ImageFormat::Qoi).content_type(), "image/qoi");
+    }
+
+    #[test]
+    fn lossless_formats_keep_every_pixel() {
+        let image = DynamicImage::ImageRgba8(gradient());
+        for format in [ImageFormat::Png, ImageFormat::WebP, ImageFormat::Qoi, ImageFormat::Tiff] {
+            assert_eq!(round_trip(image.clone(), &Encoding::of(format)).to_rgba8(), gradient(), "{format:?}");
+        }
+
+        let deep = DynamicImage::ImageRgba16(ImageBuffer::from_fn(4, 4, |x, y| Rgba([x as u16 * 4099, y as u16 * 20000, 7, 65535])));
+        assert_eq!(round_trip(deep.clone(), &Encoding::of(ImageFormat::Png)), deep);
+        assert_eq!(round_trip(deep.clone(), &Encoding::of(ImageFormat::Farbfeld)), deep);
+    }
+
+    #[test]
+    fn formats_get_the_color_types_they_can_hold() {
+        let jpeg = round_trip(DynamicImage::ImageRgba8(gradient()), &Encoding::of(ImageFormat::Jpeg));
+        assert_eq!((jpeg.color(), jpeg.width(), jpeg.height()), (image::ColorType::Rgb8, 16, 12));
+        let gray = round_trip(
+            DynamicImage::ImageLuma8(GrayImage::from_pixel(8, 8, Luma([77]))),
+            &Encoding::of(ImageFormat::Jpeg),
+        );
+        assert_eq!(gray.color(), image::ColorType::L8);
+
+        let farbfeld = round_trip(DynamicImage::ImageLuma8(GrayImage::new(2, 2)), &Encoding::of(ImageFormat::Farbfeld));
+        assert_eq!(farbfeld.color(), image::ColorType::Rgba16);
+
+        let bytes = Conversion::encode(DynamicImage::ImageRgba8(gradient()), &Encoding::of(ImageFormat::Pnm)).unwrap();
+        assert!(bytes.starts_with(b"P6"));
+    }
+
+    #[test]
+    fn webp_failures_are_errors() {
+        let wide = DynamicImage::ImageRgb8(RgbImage::new(16384, 1));
+
+        assert!(Conversion::encode(wide.clone(), &Encoding::WebP { quality: Some(80.0) }).is_err());
+        assert!(Conversion::encode(wide, &Encoding::WebP { quality: None }).is_err());
+    }
+
+    #[test]
+    fn openexr_holds_linear_light() {
+        let gray = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([128, 128, 128])));
+        let exr = round_trip(gray, &Encoding::of(ImageFormat::OpenExr)).into_rgb32f();
+        assert!((exr.get_pixel(0, 0)[0] - srgb_to_linear(128.0 / 255.0)).abs() < 1e-3);
+
+        let bright = DynamicImage::ImageRgb32F(Rgb32FImage::from_pixel(2, 2, Rgb([4.0, 0.5, 0.0])));
+        let exr = round_trip(bright, &Encoding::of(ImageFormat::OpenExr)).into_rgb32f();
+        assert_eq!(*exr.get_pixel(1, 1), Rgb([4.0, 0.5, 0.0]));
+    }
+
+    #[test]
+    fn tone_mapping_compresses_the_highlights() {
+        let hdr = DynamicImage::ImageRgb32F(Rgb32FImage::from_fn(3, 1, |x, _| Rgb([[0.5, 1.0, 8.0][x as usize]; 3])));
+        let mapped = |operator, exposure| Conversion::tone_map(&hdr, operator, exposure).into_rgb32f();
+
+        let clamped = mapped(ToneMap::Clamp, 0.0);
+        assert!(clamped.pixels().skip(1).all(|px| (px[0] - 1.0).abs() < 1e-5));
+        // Reinhard maps 1.0 to half of white, 8.0 still below white
+        let reinhard = mapped(ToneMap::Reinhard, 0.0);
+        assert!((reinhard.get_pixel(1, 0)[0] - linear_to_srgb(0.5)).abs() < 1e-5);
+        assert!(reinhard.get_pixel(2, 0)[0] < 1.0);
+        // one stop more exposure doubles the light
+        assert!((mapped(ToneMap::Reinhard, 1.0).get_pixel(0, 0)[0] - reinhard.get_pixel(1, 0)[0]).abs() < 1e-5);
+
+        let aces = mapped(ToneMap::Aces, 0.0);
+        assert!(aces.pixels().zip(aces.pixels().skip(1)).all(|(a, b)| a[0] < b[0]));
+        assert!(!Conversion::tone_map(&hdr, ToneMap::Aces, 0.0).color().has_alpha());
+    }
+}
@@ -0,0 +1,256 @@
+use image::DynamicImage;
+use std::str::FromStr;
+
+use super::processing::{ContentRect, Processing};
+use super::thumbnail::Thumbnail;
+use crate::dynamic_map;
+
+/// Unit of the coordinates and sizes of a crop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Units {
+    Pixels,
+    /// 0 - 100 of the width (x) or height (y) of the image.
+    Percent,
+    /// 0.0 - 1.0 of the width or height of the image, as the bounding boxes of the detections.
+    Normalized,
+}
+
+impl FromStr for Units {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "px" | "pixels" => Ok(Units::Pixels),
+            "%" | "percent" => Ok(Units::Percent),
+            "normalized" | "relative" => Ok(Units::Normalized),
+            other => anyhow::bail!("unknown units {other}"),
+        }
+    }
+}
+
+/// Region to crop, resolved to pixels against the size of the image by `CropSpec::resolve`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CropSpec {
+    /// Rectangle with its top left corner at `x`, `y`, which has to lie within the image, cut short at the
+    /// right and bottom edges of the image.
+    Rect { x: f32, y: f32, width: f32, height: f32, units: Units },
+    /// Rectangle of the size in the middle of the image.
+    Center { width: f32, height: f32, units: Units },
+    /// Largest rectangle of the `aspect` ratio (width / height) in the middle of the image.
+    Aspect { aspect: f32 },
+    /// Box between the corners `[x0, y0, x1, y1]` grown by `padding` on every side, cut short at the edges
+    /// of the image.
+    Bbox { corners: [f32; 4], padding: f32, units: Units },
+}
+
+impl CropSpec {
+    /// Rectangle of the crop in the pixels of a `width` x `height` image, an error when it is empty or
+    /// reaches outside of the image, but for the rectangles and padded boxes which are cut short at the edges.
+    pub fn resolve(&self, width: u32, height: u32) -> anyhow::Result<ContentRect> {
+        let (image_width, image_height) = (width as f32, height as f32);
+        let scale = |units: Units| match units {
+            Units::Pixels => (1.0, 1.0),
+            Units::Percent => (image_width / 100.0, image_height / 100.0),
+            Units::Normalized => (image_width, image_height),
+        };
+
+        // Edges in pixels, checked against the image before rounding
+        let (x0, y0, x1, y1) = match *self {
+            CropSpec::Rect { x, y, width, height, units } => {
+                let (sx, sy) = scale(units);
+                let (x0, y0) = (x * sx, y * sy);
+                if !(x0 < image_width && y0 < image_height) {
+                    anyhow::bail!("crop origin {x0},{y0} is outside of the {image_width}x{image_height} image");
+                }
+                (x0, y0, ((x + width) * sx).min(image_width), ((y + height) * sy).min(image_height))
+            }
+            CropSpec::Center { width, height, units } => {
+                let (sx, sy) = scale(units);
+                let (width, height) = (width * sx, height * sy);
+                let (x, y) = ((image_width - width) / 2.0, (image_height - height) / 2.0);
+                (x, y, x + width, y + height)
+            }
+            CropSpec::Aspect { aspect } => {
+                if !(aspect.is_finite() && aspect > 0.0) {
+                    anyhow::bail!("aspect ratio {aspect} is not positive");
+                }
+                let image = ContentRect { x: 0, y: 0, width, height };
+                return Ok(Thumbnail::window_around(width, height, aspect, &image));
+            }
+            CropSpec::Bbox { corners, padding, units } => {
+                let (sx, sy) = scale(units);
+                let [x0, y0, x1, y1] = corners;
+                (
+                    ((x0 - padding) * sx).max(0.0),
+                    ((y0 - padding) * sy).max(0.0),
+                    ((x1 + padding) * sx).min(image_width),
+                    ((y1 + padding) * sy).min(image_height),
+                )
+            }
+        };
+
+        if ![x0, y0, x1, y1].iter().all(|edge| edge.is_finite()) {
+            anyhow::bail!("crop {self:?} has edges which are not numbers");
+        }
+        // a rounding error of the relative units is let through
+        const TOLERANCE: f32 = 0.01;
+        if x0 < -TOLERANCE || y0 < -TOLERANCE || x1 > image_width + TOLERANCE || y1 > image_height + TOLERANCE {
+            anyhow::bail!("crop from {x0},{y0} to {x1},{y1} reaches outside of the {width}x{height} image");
+        }
+
+        let (left, top) = ((x0.round().max(0.0) as u32).min(width), (y0.round().max(0.0) as u32).min(height));
+        let (right, bottom) = ((x1.round().max(0.0) as u32).min(width), (y1.round().max(0.0) as u32).min(height));
+        if right <= left || bottom <= top {
+            anyhow::bail!("crop from {x0},{y0} to {x1},{y1} of the {width}x{height} image is empty");
+        }
+
+        Ok(ContentRect {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        })
+    }
+}
+
+pub struct Cropping {}
+
+impl Cropping {
+    /// Crop the image to the region, see `CropSpec::resolve`.
+    pub fn crop(image: &DynamicImage, spec: &CropSpec) -> anyhow::Result<DynamicImage> {
+        let rect = spec.resolve(image.width(), image.height())?;
+        dynamic_map!(ref image, buf => Processing::crop_image(buf, rect.x, rect.y, rect.width, rect.height).map(DynamicImage::from))
+    }
+}
+
+/// Aspect ratio from `16:9`, `16/9` or `1.78`.
+pub fn parse_aspect(s: &str) -> anyhow::Result<f32> {
+    let aspect = match s.trim().split_once([':', '/']) {
+        Some((width, height)) => width.trim().parse::<f32>()? / height.trim().parse::<f32>()?,
+        None => s.trim().parse()?,
+    };
+    if !(aspect.is_finite() && aspect > 0.0) {
+        anyhow::bail!("aspect ratio {s} is not positive");
+    }
+
+    Ok(aspect)
+}
+
+/// Tar archive (POSIX ustar) of the named files, which any archiver unpacks.
+pub fn tar_archive(files: &[(String, Vec<u8>)]) -> anyhow::Result<Vec<u8>> {
+    const BLOCK: usize = 512;
+    let mut archive = Vec::new();
+
+    for (name, data) in files {
+        if name.len() > 100 {
+            anyhow::bail!("file name {name} is too long for a tar archive");
+        }
+
+        let mut header = [0u8; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        // numbers are octal text ending with NUL
+        let mut field = |offset: usize, width: usize, value: u64| {
+            let text = format!("{value:0digits$o}\0", digits = width - 1);
+            header[offset..offset + width].copy_from_slice(text.as_bytes());
+        };
+        field(100, 8, 0o644);
+        field(108, 8, 0);
+        field(116, 8, 0);
+        field(124, 12, data.len() as u64);
+        field(136, 12, 0);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // the checksum is summed with its own field as spaces
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&byte| byte as u32).sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(BLOCK), 0);
+    }
+
+    // the end is marked by two empty blocks
+    archive.resize(archive.len() + 2 * BLOCK, 0);
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rectangles_are_cut_short_at_the_edges() {
+        let rect = |x, y, width, height| CropSpec::Rect {
+            x,
+            y,
+            width,
+            height,
+            units: Units::Pixels,
+        };
+        let resolved = |spec: CropSpec| spec.resolve(100, 50).map(|rect| (rect.x, rect.y, rect.width, rect.height));
+
+        assert_eq!(resolved(rect(10.0, 20.0, 30.0, 10.0)).unwrap(), (10, 20, 30, 10));
+        assert_eq!(resolved(rect(80.0, 40.0, 50.0, 50.0)).unwrap(), (80, 40, 20, 10));
+        let percent = CropSpec::Rect {
+            x: 50.0,
+            y: 50.0,
+            width: 80.0,
+            height: 80.0,
+            units: Units::Percent,
+        };
+        assert_eq!(resolved(percent).unwrap(), (50, 25, 50, 25));
+    }
+
+    #[test]
+    fn origin_outside_of_the_image_is_rejected() {
+        let rect = |x, y| CropSpec::Rect {
+            x,
+            y,
+            width: 10.0,
+            height: 10.0,
+            units: Units::Pixels,
+        };
+
+        let err = rect(100.0, 0.0).resolve(100, 50).unwrap_err();
+        assert_eq!(err.to_string(), "crop origin 100,0 is outside of the 100x50 image");
+        assert!(rect(500.0, 500.0).resolve(100, 50).is_err());
+        assert!(rect(-5.0, 0.0).resolve(100, 50).is_err());
+        assert!(rect(f32::NAN, 0.0).resolve(100, 50).is_err());
+        assert!(CropSpec::Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 0.0,
+            height: 10.0,
+            units: Units::Pixels
+        }
+        .resolve(100, 50)
+        .is_err());
+    }
+
+    #[test]
+    fn tar_headers_are_checksummed_and_padded() {
+        let archive = tar_archive(&[("a.png".to_string(), vec![1; 700]), ("b.png".to_string(), vec![2; 3])]).unwrap();
+
+        // header, two blocks of data, header, one block of data and the two empty blocks of the end
+        assert_eq!(archive.len(), 7 * 512);
+        for (offset, name, size) in [(0, "a.png", 700), (3 * 512, "b.png", 3)] {
+            let header = &archive[offset..offset + 512];
+            assert!(header.starts_with(name.as_bytes()));
+            assert_eq!(&header[124..136], format!("{size:011o}\0").as_bytes());
+            assert_eq!(&header[257..263], b"ustar\0");
+
+            let checksum = std::str::from_utf8(&header[148..154]).unwrap();
+            let sum: u32 = header
+                .iter()
+                .enumerate()
+                .map(|(i, &byte)| if (148..156).contains(&i) { b' ' as u32 } else { byte as u32 })
+                .sum();
+            assert_eq!(u32::from_str_radix(checksum, 8).unwrap(), sum);
+        }
+        assert!(archive[512 + 700..3 * 512].iter().all(|&byte| byte == 0));
+        assert!(archive[4 * 512 + 3..].iter().all(|&byte| byte == 0));
+    }
+}
@@ -0,0 +1,207 @@
+use image::{
+    imageops::{self, FilterType},
+    GrayImage, Luma, Pixel,
+};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use super::distortion::sample_bilinear;
+use super::processing::{rgb_of, set_rgba, BorderColor, ContentRect, Image, Processing, TrimOptions};
+
+/// Options of `Deskew::apply`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeskewOptions {
+    /// Largest skew searched for, in degrees either way, at most `Deskew::MAX_ANGLE`.
+    pub max_angle: f32,
+    /// Resolution of the search in degrees.
+    pub step: f32,
+    /// Trimming after the rotation, the corners uncovered by the rotation are filled with the border color.
+    pub trim: Option<TrimOptions>,
+}
+
+impl Default for DeskewOptions {
+    fn default() -> Self {
+        Self {
+            max_angle: 15.0,
+            step: 0.1,
+            trim: Some(TrimOptions {
+                color: BorderColor::Auto,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// Skew found in an image, the angle is clockwise in degrees and the image is corrected by rotating it
+/// by the opposite angle.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Skew {
+    pub angle: f32,
+    /// The content left after the correction, when trimmed.
+    pub content: Option<ContentRect>,
+}
+
+pub struct Deskew {}
+
+impl Deskew {
+    // Longest side of the image the skew is estimated on
+    const ANALYSIS_SIZE: u32 = 1000;
+    /// Larger skews are searched up to this angle, beyond it a vertical line is taken for a tilted horizontal one.
+    pub const MAX_ANGLE: f32 = 45.0;
+
+    /// Straighten a tilted scan, then trim its borders.
+    pub fn apply<P>(buf: &Image<P>, options: &DeskewOptions) -> anyhow::Result<(Image<P>, Skew)>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        if buf.width() == 0 || buf.height() == 0 {
+            anyhow::bail!("provided image is empty");
+        }
+        if !options.max_angle.is_finite() || !options.step.is_finite() {
+            anyhow::bail!("the angle and step of the search must be finite numbers");
+        }
+
+        let angle = Self::estimate_angle(buf, options.max_angle, options.step);
+
+        let Some(trim) = &options.trim else {
+            let rotated = Self::rotate_expanded(buf, -angle, [0.0; 4]);
+            return Ok((rotated, Skew { angle, content: None }));
+        };
+
+        // The corners are filled with the border color, so that the trimming removes them with the borders
+        let (fill, trim) = match trim.color {
+            BorderColor::Transparent => ([0.0; 4], trim.clone()),
+            _ => {
+                let [r, g, b] = Processing::border_color(buf, trim);
+                let trim = TrimOptions {
+                    color: BorderColor::Rgb([r, g, b].map(|c| c.round() as u8)),
+                    ..trim.clone()
+                };
+                ([r / 255.0, g / 255.0, b / 255.0, 1.0], trim)
+            }
+        };
+
+        let rotated = Self::rotate_expanded(buf, -angle, fill);
+        let content = Processing::find_content(&rotated, &trim)?;
+        let trimmed = Processing::crop_image(&rotated, content.x, content.y, content.width, content.height)?;
+
+        Ok((trimmed, Skew { angle, content: Some(content) }))
+    }
+
+    /// Estimate the skew of the lines of a document (text lines, table rules, page edges) within
+    /// `max_angle` (at most `MAX_ANGLE`) degrees of horizontal. The edges of a downscaled copy vote in a Hough transform and
+    /// the angle whose votes concentrate on the fewest lines wins.
+    pub fn estimate_angle<P: Pixel>(buf: &Image<P>, max_angle: f32, step: f32) -> f32 {
+        let (width, height) = buf.dimensions();
+        let gray = GrayImage::from_fn(width, height, |x, y| {
+            let [r, g, b] = rgb_of(buf.get_pixel(x, y));
+            Luma([(0.299 * r + 0.587 * g + 0.114 * b).round() as u8])
+        });
+
+        let scale = (Self::ANALYSIS_SIZE as f32 / width.max(height) as f32).min(1.0);
+        let (small_width, small_height) = (((width as f32 * scale) as u32).max(1), ((height as f32 * scale) as u32).max(1));
+        let gray = imageops::resize(&gray, small_width, small_height, FilterType::Triangle);
+        let edges = imageproc::edges::canny(&gray, 30.0, 90.0);
+
+        let points: Vec<(f32, f32)> = edges.enumerate_pixels().filter(|(_, _, px)| px[0] > 0).map(|(x, y, _)| (x as f32, y as f32)).collect();
+        if points.is_empty() {
+            return 0.0;
+        }
+
+        let step = step.max(0.01);
+        let steps = (max_angle.abs().min(Self::MAX_ANGLE) / step).round() as i32;
+        let diagonal = (gray.width() as f32).hypot(gray.height() as f32).ceil() as usize;
+
+        // Every edge point votes for the line through it at each angle, `rho` being the offset of the line
+        // along its normal. Squaring the votes rewards angles at which the points pile up on few lines.
+        (-steps..=steps)
+            .into_par_iter()
+            .map(|i| {
+                let angle = i as f32 * step;
+                let (sin, cos) = angle.to_radians().sin_cos();
+                let mut accumulator = vec![0u32; 2 * diagonal + 1];
+                for &(x, y) in points.iter() {
+                    let rho = y * cos - x * sin;
+                    accumulator[(rho.round() as i64 + diagonal as i64) as usize] += 1;
+                }
+                let score: u64 = accumulator.iter().map(|&votes| votes as u64 * votes as u64).sum();
+                // ties go to the smaller correction
+                (score, -(i.abs()), angle)
+            })
+            .max_by_key(|&(score, closeness, _)| (score, closeness))
+            .map(|(_, _, angle)| angle)
+            .unwrap_or(0.0)
+    }
+
+    /// Rotate clockwise by `angle` degrees onto a canvas large enough to keep the whole image, with the
+    /// uncovered corners in the RGBA `fill` color.
+    pub fn rotate_expanded<P>(buf: &Image<P>, angle: f32, fill: [f32; 4]) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = (buf.width() as f32, buf.height() as f32);
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let rotated_width = (width * cos.abs() + height * sin.abs()).round().max(1.0) as u32;
+        let rotated_height = (width * sin.abs() + height * cos.abs()).round().max(1.0) as u32;
+
+        let channels = P::CHANNEL_COUNT as usize;
+        let mut rotated: Image<P> = Image::new(rotated_width, rotated_height);
+        let (center_x, center_y) = (rotated_width as f32 / 2.0, rotated_height as f32 / 2.0);
+
+        // The source of every pixel is found through the inverse rotation, like in `Processing::rotate`
+        rotated.par_chunks_mut(rotated_width as usize * channels).enumerate().for_each(|(y, row)| {
+            let dy = y as f32 + 0.5 - center_y;
+            for (x, px) in row.chunks_exact_mut(channels).enumerate() {
+                let dx = x as f32 + 0.5 - center_x;
+                let source_x = cos * dx + sin * dy + width / 2.0 - 0.5;
+                let source_y = -sin * dx + cos * dy + height / 2.0 - 0.5;
+
+                match sample_bilinear(buf, source_x, source_y) {
+                    Some(sampled) => px.copy_from_slice(sampled.channels()),
+                    None => set_rgba(P::from_slice_mut(px), fill),
+                }
+            }
+        });
+
+        rotated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::*;
+
+    // Dark text-like lines on white paper, tilted clockwise by `angle` degrees
+    fn page(angle: f32) -> GrayImage {
+        let lines = GrayImage::from_fn(300, 200, |_, y| Luma([if y % 20 < 3 { 0 } else { 255 }]));
+        Deskew::rotate_expanded(&lines, angle, [1.0; 4])
+    }
+
+    #[test]
+    fn skew_of_the_lines_is_found() {
+        let angle = Deskew::estimate_angle(&page(4.0), 10.0, 0.5);
+        assert!((angle - 4.0).abs() <= 0.5, "estimated {angle}");
+    }
+
+    #[test]
+    fn search_is_limited_to_max_angle() {
+        assert!(Deskew::estimate_angle(&page(4.0), 2.0, 0.5).abs() <= 2.0);
+        assert!(Deskew::estimate_angle(&page(30.0), 1000.0, 1.0).abs() <= Deskew::MAX_ANGLE);
+    }
+
+    #[test]
+    fn non_finite_search_is_rejected() {
+        for (max_angle, step) in [(f32::INFINITY, 0.1), (f32::NAN, 0.1), (15.0, f32::NAN)] {
+            let options = DeskewOptions {
+                max_angle,
+                step,
+                ..Default::default()
+            };
+            assert!(Deskew::apply(&page(0.0), &options).is_err());
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use image::{Pixel, Primitive};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+use std::{f32::consts::PI, str::FromStr};
+
+use super::processing::{denormalized, normalized, Image};
+
+/// Axis along which an effect displaces the pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    Horizontal,
+    Vertical,
+    Both,
+}
+
+impl Axis {
+    fn horizontal(&self) -> bool {
+        matches!(self, Axis::Horizontal | Axis::Both)
+    }
+
+    fn vertical(&self) -> bool {
+        matches!(self, Axis::Vertical | Axis::Both)
+    }
+}
+
+impl FromStr for Axis {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "horizontal" | "x" => Ok(Axis::Horizontal),
+            "vertical" | "y" => Ok(Axis::Vertical),
+            "both" | "xy" => Ok(Axis::Both),
+            other => anyhow::bail!("unknown axis {other}"),
+        }
+    }
+}
+
+/// Distortion effect with its parameters. Lengths are in pixels, `radius` is relative to half of the
+/// shorter image side.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    /// Random jitter of every pixel by up to `amplitude` pixels.
+    Wobble { amplitude: u32, axis: Axis },
+    /// Rotation around the center by `strength` degrees, fading out towards `radius`.
+    Swirl { strength: f32, radius: f32 },
+    /// Sine wave displacement with the given `amplitude` and `wavelength`.
+    Ripple { amplitude: f32, wavelength: f32, axis: Axis },
+    /// Radial lens distortion bulging the image outwards, `strength` around 0.0 - 1.0.
+    Barrel { strength: f32 },
+    /// Radial lens distortion pinching the image inwards, `strength` around 0.0 - 1.0.
+    Pincushion { strength: f32 },
+    /// Circular fisheye magnifying the center, `strength` around 0.0 - 2.0.
+    Fisheye { strength: f32 },
+    /// Blocks of `block_size` x `block_size` pixels filled with their average color.
+    Pixelate { block_size: u32 },
+    /// Red and blue channels shifted apart by `shift` pixels and `slices` randomly displaced bands.
+    Glitch { shift: u32, slices: u32 },
+}
+
+pub struct Distortion {}
+
+impl Distortion {
+    /// Apply the effect, random effects draw from a generator seeded by `seed`, so the same image,
+    /// effect and seed always give the same output.
+    pub fn apply<P>(buf: &Image<P>, effect: &Effect, seed: u64) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        match *effect {
+            Effect::Wobble { amplitude, axis } => Self::wobble(buf, amplitude, axis, seed),
+            Effect::Swirl { strength, radius } => Self::swirl(buf, strength, radius),
+            Effect::Ripple { amplitude, wavelength, axis } => Self::ripple(buf, amplitude, wavelength, axis),
+            Effect::Barrel { strength } => Self::radial(buf, strength),
+            Effect::Pincushion { strength } => Self::radial(buf, -strength),
+            Effect::Fisheye { strength } => Self::fisheye(buf, strength),
+            Effect::Pixelate { block_size } => Self::pixelate(buf, block_size),
+            Effect::Glitch { shift, slices } => Self::glitch(buf, shift, slices, seed),
+        }
+    }
+
+    pub fn wobble<P>(buf: &Image<P>, amplitude: u32, axis: Axis, seed: u64) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = buf.dimensions();
+        let amplitude = amplitude as i64;
+        let channels = P::CHANNEL_COUNT as usize;
+        let mut wobbled = buf.clone();
+
+        wobbled.par_chunks_mut(width as usize * channels).enumerate().for_each(|(y, row)| {
+            // Every row has its own generator, so the output does not depend on the thread scheduling
+            let mut rng = row_rng(seed, y);
+            for (x, px) in row.chunks_exact_mut(channels).enumerate() {
+                let dx = if axis.horizontal() { rng.gen_range(-amplitude..=amplitude) } else { 0 };
+                let dy = if axis.vertical() { rng.gen_range(-amplitude..=amplitude) } else { 0 };
+                let (source_x, source_y) = (x as i64 + dx, y as i64 + dy);
+
+                if source_x >= 0 && source_y >= 0 && source_x < width as i64 && source_y < height as i64 {
+                    px.copy_from_slice(buf.get_pixel(source_x as u32, source_y as u32).channels());
+                }
+            }
+        });
+
+        wobbled
+    }
+
+    pub fn swirl<P>(buf: &Image<P>, strength: f32, radius: f32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (cx, cy) = center(buf);
+        let radius = radius * cx.min(cy);
+        let strength = strength.to_radians();
+
+        Self::remap(buf, |x, y| {
+            let (dx, dy) = (x - cx, y - cy);
+            let r = (dx * dx + dy * dy).sqrt();
+            if r >= radius {
+                return (x, y);
+            }
+
+            let fade = 1.0 - r / radius;
+            let angle = dy.atan2(dx) + strength * fade * fade;
+            (cx + r * angle.cos(), cy + r * angle.sin())
+        })
+    }
+
+    pub fn ripple<P>(buf: &Image<P>, amplitude: f32, wavelength: f32, axis: Axis) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let wavelength = wavelength.max(1.0);
+
+        Self::remap(buf, |x, y| {
+            let dx = if axis.horizontal() { amplitude * (2.0 * PI * y / wavelength).sin() } else { 0.0 };
+            let dy = if axis.vertical() { amplitude * (2.0 * PI * x / wavelength).sin() } else { 0.0 };
+            (x + dx, y + dy)
+        })
+    }
+
+    // Barrel distortion for positive k, pincushion for negative k
+    fn radial<P>(buf: &Image<P>, k: f32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (cx, cy) = center(buf);
+        let max_r2 = cx * cx + cy * cy;
+
+        Self::remap(buf, |x, y| {
+            let (dx, dy) = (x - cx, y - cy);
+            let scale = 1.0 + k * (dx * dx + dy * dy) / max_r2;
+            (cx + dx * scale, cy + dy * scale)
+        })
+    }
+
+    pub fn fisheye<P>(buf: &Image<P>, strength: f32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (cx, cy) = center(buf);
+        let radius = cx.min(cy);
+
+        Self::remap(buf, |x, y| {
+            let (dx, dy) = (x - cx, y - cy);
+            let r = (dx * dx + dy * dy).sqrt() / radius;
+            if r > 1.0 {
+                // outside of the lens circle
+                return (-1.0, -1.0);
+            }
+
+            let scale = r.powf(strength);
+            (cx + dx * scale, cy + dy * scale)
+        })
+    }
+
+    pub fn pixelate<P>(buf: &Image<P>, block_size: u32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let block_size = block_size.max(1) as usize;
+        let channels = P::CHANNEL_COUNT as usize;
+        let width = buf.width() as usize;
+        let row_len = width * channels;
+        let mut pixelated = buf.clone();
+
+        pixelated.par_chunks_mut(row_len * block_size).for_each(|band| {
+            let rows = band.len() / row_len;
+            for block_x in (0..width).step_by(block_size) {
+                let block_end = (block_x + block_size).min(width);
+                let count = ((block_end - block_x) * rows) as f32;
+
+                let mut sum = vec![0.0f32; channels];
+                for row in band.chunks_exact(row_len) {
+                    for px in row[block_x * channels..block_end * channels].chunks_exact(channels) {
+                        for c in 0..channels {
+                            sum[c] += normalized(px[c]);
+                        }
+                    }
+                }
+
+                let average: Vec<P::Subpixel> = sum.iter().map(|channel| denormalized(channel / count)).collect();
+                for row in band.chunks_exact_mut(row_len) {
+                    for px in row[block_x * channels..block_end * channels].chunks_exact_mut(channels) {
+                        px.copy_from_slice(&average);
+                    }
+                }
+            }
+        });
+
+        pixelated
+    }
+
+    pub fn glitch<P>(buf: &Image<P>, shift: u32, slices: u32, seed: u64) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = buf.dimensions();
+        let shift = shift as i64;
+
+        // Bands are drawn up front from a single generator, as `(first row, last row, offset)`
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let bands: Vec<(u32, u32, i64)> = (0..slices)
+            .map(|_| {
+                let start = rng.gen_range(0..height.max(1));
+                let end = (start + rng.gen_range(1..=(height / 10).max(1))).min(height);
+                let offset = rng.gen_range(-4 * shift..=4 * shift);
+                (start, end, offset)
+            })
+            .collect();
+
+        // Grayscale images only have the offset bands, there are no channels to shift apart
+        let channels = P::CHANNEL_COUNT as usize;
+        let shifted = P::COLOR_MODEL.starts_with("RGB");
+
+        let mut glitched = buf.clone();
+        glitched.par_chunks_mut(width as usize * channels).enumerate().for_each(|(y, row)| {
+            let y = y as u32;
+            let offset: i64 = bands.iter().filter(|(start, end, _)| (*start..*end).contains(&y)).map(|(_, _, offset)| offset).sum();
+
+            for (x, px) in row.chunks_exact_mut(channels).enumerate() {
+                let x = x as i64 + offset;
+                px.copy_from_slice(buf.get_pixel(clamp_x(x, width), y).channels());
+                if shifted {
+                    // red is pulled from the left, blue from the right, green and alpha stay in place
+                    px[0] = buf.get_pixel(clamp_x(x - shift, width), y).channels()[0];
+                    px[2] = buf.get_pixel(clamp_x(x + shift, width), y).channels()[2];
+                }
+            }
+        });
+
+        glitched
+    }
+
+    /// Build a new image by sampling every pixel from the source coordinates given by `mapping`,
+    /// pixels mapped outside of the image are zeroed (black, or transparent with alpha).
+    pub fn remap<P, F>(buf: &Image<P>, mapping: F) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+        F: Fn(f32, f32) -> (f32, f32) + Sync,
+    {
+        let (width, height) = buf.dimensions();
+        Self::resample(buf, width, height, mapping)
+    }
+
+    /// Like `remap`, into a new image of a different size.
+    pub fn resample<P, F>(buf: &Image<P>, width: u32, height: u32, mapping: F) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+        F: Fn(f32, f32) -> (f32, f32) + Sync,
+    {
+        let channels = P::CHANNEL_COUNT as usize;
+        let mut remapped: Image<P> = Image::new(width, height);
+
+        remapped.par_chunks_mut(width as usize * channels).enumerate().for_each(|(y, row)| {
+            for (x, px) in row.chunks_exact_mut(channels).enumerate() {
+                let (source_x, source_y) = mapping(x as f32, y as f32);
+                if let Some(sampled) = sample_bilinear(buf, source_x, source_y) {
+                    px.copy_from_slice(sampled.channels());
+                }
+            }
+        });
+
+        remapped
+    }
+}
+
+/// Sample the image at fractional coordinates by interpolating the four neighbouring pixels,
+/// `None` when the coordinates lie outside of the image.
+pub fn sample_bilinear<P: Pixel>(buf: &Image<P>, x: f32, y: f32) -> Option<P> {
+    let (width, height) = buf.dimensions();
+    if !(x >= 0.0 && y >= 0.0 && x <= (width - 1) as f32 && y <= (height - 1) as f32) {
+        return None;
+    }
+
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let (tl, tr) = (buf.get_pixel(x0, y0), buf.get_pixel(x1, y0));
+    let (bl, br) = (buf.get_pixel(x0, y1), buf.get_pixel(x1, y1));
+
+    let mut sampled = *tl;
+    for (c, channel) in sampled.channels_mut().iter_mut().enumerate() {
+        let top = lerp(tl.channels()[c], tr.channels()[c], fx);
+        let bottom = lerp(bl.channels()[c], br.channels()[c], fx);
+        *channel = denormalized(top * (1.0 - fy) + bottom * fy);
+    }
+
+    Some(sampled)
+}
+
+fn lerp<T: Primitive>(a: T, b: T, t: f32) -> f32 {
+    normalized(a) * (1.0 - t) + normalized(b) * t
+}
+
+fn center<P: Pixel>(buf: &Image<P>) -> (f32, f32) {
+    ((buf.width() as f32 - 1.0) / 2.0, (buf.height() as f32 - 1.0) / 2.0)
+}
+
+fn clamp_x(x: i64, width: u32) -> u32 {
+    x.clamp(0, width as i64 - 1) as u32
+}
+
+/// Generator of a single row, derived from the request seed and the row index.
+pub fn row_rng(seed: u64, y: usize) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(y as u64);
+    rng
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(32, 24, |x, y| Rgb([(x * 8) as u8, (y * 10) as u8, ((x + y) * 4) as u8]))
+    }
+
+    #[test]
+    fn random_effects_follow_the_seed() {
+        let effects = [
+            Effect::Wobble {
+                amplitude: 3,
+                axis: Axis::Both,
+            },
+            Effect::Glitch { shift: 2, slices: 4 },
+        ];
+
+        for effect in &effects {
+            let once = Distortion::apply(&gradient(), effect, 7);
+            assert_eq!(Distortion::apply(&gradient(), effect, 7), once);
+            assert_ne!(Distortion::apply(&gradient(), effect, 8), once);
+        }
+    }
+
+    #[test]
+    fn effects_without_strength_leave_the_image_alone() {
+        let effects = [
+            Effect::Wobble {
+                amplitude: 0,
+                axis: Axis::Both,
+            },
+            Effect::Swirl { strength: 0.0, radius: 1.0 },
+            Effect::Ripple {
+                amplitude: 0.0,
+                wavelength: 10.0,
+                axis: Axis::Both,
+            },
+            Effect::Barrel { strength: 0.0 },
+            Effect::Pincushion { strength: 0.0 },
+            Effect::Pixelate { block_size: 1 },
+            Effect::Glitch { shift: 0, slices: 0 },
+        ];
+
+        for effect in &effects {
+            assert_eq!(Distortion::apply(&gradient(), effect, 1), gradient(), "{effect:?}");
+        }
+    }
+
+    #[test]
+    fn wobble_moves_pixels_only_along_its_axis() {
+        let columns = GrayImage::from_fn(20, 20, |x, _| Luma([(x * 10) as u8]));
+        let rows = GrayImage::from_fn(20, 20, |_, y| Luma([(y * 10) as u8]));
+
+        assert_eq!(Distortion::wobble(&columns, 4, Axis::Vertical, 3), columns);
+        assert_eq!(Distortion::wobble(&rows, 4, Axis::Horizontal, 3), rows);
+        assert_ne!(Distortion::wobble(&columns, 4, Axis::Horizontal, 3), columns);
+    }
+
+    #[test]
+    fn pixelate_fills_blocks_with_their_average() {
+        let checkered = RgbaImage::from_fn(5, 5, |x, y| match (x + y) % 2 {
+            0 => Rgba([200, 100, 0, 255]),
+            _ => Rgba([0, 100, 200, 55]),
+        });
+        let pixelated = Distortion::pixelate(&checkered, 2);
+
+        assert_eq!(*pixelated.get_pixel(0, 0), Rgba([100, 100, 100, 155]));
+        assert_eq!(pixelated.get_pixel(1, 1), pixelated.get_pixel(0, 0));
+        assert_eq!(*pixelated.get_pixel(0, 4), Rgba([100, 100, 100, 155]));
+        // the last block of the last row is a single pixel
+        assert_eq!(*pixelated.get_pixel(4, 4), Rgba([200, 100, 0, 255]));
+    }
+
+    #[test]
+    fn samples_are_interpolated_inside_of_the_image_only() {
+        let image = GrayImage::from_fn(2, 2, |x, y| Luma([[[0, 100], [50, 250]][y as usize][x as usize]]));
+
+        assert_eq!(sample_bilinear(&image, 0.5, 0.0), Some(Luma([50])));
+        assert_eq!(sample_bilinear(&image, 0.5, 0.5), Some(Luma([100])));
+        assert_eq!(sample_bilinear(&image, 1.0, 1.0), Some(Luma([250])));
+        assert_eq!(sample_bilinear(&image, -0.1, 0.0), None);
+        assert_eq!(sample_bilinear(&image, 0.0, 1.1), None);
+    }
+
+    #[test]
+    fn fisheye_clears_the_corners_and_keeps_the_center() {
+        let image = RgbaImage::from_pixel(21, 21, Rgba([10, 20, 30, 255]));
+        let lens = Distortion::fisheye(&image, 1.0);
+
+        assert_eq!(*lens.get_pixel(10, 10), Rgba([10, 20, 30, 255]));
+        assert_eq!(*lens.get_pixel(0, 0), Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn glitch_shifts_red_and_blue_apart() {
+        let dot = RgbImage::from_fn(9, 1, |x, _| if x == 4 { Rgb([255, 255, 255]) } else { Rgb([0, 0, 0]) });
+        let glitched = Distortion::glitch(&dot, 2, 0, 0);
+
+        assert_eq!(*glitched.get_pixel(6, 0), Rgb([255, 0, 0]));
+        assert_eq!(*glitched.get_pixel(4, 0), Rgb([0, 255, 0]));
+        assert_eq!(*glitched.get_pixel(2, 0), Rgb([0, 0, 255]));
+    }
+}
@@ -0,0 +1,333 @@
+use image::{imageops::FilterType, DynamicImage};
+use serde::{Serialize, Serializer};
+use std::{
+    f32::consts::PI,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Perceptual hash algorithm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashKind {
+    /// Brightness of an 8x8 thumbnail against its mean.
+    Average,
+    /// Brightness gradients between neighbours of a 9x8 thumbnail.
+    Difference,
+    /// Low frequencies of the DCT of a 32x32 thumbnail against their median, the most robust one.
+    Perceptual,
+}
+
+impl FromStr for HashKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ahash" | "average" => Ok(HashKind::Average),
+            "dhash" | "difference" => Ok(HashKind::Difference),
+            "phash" | "perceptual" => Ok(HashKind::Perceptual),
+            other => anyhow::bail!("unknown hash {other}"),
+        }
+    }
+}
+
+/// 64 bit perceptual hash, similar images have hashes differing in few bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageHash(pub u64);
+
+impl ImageHash {
+    /// Number of differing bits, 0 - 64.
+    pub fn distance(&self, other: &ImageHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+impl fmt::Display for ImageHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for ImageHash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ImageHash(u64::from_str_radix(s.trim(), 16)?))
+    }
+}
+
+// Hashes are serialized as hex strings, 64 bit integers do not survive JSON numbers
+impl Serialize for ImageHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// All the hashes of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Hashes {
+    pub ahash: ImageHash,
+    pub dhash: ImageHash,
+    pub phash: ImageHash,
+}
+
+impl Hashes {
+    pub fn get(&self, kind: HashKind) -> ImageHash {
+        match kind {
+            HashKind::Average => self.ahash,
+            HashKind::Difference => self.dhash,
+            HashKind::Perceptual => self.phash,
+        }
+    }
+}
+
+pub struct Hashing {}
+
+impl Hashing {
+    pub fn hashes(image: &DynamicImage) -> Hashes {
+        Hashes {
+            ahash: Self::hash(image, HashKind::Average),
+            dhash: Self::hash(image, HashKind::Difference),
+            phash: Self::hash(image, HashKind::Perceptual),
+        }
+    }
+
+    pub fn hash(image: &DynamicImage, kind: HashKind) -> ImageHash {
+        match kind {
+            HashKind::Average => {
+                let pixels = thumbnail(image, 8, 8);
+                let mean = pixels.iter().sum::<f32>() / pixels.len() as f32;
+                bits(pixels.iter().map(|&px| px > mean))
+            }
+            HashKind::Difference => {
+                let pixels = thumbnail(image, 9, 8);
+                bits(pixels.chunks_exact(9).flat_map(|row| row.windows(2).map(|pair| pair[0] < pair[1])))
+            }
+            HashKind::Perceptual => {
+                let pixels = thumbnail(image, 32, 32);
+                let coefficients = dct_low_frequencies(&pixels, 32, 8);
+                // the DC coefficient is the mean brightness, which would skew the median
+                let mut sorted = coefficients[1..].to_vec();
+                sorted.sort_by(f32::total_cmp);
+                let median = sorted[sorted.len() / 2];
+                bits(coefficients.iter().map(|&c| c > median))
+            }
+        }
+    }
+}
+
+// Brightness of the image scaled to `width` x `height`, row by row
+fn thumbnail(image: &DynamicImage, width: u32, height: u32) -> Vec<f32> {
+    image.resize_exact(width, height, FilterType::Triangle).to_luma32f().into_raw()
+}
+
+// Pack up to 64 booleans into a hash, the first one being the highest bit
+fn bits(values: impl Iterator<Item = bool>) -> ImageHash {
+    ImageHash(values.take(64).fold(0u64, |hash, bit| (hash << 1) | bit as u64))
+}
+
+// The `count` x `count` lowest frequencies of the 2D DCT-II of a `size` x `size` image, row by row
+fn dct_low_frequencies(pixels: &[f32], size: usize, count: usize) -> Vec<f32> {
+    let basis: Vec<f32> = (0..count * size)
+        .map(|i| {
+            let (frequency, x) = (i / size, i % size);
+            (PI * frequency as f32 * (2 * x + 1) as f32 / (2 * size) as f32).cos()
+        })
+        .collect();
+
+    // The transform is separable, first along the rows then along the columns
+    let mut rows = vec![0.0f32; size * count];
+    for y in 0..size {
+        for u in 0..count {
+            rows[y * count + u] = (0..size).map(|x| pixels[y * size + x] * basis[u * size + x]).sum();
+        }
+    }
+
+    let mut coefficients = vec![0.0f32; count * count];
+    for v in 0..count {
+        for u in 0..count {
+            coefficients[v * count + u] = (0..size).map(|y| rows[y * count + u] * basis[v * size + y]).sum();
+        }
+    }
+
+    coefficients
+}
+
+/// Registered image found close to a queried one.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Match {
+    pub id: String,
+    pub distance: u32,
+}
+
+/// Hashes of registered images, searched for near-duplicates by Hamming distance. The index is kept in
+/// memory and, when it has a path, rewritten to that file on every change as lines of `id ahash dhash phash`.
+#[derive(Debug, Default)]
+pub struct HashIndex {
+    path: Option<PathBuf>,
+    entries: Vec<(String, Hashes)>,
+}
+
+impl HashIndex {
+    /// Index persisted to the file at `path`, loaded from it when the file exists.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = Vec::new();
+
+        if path.exists() {
+            for (i, line) in fs::read_to_string(&path)?.lines().enumerate() {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                match fields[..] {
+                    [] => continue,
+                    [id, ahash, dhash, phash] => entries.push((
+                        id.to_string(),
+                        Hashes {
+                            ahash: ahash.parse()?,
+                            dhash: dhash.parse()?,
+                            phash: phash.parse()?,
+                        },
+                    )),
+                    _ => anyhow::bail!("malformed line {} of the hash index {}", i + 1, path.display()),
+                }
+            }
+        }
+
+        println!("Loaded hash index {} with {} images", path.display(), entries.len());
+        Ok(Self { path: Some(path), entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Register the hashes of an image under `id`, replacing the hashes already registered under it.
+    pub fn insert(&mut self, id: &str, hashes: Hashes) -> anyhow::Result<()> {
+        if id.is_empty() || id.contains(char::is_whitespace) {
+            anyhow::bail!("image ids must be non-empty and without whitespace, got {id:?}");
+        }
+
+        match self.entries.iter_mut().find(|(existing, _)| existing == id) {
+            Some(entry) => entry.1 = hashes,
+            None => self.entries.push((id.to_string(), hashes)),
+        }
+
+        self.save()
+    }
+
+    /// Registered images whose `kind` hash is at most `max_distance` bits away, closest first.
+    pub fn query(&self, hashes: &Hashes, kind: HashKind, max_distance: u32) -> Vec<Match> {
+        let hash = hashes.get(kind);
+        let mut matches: Vec<Match> = self
+            .entries
+            .iter()
+            .map(|(id, registered)| Match {
+                id: id.clone(),
+                distance: registered.get(kind).distance(&hash),
+            })
+            .filter(|m| m.distance <= max_distance)
+            .collect();
+
+        matches.sort_by_key(|m| m.distance);
+        matches
+    }
+
+    // The file is replaced as a whole, through a temporary file so that a crash does not truncate it
+    fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let contents: String = self.entries.iter().map(|(id, h)| format!("{id} {} {} {}\n", h.ahash, h.dhash, h.phash)).collect();
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, GrayImage, Luma};
+
+    use super::*;
+
+    const KINDS: [HashKind; 3] = [HashKind::Average, HashKind::Difference, HashKind::Perceptual];
+
+    // Bright disc and dark bar on a diagonal gradient, drawn at any size
+    fn scene(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+            let (u, v) = (x as f32 / width as f32, y as f32 / height as f32);
+            let value = if (u - 0.3).hypot(v - 0.4) < 0.2 {
+                240.0
+            } else if (0.6..0.8).contains(&u) && v > 0.3 {
+                20.0
+            } else {
+                60.0 + 100.0 * (u + v) / 2.0
+            };
+            Luma([value as u8])
+        }))
+    }
+
+    fn checkers() -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(128, 128, |x, y| Luma([if (x / 16 + y / 16) % 2 == 0 { 255 } else { 0 }])))
+    }
+
+    #[test]
+    fn distance_counts_the_differing_bits() {
+        assert_eq!(ImageHash(0).distance(&ImageHash(0)), 0);
+        assert_eq!(ImageHash(0b1011).distance(&ImageHash(0b0001)), 2);
+        assert_eq!(ImageHash(0).distance(&ImageHash(u64::MAX)), 64);
+    }
+
+    #[test]
+    fn rescaled_image_keeps_its_hashes() {
+        for kind in KINDS {
+            let distance = Hashing::hash(&scene(256, 256), kind).distance(&Hashing::hash(&scene(100, 100), kind));
+            assert!(distance <= 4, "{kind:?} hashes of a rescaled image are {distance} bits apart");
+        }
+    }
+
+    #[test]
+    fn different_images_are_far_apart() {
+        for kind in KINDS {
+            let distance = Hashing::hash(&scene(128, 128), kind).distance(&Hashing::hash(&checkers(), kind));
+            assert!(distance >= 16, "{kind:?} hashes of different images are only {distance} bits apart");
+        }
+    }
+
+    #[test]
+    fn hashes_round_trip_through_hex() {
+        let hash = ImageHash(0x0123_4567_89ab_cdef);
+        assert_eq!(hash.to_string(), "0123456789abcdef");
+        assert_eq!("0123456789abcdef".parse::<ImageHash>().unwrap(), hash);
+    }
+
+    #[test]
+    fn index_finds_the_closest_images_first() {
+        let mut index = HashIndex::default();
+        index.insert("scene", Hashing::hashes(&scene(128, 128))).unwrap();
+        index.insert("checkers", Hashing::hashes(&checkers())).unwrap();
+        assert!(index.insert("with space", Hashing::hashes(&checkers())).is_err());
+
+        let matches = index.query(&Hashing::hashes(&scene(90, 90)), HashKind::Perceptual, 64);
+        assert_eq!(matches.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), vec!["scene", "checkers"]);
+        assert_eq!(index.query(&Hashing::hashes(&scene(90, 90)), HashKind::Perceptual, 4).len(), 1);
+    }
+
+    #[test]
+    fn index_is_persisted() {
+        let path = std::env::temp_dir().join(format!("hash-index-{}.txt", std::process::id()));
+        let hashes = Hashing::hashes(&checkers());
+        HashIndex::open(&path).unwrap().insert("checkers", hashes).unwrap();
+
+        let reopened = HashIndex::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reopened.len(), 1);
+        assert_eq!(reopened.query(&hashes, HashKind::Average, 0)[0].id, "checkers");
+    }
+}
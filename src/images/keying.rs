@@ -0,0 +1,164 @@
+use image::{DynamicImage, GrayImage, Luma, Pixel};
+use rayon::prelude::*;
+
+use super::color::{rgb_to_ycbcr, ycbcr_to_rgb};
+use super::processing::{rgba_of, set_rgba, BorderColor, Image, Processing, TrimOptions};
+
+/// Options of `Keying::apply`, distances are between the chroma (CbCr) of the colors on the 0 - 255 scale.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyOptions {
+    /// Color keyed out, detected from the corners of the image when `None`.
+    pub color: Option<[u8; 3]>,
+    /// Colors closer to the key than this are fully transparent.
+    pub tolerance: f32,
+    /// Width of the ramp from transparent to opaque beyond the tolerance, softening the edges.
+    pub softness: f32,
+    /// 0.0 - 1.0, how much of the key color reflected onto the foreground is removed.
+    pub spill: f32,
+}
+
+impl Default for KeyOptions {
+    fn default() -> Self {
+        Self {
+            color: Some([0, 255, 0]),
+            tolerance: 40.0,
+            softness: 30.0,
+            spill: 0.5,
+        }
+    }
+}
+
+pub struct Keying {}
+
+impl Keying {
+    /// Key a color out of the image, into its alpha. The image needs an alpha channel to hold the result,
+    /// see `with_alpha`.
+    pub fn apply<P>(buf: &mut Image<P>, options: &KeyOptions)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let key = match options.color {
+            Some(color) => color.map(|c| c as f32 / 255.0),
+            None => {
+                let auto = TrimOptions {
+                    color: BorderColor::Auto,
+                    ..Default::default()
+                };
+                Processing::border_color(buf, &auto).map(|c| c / 255.0)
+            }
+        };
+
+        let (_, key_cb, key_cr) = rgb_to_ycbcr(key);
+        // unit vector of the key in the chroma plane, the direction the spill is removed along
+        let key_length = key_cb.hypot(key_cr).max(f32::EPSILON);
+        let direction = (key_cb / key_length, key_cr / key_length);
+        let (tolerance, softness) = (options.tolerance / 255.0, options.softness.max(0.0) / 255.0);
+        let spill = options.spill.clamp(0.0, 1.0);
+
+        let channels = P::CHANNEL_COUNT as usize;
+        let row_len = buf.width() as usize * channels;
+        buf.par_chunks_mut(row_len).for_each(|row| {
+            for px in row.chunks_exact_mut(channels) {
+                let px = P::from_slice_mut(px);
+                let [r, g, b, a] = rgba_of(px);
+                let (y, cb, cr) = rgb_to_ycbcr([r, g, b]);
+
+                let distance = (cb - key_cb).hypot(cr - key_cr);
+                let opacity = if softness > 0.0 { ((distance - tolerance) / softness).clamp(0.0, 1.0) } else { (distance > tolerance) as u8 as f32 };
+
+                // The chroma towards the key is removed from what remains, keeping the luminance
+                let along = cb * direction.0 + cr * direction.1;
+                let (cb, cr) = if along > 0.0 { (cb - direction.0 * along * spill, cr - direction.1 * along * spill) } else { (cb, cr) };
+                let [r, g, b] = ycbcr_to_rgb(y, cb, cr);
+
+                set_rgba(px, [r, g, b, a * opacity]);
+            }
+        });
+    }
+
+    /// Alpha of the image as a grayscale mask, white where opaque.
+    pub fn mask(image: &DynamicImage) -> GrayImage {
+        let rgba = image.to_rgba32f();
+        GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| Luma([(rgba.get_pixel(x, y)[3].clamp(0.0, 1.0) * 255.0).round() as u8]))
+    }
+}
+
+/// The image with an alpha channel added (opaque) if it has none, keeping its bit depth.
+pub fn with_alpha(image: DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageLuma8(_) => DynamicImage::ImageLumaA8(image.into_luma_alpha8()),
+        DynamicImage::ImageRgb8(_) => DynamicImage::ImageRgba8(image.into_rgba8()),
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageLumaA16(image.into_luma_alpha16()),
+        DynamicImage::ImageRgb16(_) => DynamicImage::ImageRgba16(image.into_rgba16()),
+        DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgba32F(image.into_rgba32f()),
+        image => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    // Subject of `color` in the middle of a `screen` background
+    fn shot(screen: [u8; 3], color: [u8; 3]) -> RgbaImage {
+        RgbaImage::from_fn(20, 20, |x, y| {
+            let subject = (5..15).contains(&x) && (5..15).contains(&y);
+            let [r, g, b] = if subject { color } else { screen };
+            Rgba([r, g, b, 255])
+        })
+    }
+
+    #[test]
+    fn key_color_becomes_transparent() {
+        let mut image = shot([0, 255, 0], [200, 40, 40]);
+        Keying::apply(&mut image, &KeyOptions::default());
+
+        assert_eq!(image.get_pixel(0, 0)[3], 0);
+        assert_eq!(image.get_pixel(10, 10)[3], 255);
+        let mask = Keying::mask(&DynamicImage::ImageRgba8(image));
+        assert_eq!((mask.get_pixel(0, 0)[0], mask.get_pixel(10, 10)[0]), (0, 255));
+    }
+
+    #[test]
+    fn key_is_detected_from_the_corners() {
+        let mut image = shot([20, 40, 220], [230, 200, 40]);
+        let options = KeyOptions {
+            color: None,
+            ..Default::default()
+        };
+        Keying::apply(&mut image, &options);
+
+        assert_eq!(image.get_pixel(19, 19)[3], 0);
+        assert_eq!(image.get_pixel(10, 10)[3], 255);
+    }
+
+    #[test]
+    fn softness_ramps_the_edges_and_spill_is_removed() {
+        // a greenish gray, between the key and the foreground colors
+        let mut image = RgbaImage::from_pixel(1, 1, Rgba([100, 160, 100, 255]));
+        let options = KeyOptions {
+            tolerance: 0.0,
+            softness: 255.0,
+            spill: 1.0,
+            ..Default::default()
+        };
+        Keying::apply(&mut image, &options);
+
+        let [r, g, b, a] = image.get_pixel(0, 0).0;
+        assert!(a > 0 && a < 255, "alpha {a}");
+        assert!(g.abs_diff(r) <= 2 && g.abs_diff(b) <= 2, "green spill left in {r},{g},{b}");
+    }
+
+    #[test]
+    fn alpha_is_added_at_the_same_depth() {
+        let rgb = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([1, 2, 3])));
+        assert_eq!(with_alpha(rgb).color(), image::ColorType::Rgba8);
+        let rgb16 = DynamicImage::ImageRgb16(image::ImageBuffer::new(2, 2));
+        assert_eq!(with_alpha(rgb16).color(), image::ColorType::Rgba16);
+        let rgba = DynamicImage::ImageRgba8(RgbaImage::new(2, 2));
+        assert_eq!(with_alpha(rgba).color(), image::ColorType::Rgba8);
+    }
+}
@@ -1,85 +1,200 @@
+pub mod animation;
+pub mod annotation;
+pub mod collage;
+pub mod color;
+pub mod comparison;
+pub mod compositing;
+pub mod conversion;
+pub mod cropping;
+pub mod deskew;
+pub mod distortion;
+pub mod hashing;
+pub mod keying;
+pub mod ops;
+pub mod palette;
+pub mod perspective;
 pub mod processing;
+pub mod retarget;
+pub mod stylize;
+pub mod text;
+pub mod thumbnail;
+pub mod tiling;
 
-use std::io::{BufWriter, Cursor};
 use std::time::Instant;
 
-use axum::extract::multipart::Field;
-use image::{io::Reader as ImageReader, load_from_memory, ImageBuffer, ImageFormat, Rgb, RgbImage};
-use imageproc::{
-    drawing::{draw_hollow_rect},
-    rect::Rect,
-};
-use tract_onnx::tract_hir::tract_num_traits::ToPrimitive;
+use axum::extract::{multipart::Field, Multipart};
+use image::{io::Reader as ImageReader, DynamicImage, ImageFormat, RgbImage};
+
+use self::animation::Animation;
+use self::annotation::{Annotation, AnnotationOptions};
+use self::conversion::{Conversion, Encoding};
+use crate::core::Detection;
+
+/// Run an operation on the image buffer inside of a `DynamicImage`, whatever its color type and bit depth,
+/// the operation being generic over the `image::Pixel` of `processing::Image`.
+///
+/// `dynamic_map!(image, buf => expr)` gives `buf` by reference and wraps the resulting buffer back into
+/// the same variant. `dynamic_map!(ref image, buf => expr)` and `dynamic_map!(mut image, buf => expr)`
+/// give `buf` by (mutable) reference and return the value of the expression as it is.
+#[macro_export]
+macro_rules! dynamic_map {
+    (mut $image:expr, $buf:ident => $body:expr) => {
+        match $image {
+            image::DynamicImage::ImageLuma8(ref mut $buf) => $body,
+            image::DynamicImage::ImageLumaA8(ref mut $buf) => $body,
+            image::DynamicImage::ImageRgb8(ref mut $buf) => $body,
+            image::DynamicImage::ImageRgba8(ref mut $buf) => $body,
+            image::DynamicImage::ImageLuma16(ref mut $buf) => $body,
+            image::DynamicImage::ImageLumaA16(ref mut $buf) => $body,
+            image::DynamicImage::ImageRgb16(ref mut $buf) => $body,
+            image::DynamicImage::ImageRgba16(ref mut $buf) => $body,
+            image::DynamicImage::ImageRgb32F(ref mut $buf) => $body,
+            image::DynamicImage::ImageRgba32F(ref mut $buf) => $body,
+            ref mut other => {
+                // color types added in later versions of `image` are processed as float RGBA
+                let $buf = &mut other.to_rgba32f();
+                let result = $body;
+                *other = image::DynamicImage::ImageRgba32F($buf.clone());
+                result
+            }
+        }
+    };
+    (ref $image:expr, $buf:ident => $body:expr) => {
+        match $image {
+            image::DynamicImage::ImageLuma8(ref $buf) => $body,
+            image::DynamicImage::ImageLumaA8(ref $buf) => $body,
+            image::DynamicImage::ImageRgb8(ref $buf) => $body,
+            image::DynamicImage::ImageRgba8(ref $buf) => $body,
+            image::DynamicImage::ImageLuma16(ref $buf) => $body,
+            image::DynamicImage::ImageLumaA16(ref $buf) => $body,
+            image::DynamicImage::ImageRgb16(ref $buf) => $body,
+            image::DynamicImage::ImageRgba16(ref $buf) => $body,
+            image::DynamicImage::ImageRgb32F(ref $buf) => $body,
+            image::DynamicImage::ImageRgba32F(ref $buf) => $body,
+            ref other => {
+                let $buf = &other.to_rgba32f();
+                $body
+            }
+        }
+    };
+    ($image:expr, $buf:ident => $body:expr) => {
+        match $image {
+            image::DynamicImage::ImageLuma8(ref $buf) => image::DynamicImage::ImageLuma8($body),
+            image::DynamicImage::ImageLumaA8(ref $buf) => image::DynamicImage::ImageLumaA8($body),
+            image::DynamicImage::ImageRgb8(ref $buf) => image::DynamicImage::ImageRgb8($body),
+            image::DynamicImage::ImageRgba8(ref $buf) => image::DynamicImage::ImageRgba8($body),
+            image::DynamicImage::ImageLuma16(ref $buf) => image::DynamicImage::ImageLuma16($body),
+            image::DynamicImage::ImageLumaA16(ref $buf) => image::DynamicImage::ImageLumaA16($body),
+            image::DynamicImage::ImageRgb16(ref $buf) => image::DynamicImage::ImageRgb16($body),
+            image::DynamicImage::ImageRgba16(ref $buf) => image::DynamicImage::ImageRgba16($body),
+            image::DynamicImage::ImageRgb32F(ref $buf) => image::DynamicImage::ImageRgb32F($body),
+            image::DynamicImage::ImageRgba32F(ref $buf) => image::DynamicImage::ImageRgba32F($body),
+            ref other => {
+                // color types added in later versions of `image` are processed as float RGBA
+                let $buf = &other.to_rgba32f();
+                image::DynamicImage::ImageRgba32F($body)
+            }
+        }
+    };
+}
 
-pub fn load_image_buffer(path: &str) -> anyhow::Result<RgbImage> {
+// Images are kept in their decoded color type and bit depth, so alpha, grayscale and 16-bit survive
+// the processing, use `to_rgb8()` where plain RGB is needed (e.g. for the neural inference).
+pub fn load_image_buffer(path: &str) -> anyhow::Result<DynamicImage> {
     let start = Instant::now();
     let img = ImageReader::open(path)?.decode()?;
 
-    let buf: RgbImage = img.into_rgb8(); // convert to rgb immediately
+    println!("Loaded {:?} image {path} as {}x{} in {:?}", img.color(), img.width(), img.height(), start.elapsed());
+
+    Ok(img)
+}
+
+pub async fn load_image_from_bytes(field: Field<'_>) -> anyhow::Result<DynamicImage> {
+    let start = Instant::now();
+
+    let name = field.file_name().unwrap_or_default().to_string();
+    let data = field.bytes().await?;
+    let (img, _) = Conversion::decode(&data, Some(&name))?;
 
-    println!("Loaded image {path} as {}x{} in {:?}", buf.width(), buf.height(), start.elapsed());
+    println!("Loaded {:?} image {name} as {}x{} in {:?}", img.color(), img.width(), img.height(), start.elapsed());
 
-    Ok(buf)
+    Ok(img)
 }
 
-pub async fn load_image_from_bytes(field: Field<'_>) -> anyhow::Result<RgbImage> {
+/// Load all the frames of an animated GIF or APNG, other images are loaded as a single frame.
+pub async fn load_animation_from_bytes(field: Field<'_>) -> anyhow::Result<Animation> {
     let start = Instant::now();
 
-    let name = field.file_name().unwrap().to_string();
+    let name = field.file_name().unwrap_or_default().to_string();
     let data = field.bytes().await?;
-    let img = load_from_memory(&data)?;
-    let buf: RgbImage = img.into_rgb8(); // convert to rgb immediately
+    let animation = Animation::decode(&data, Some(&name))?;
+
+    let first = &animation.frames[0].image;
+    println!("Loaded {:?} image {name} as {}x{} with {} frames in {:?}", first.color(), first.width(), first.height(), animation.frames.len(), start.elapsed());
+
+    Ok(animation)
+}
 
-    println!("Loaded image {name} as {}x{} in {:?}", buf.width(), buf.height(), start.elapsed());
+/// Image uploaded in a field of a multipart request.
+pub struct Upload {
+    pub name: String,
+    pub file_name: Option<String>,
+    pub image: DynamicImage,
+}
+
+/// Load the images from all fields of a multipart request, in the order they were sent.
+pub async fn load_images_from_multipart(data: &mut Multipart) -> anyhow::Result<Vec<Upload>> {
+    let mut uploads = Vec::new();
+    while let Some(field) = data.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        let file_name = field.file_name().map(str::to_string);
+        let image = load_image_from_bytes(field).await?;
 
-    Ok(buf)
+        uploads.push(Upload { name, file_name, image });
+    }
+
+    Ok(uploads)
 }
 
-pub fn get_image_as_bytes(data: ImageBuffer<Rgb<u8>, Vec<u8>>) -> anyhow::Result<Vec<u8>> {
-    let mut buffer = BufWriter::new(Cursor::new(Vec::new()));
+/// Take the upload from the field called `name` or, if there is no such field, the first remaining upload.
+pub fn take_upload(uploads: &mut Vec<Upload>, name: &str) -> anyhow::Result<DynamicImage> {
+    let index = uploads.iter().position(|upload| upload.name == name).unwrap_or(0);
+    if index >= uploads.len() {
+        anyhow::bail!("missing image {name}");
+    }
 
-    data.write_to(&mut buffer, ImageFormat::Jpeg)?;
-    let bytes: Vec<u8> = buffer.into_inner()?.into_inner();
+    Ok(uploads.remove(index).image)
+}
 
-    Ok(bytes)
+/// Encode the image as PNG, which keeps alpha and 16-bit depth without any quality loss.
+pub fn get_image_as_bytes(data: impl Into<DynamicImage>) -> anyhow::Result<Vec<u8>> {
+    Conversion::encode(data.into(), &Encoding::of(ImageFormat::Png))
 }
 
-pub fn save_image_buffer(path: &str, buf: &RgbImage) -> anyhow::Result<()> {
+/// Save the image in the format given by the extension of `path`, with the default options of its encoder.
+pub fn save_image_buffer(path: &str, buf: impl Into<DynamicImage>) -> anyhow::Result<()> {
     let start = Instant::now();
-    buf.save_with_format(path, image::ImageFormat::Jpeg)?;
+    let encoding = Encoding::of(ImageFormat::from_path(path)?);
+    std::fs::write(path, Conversion::encode(buf.into(), &encoding)?)?;
 
     println!("Saved to {path} in {:?}", start.elapsed());
     Ok(())
 }
 
-/// Draw bounding boxes with confidence scores on the image.
+// PNG and TIFF have no float samples, those are stored as 16-bit
+fn encodable(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgb32F(_) => DynamicImage::ImageRgb16(img.into_rgb16()),
+        DynamicImage::ImageRgba32F(_) => DynamicImage::ImageRgba16(img.into_rgba16()),
+        img => img,
+    }
+}
+
+/// Draw bounding boxes with confidence scores on the image, see `Annotation::draw` for more options.
 /// https://github.com/sgasse/infercam_onnx/blob/main/infer_server/src/inferer.rs
 pub fn draw_bboxes_on_image(mut frame: RgbImage, bboxes_with_confidences: Vec<([f32; 4], f32)>) -> RgbImage {
-    let (width, height) = (frame.width().to_f32().unwrap(), frame.height().to_f32().unwrap());
-
-    let _color = Rgb::from([0, 255, 0]);
-
-    for (bbox, _confidence) in bboxes_with_confidences.iter() {
-        // Coordinates of top-left and bottom-right points
-        // Coordinate frame basis is on the top left corner
-        let (x_tl, y_tl) = (bbox[0] * width, bbox[1] * height);
-        let (x_br, y_br) = (bbox[2] * width, bbox[3] * height);
-        let rect_width = x_br - x_tl;
-        let rect_height = y_br - y_tl;
-
-        let face_rect = Rect::at(x_tl as i32, y_tl as i32).of_size(rect_width as u32, rect_height as u32);
-
-        frame = draw_hollow_rect(&frame, face_rect, Rgb::from([255, 0, 255]));
-        // frame = draw_text(
-        //     &frame,
-        //     color,
-        //     x_tl as i32,
-        //     y_tl as i32,
-        //     rusttype::Scale { x: 16.0, y: 16.0 },
-        //     &DEJAVU_MONO,
-        //     &format!("{:.2}%", confidence * 100.0),
-        // );
-    }
+    let detections: Vec<Detection> = bboxes_with_confidences.into_iter().map(Detection::from).collect();
+    Annotation::draw(&mut frame, &detections, &AnnotationOptions::default());
 
     frame
 }
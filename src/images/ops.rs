@@ -0,0 +1,1161 @@
+use image::DynamicImage;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use super::color::{Color, ColorSpace, WhiteBalance};
+use super::cropping::{parse_aspect, CropSpec, Cropping, Units};
+use super::distortion::{Axis, Distortion, Effect};
+use super::keying::{with_alpha, KeyOptions, Keying};
+use super::processing::{parse_color, BorderColor, Denoise, Processing, Sides, TrimOptions};
+use super::stylize::{Style, Stylize};
+use crate::dynamic_map;
+
+/// Operation on a single image, which the `OpRegistry` serves over HTTP (`/ops/<name>`), runs in
+/// pipelines and editing sessions, and from the command line, with the parameters declared by `op_params!`.
+pub trait ImageOp: Send + Sync {
+    /// Parameters of the operation, deserialized from JSON (or from text parsed as the kinds of their schema).
+    type Params: DeserializeOwned + OpParams;
+
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /// Routes serving the operation besides `/ops/<name>`, those it had before the registry.
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &Self::Params) -> anyhow::Result<DynamicImage>;
+
+    /// Apply the operation to the frames of an animation, every frame on its own unless the operation
+    /// has to treat them alike.
+    fn apply_frames(&self, frames: Vec<DynamicImage>, params: &Self::Params) -> anyhow::Result<Vec<DynamicImage>> {
+        frames.iter().map(|frame| self.apply(frame, params)).collect()
+    }
+
+    /// What the operation would do to the image as JSON, for the operations which can tell it without
+    /// doing it.
+    fn dry_run(&self, _image: &DynamicImage, _params: &Self::Params) -> anyhow::Result<Value> {
+        anyhow::bail!("{} has no dry run", self.name())
+    }
+
+    /// The image as text, for the operations which draw it with characters.
+    fn text(&self, _image: &DynamicImage, _params: &Self::Params) -> anyhow::Result<String> {
+        anyhow::bail!("{} has no text output", self.name())
+    }
+}
+
+/// Kind of the values of a parameter, the values given as text (query strings, command line arguments)
+/// are parsed as such.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamKind {
+    Integer,
+    Number,
+    Boolean,
+    String,
+}
+
+/// Parameter of an operation as published in its schema.
+#[derive(Debug, Clone, Serialize)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: ParamKind,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+impl Param {
+    // Value of the parameter given as text
+    fn parse(&self, value: &str) -> anyhow::Result<Value> {
+        let invalid = || anyhow::anyhow!("parameter {} is {:?}, not {value}", self.name, self.kind);
+        let value = value.trim();
+
+        Ok(match self.kind {
+            ParamKind::Integer => match value.parse::<u64>() {
+                Ok(unsigned) => unsigned.into(),
+                Err(_) => value.parse::<i64>().map_err(|_| invalid())?.into(),
+            },
+            ParamKind::Number => serde_json::Number::from_f64(value.parse().map_err(|_| invalid())?).ok_or_else(invalid)?.into(),
+            ParamKind::Boolean => value.parse::<bool>().map_err(|_| invalid())?.into(),
+            ParamKind::String => value.into(),
+        })
+    }
+}
+
+/// Parameters of an operation with the schema of their fields, implemented by `op_params!`.
+pub trait OpParams {
+    fn schema() -> Vec<Param>;
+}
+
+/// Kind of the values of a parameter of the type, which is required unless it is an `Option`.
+pub trait ParamType {
+    const KIND: ParamKind;
+    const REQUIRED: bool = true;
+}
+
+macro_rules! param_types {
+    ($($ty:ty => $kind:ident),*) => {
+        $(impl ParamType for $ty {
+            const KIND: ParamKind = ParamKind::$kind;
+        })*
+    };
+}
+
+param_types!(u32 => Integer, u64 => Integer, i32 => Integer, i64 => Integer, f32 => Number, f64 => Number, bool => Boolean, String => String);
+
+impl<T: ParamType> ParamType for Option<T> {
+    const KIND: ParamKind = T::KIND;
+    const REQUIRED: bool = false;
+}
+
+/// Declare the parameters of an operation, a struct deserialized from its JSON parameters whose schema is
+/// derived from the fields: the kinds from their types, optional when they are `Option`s, and the
+/// descriptions from their doc comments.
+macro_rules! op_params {
+    ($(#[$meta:meta])* $vis:vis struct $name:ident { $($(#[doc = $doc:literal])* $field_vis:vis $field:ident: $ty:ty),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Deserialize)]
+        $vis struct $name {
+            $($(#[doc = $doc])* $field_vis $field: $ty,)*
+        }
+
+        impl OpParams for $name {
+            fn schema() -> Vec<Param> {
+                vec![$(Param {
+                    name: stringify!($field),
+                    kind: <$ty as ParamType>::KIND,
+                    required: <$ty as ParamType>::REQUIRED,
+                    description: concat!($($doc),*).trim(),
+                }),*]
+            }
+        }
+    };
+}
+
+/// Name, description and parameters of an operation, as listed by `GET /ops`.
+#[derive(Debug, Serialize)]
+pub struct OpSchema {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub params: Vec<Param>,
+}
+
+/// Step of a pipeline, the name of the operation in `op` next to its parameters, e.g.
+/// `{"op": "denoise", "method": "median", "radius": 2}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PipelineStep {
+    pub op: String,
+    #[serde(flatten)]
+    pub params: Map<String, Value>,
+}
+
+// Operation with the type of its parameters erased, so that all of them fit in the registry
+trait RegisteredOp: Send + Sync {
+    fn schema(&self) -> OpSchema;
+    fn routes(&self) -> &'static [&'static str];
+    fn check(&self, params: &Value) -> anyhow::Result<()>;
+    fn apply_json(&self, image: &DynamicImage, params: &Value) -> anyhow::Result<DynamicImage>;
+    fn apply_frames_json(&self, frames: Vec<DynamicImage>, params: &Value) -> anyhow::Result<Vec<DynamicImage>>;
+    fn dry_run_json(&self, image: &DynamicImage, params: &Value) -> anyhow::Result<Value>;
+    fn text_json(&self, image: &DynamicImage, params: &Value) -> anyhow::Result<String>;
+}
+
+impl<T: ImageOp> RegisteredOp for T {
+    fn schema(&self) -> OpSchema {
+        OpSchema {
+            name: self.name(),
+            description: self.description(),
+            params: T::Params::schema(),
+        }
+    }
+
+    fn routes(&self) -> &'static [&'static str] {
+        self.aliases()
+    }
+
+    fn check(&self, params: &Value) -> anyhow::Result<()> {
+        parameters(self, params)?;
+        Ok(())
+    }
+
+    fn apply_json(&self, image: &DynamicImage, params: &Value) -> anyhow::Result<DynamicImage> {
+        self.apply(image, &parameters(self, params)?)
+    }
+
+    fn apply_frames_json(&self, frames: Vec<DynamicImage>, params: &Value) -> anyhow::Result<Vec<DynamicImage>> {
+        self.apply_frames(frames, &parameters(self, params)?)
+    }
+
+    fn dry_run_json(&self, image: &DynamicImage, params: &Value) -> anyhow::Result<Value> {
+        self.dry_run(image, &parameters(self, params)?)
+    }
+
+    fn text_json(&self, image: &DynamicImage, params: &Value) -> anyhow::Result<String> {
+        self.text(image, &parameters(self, params)?)
+    }
+}
+
+// The parameters of the operation, which may not have any field missing from their schema
+fn parameters<T: ImageOp>(op: &T, params: &Value) -> anyhow::Result<T::Params> {
+    let schema = T::Params::schema();
+    let known = |key: &String| schema.iter().any(|param| param.name == key.as_str());
+    if let Some(unknown) = params.as_object().and_then(|params| params.keys().find(|key| !known(key))) {
+        anyhow::bail!("{} has no parameter {unknown}", op.name());
+    }
+
+    T::Params::deserialize(params).map_err(|err| anyhow::anyhow!("invalid parameters of {}: {err}", op.name()))
+}
+
+/// Operations known by name, see `ImageOp`.
+#[derive(Default)]
+pub struct OpRegistry {
+    ops: BTreeMap<&'static str, Box<dyn RegisteredOp>>,
+}
+
+impl OpRegistry {
+    /// Registry of all the operations of this crate.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::default();
+        registry.register(InvertOp {});
+        registry.register(DenoiseOp {});
+        registry.register(RotateOp {});
+        registry.register(CropOp {});
+        registry.register(TrimOp {});
+        registry.register(DistortOp {});
+        registry.register(ChromaKeyOp {});
+        registry.register(ChannelOp {});
+        registry.register(WhiteBalanceOp {});
+        registry.register(StylizeOp {});
+
+        registry
+    }
+
+    /// Add the operation, replacing any registered under the same name.
+    pub fn register<T: ImageOp + 'static>(&mut self, op: T) -> &mut Self {
+        self.ops.insert(op.name(), Box::new(op));
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.ops.keys().copied()
+    }
+
+    /// Paths of the routes of every operation with its name, `/ops/<name>` and its aliases.
+    pub fn routes(&self) -> Vec<(String, &'static str)> {
+        let mut routes = Vec::new();
+        for (&name, op) in &self.ops {
+            routes.push((format!("/ops/{name}"), name));
+            routes.extend(op.routes().iter().map(|&alias| (alias.to_string(), name)));
+        }
+
+        routes
+    }
+
+    pub fn schemas(&self) -> Vec<OpSchema> {
+        self.ops.values().map(|op| op.schema()).collect()
+    }
+
+    pub fn schema(&self, name: &str) -> anyhow::Result<OpSchema> {
+        Ok(self.op(name)?.schema())
+    }
+
+    /// Parameters of the operation from `name=value` pairs of text, which have to be in its schema.
+    pub fn parse_params<'a>(&self, name: &str, pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> anyhow::Result<Value> {
+        let schema = self.schema(name)?;
+
+        let mut params = Map::new();
+        for (key, value) in pairs {
+            let param = schema.params.iter().find(|param| param.name == key).ok_or_else(|| anyhow::anyhow!("{name} has no parameter {key}"))?;
+            params.insert(key.to_string(), param.parse(value)?);
+        }
+
+        Ok(Value::Object(params))
+    }
+
+    /// Pick a random seed for an operation which takes a `seed` without one in its parameters, once for the
+    /// whole request so that all the frames of an animation get the same one. The seed of the operation is
+    /// returned to report it, with it the output can be reproduced.
+    pub fn seed(&self, name: &str, params: &mut Value) -> anyhow::Result<Option<u64>> {
+        match params.as_object_mut() {
+            Some(params) => self.seed_params(name, params),
+            None => Ok(None),
+        }
+    }
+
+    /// Seeds of the steps of a pipeline as by `seed`, `None` for the steps without one.
+    pub fn seed_pipeline(&self, steps: &mut [PipelineStep]) -> anyhow::Result<Vec<Option<u64>>> {
+        steps.iter_mut().map(|step| self.seed_params(&step.op, &mut step.params)).collect()
+    }
+
+    fn seed_params(&self, name: &str, params: &mut Map<String, Value>) -> anyhow::Result<Option<u64>> {
+        if !self.schema(name)?.params.iter().any(|param| param.name == "seed") {
+            return Ok(None);
+        }
+
+        let seed = params.entry("seed").or_insert_with(|| rand::random::<u64>().into());
+        match seed.as_u64() {
+            Some(seed) => Ok(Some(seed)),
+            None => anyhow::bail!("seed of {name} is {seed}, not an unsigned integer"),
+        }
+    }
+
+    pub fn apply(&self, name: &str, image: &DynamicImage, params: &Value) -> anyhow::Result<DynamicImage> {
+        self.op(name)?.apply_json(image, params)
+    }
+
+    /// Apply the operation to all the frames of an animation, see `ImageOp::apply_frames`.
+    pub fn apply_frames(&self, name: &str, frames: Vec<DynamicImage>, params: &Value) -> anyhow::Result<Vec<DynamicImage>> {
+        self.op(name)?.apply_frames_json(frames, params)
+    }
+
+    pub fn dry_run(&self, name: &str, image: &DynamicImage, params: &Value) -> anyhow::Result<Value> {
+        self.op(name)?.dry_run_json(image, params)
+    }
+
+    /// The image as text, see `ImageOp::text`.
+    pub fn text(&self, name: &str, image: &DynamicImage, params: &Value) -> anyhow::Result<String> {
+        self.op(name)?.text_json(image, params)
+    }
+
+    /// Run the steps one after the other on the frames (of an animation, or the single frame of a still image).
+    /// All of them are checked first, so that a mistake in the last step does not come up only after the
+    /// work of the others.
+    pub fn run_pipeline(&self, frames: Vec<DynamicImage>, steps: &[PipelineStep]) -> anyhow::Result<Vec<DynamicImage>> {
+        let steps: Vec<(&str, Value)> = steps.iter().map(|step| (step.op.as_str(), Value::Object(step.params.clone()))).collect();
+        for (i, (name, params)) in steps.iter().enumerate() {
+            self.op(name)
+                .and_then(|op| op.check(params))
+                .map_err(|err| anyhow::anyhow!("step {} of the pipeline: {err}", i + 1))?;
+        }
+
+        let mut frames = frames;
+        for (name, params) in &steps {
+            frames = self.apply_frames(name, frames, params)?;
+        }
+
+        Ok(frames)
+    }
+
+    fn op(&self, name: &str) -> anyhow::Result<&dyn RegisteredOp> {
+        self.ops.get(name).map(Box::as_ref).ok_or_else(|| anyhow::anyhow!("unknown operation {name}"))
+    }
+}
+
+op_params! {
+    /// Parameters of the operations without any.
+    pub struct NoParams {}
+}
+
+/// Negative of the image, see `Processing::negative_basic`.
+pub struct InvertOp {}
+
+impl ImageOp for InvertOp {
+    type Params = NoParams;
+
+    fn name(&self) -> &'static str {
+        "invert"
+    }
+
+    fn description(&self) -> &'static str {
+        "Negative of the image, alpha is kept"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["/invert"]
+    }
+
+    fn apply(&self, image: &DynamicImage, _: &NoParams) -> anyhow::Result<DynamicImage> {
+        let mut inverted = image.clone();
+        dynamic_map!(mut inverted, buf => Processing::negative_basic(buf));
+        Ok(inverted)
+    }
+}
+
+op_params! {
+    /// Parameters of `denoise`. Only the parameters of the chosen `method` (`median` by default) are used,
+    /// the missing ones take the defaults below.
+    pub struct DenoiseParams {
+        /// `median` (default), `bilateral` or `nlm` (non-local means)
+        method: Option<String>,
+        /// median: 1, bilateral: 3
+        radius: Option<u32>,
+        /// bilateral: 25, on the 0 - 255 scale
+        sigma_color: Option<f32>,
+        /// bilateral: 2.0
+        sigma_space: Option<f32>,
+        /// nlm: 10, on the 0 - 255 scale, raise it for noisier images
+        strength: Option<f32>,
+        /// nlm: 1
+        patch_radius: Option<u32>,
+        /// nlm: 5
+        search_radius: Option<u32>,
+    }
+}
+
+impl DenoiseParams {
+    // Windows grow with the square of the radius, larger ones would tie up the processing threads
+    const MAX_RADIUS: u32 = 15;
+
+    pub fn filter(&self) -> anyhow::Result<Denoise> {
+        let filter = match self.method.as_deref().unwrap_or("median") {
+            "median" => Denoise::Median { radius: self.radius.unwrap_or(1) },
+            "bilateral" => Denoise::Bilateral {
+                radius: self.radius.unwrap_or(3),
+                sigma_color: self.sigma_color.unwrap_or(25.0),
+                sigma_space: self.sigma_space.unwrap_or(2.0),
+            },
+            "nlm" | "non-local-means" => Denoise::NonLocalMeans {
+                strength: self.strength.unwrap_or(10.0),
+                patch_radius: self.patch_radius.unwrap_or(1),
+                search_radius: self.search_radius.unwrap_or(5),
+            },
+            other => anyhow::bail!("unknown denoise method {other}"),
+        };
+
+        let largest = [self.radius, self.patch_radius, self.search_radius].into_iter().flatten().max().unwrap_or(0);
+        if largest > Self::MAX_RADIUS {
+            anyhow::bail!("radius {largest} is over the limit of {}", Self::MAX_RADIUS);
+        }
+
+        Ok(filter)
+    }
+}
+
+/// Noise reduction, see `Processing::denoise`.
+pub struct DenoiseOp {}
+
+impl ImageOp for DenoiseOp {
+    type Params = DenoiseParams;
+
+    fn name(&self) -> &'static str {
+        "denoise"
+    }
+
+    fn description(&self) -> &'static str {
+        "Noise reduction by a median, bilateral or non-local means filter"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["/denoise"]
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &DenoiseParams) -> anyhow::Result<DynamicImage> {
+        let filter = params.filter()?;
+        Ok(dynamic_map!(image, buf => Processing::denoise(buf, &filter)))
+    }
+}
+
+op_params! {
+    /// Parameters of `rotate`.
+    pub struct RotateParams {
+        /// degrees
+        angle: f32,
+    }
+}
+
+/// Rotation around the center, see `Processing::rotate`.
+pub struct RotateOp {}
+
+impl ImageOp for RotateOp {
+    type Params = RotateParams;
+
+    fn name(&self) -> &'static str {
+        "rotate"
+    }
+
+    fn description(&self) -> &'static str {
+        "Rotation around the center, the uncovered corners left black or transparent"
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &RotateParams) -> anyhow::Result<DynamicImage> {
+        Ok(dynamic_map!(image, buf => Processing::rotate(buf, params.angle)))
+    }
+}
+
+op_params! {
+    /// Parameters of `crop`, in the `units` (pixels by default, normalized for `bbox`). Only the parameters of
+    /// the chosen `mode` (`rect` by default) are used.
+    pub struct CropParams {
+        /// `rect` (default), `center`, `aspect` or `bbox`
+        mode: Option<String>,
+        /// rect: left edge, 0 by default
+        x: Option<f32>,
+        /// rect: top edge, 0 by default
+        y: Option<f32>,
+        /// rect and center: width
+        w: Option<f32>,
+        /// rect and center: height
+        h: Option<f32>,
+        /// `px` (default but for bbox), `percent` or `normalized` (0.0 - 1.0, default for bbox)
+        units: Option<String>,
+        /// aspect: width / height as `16:9` or `1.78`
+        aspect: Option<String>,
+        /// bbox: corners `x0,y0,x1,y1`, as the bounding boxes of `/detect-bbox`
+        bbox: Option<String>,
+        /// bbox: added around the box on every side, 0 by default
+        padding: Option<f32>,
+    }
+}
+
+impl CropParams {
+    pub fn spec(&self) -> anyhow::Result<CropSpec> {
+        let units = |default| self.units.as_deref().map(str::parse::<Units>).transpose().map(|units| units.unwrap_or(default));
+        let size = || match (self.w, self.h) {
+            (Some(width), Some(height)) => Ok((width, height)),
+            _ => Err(anyhow::anyhow!("the size of the crop is needed in `w` and `h`")),
+        };
+
+        let spec = match self.mode.as_deref().unwrap_or("rect") {
+            "rect" => {
+                let (width, height) = size()?;
+                CropSpec::Rect {
+                    x: self.x.unwrap_or(0.0),
+                    y: self.y.unwrap_or(0.0),
+                    width,
+                    height,
+                    units: units(Units::Pixels)?,
+                }
+            }
+            "center" => {
+                let (width, height) = size()?;
+                CropSpec::Center {
+                    width,
+                    height,
+                    units: units(Units::Pixels)?,
+                }
+            }
+            "aspect" => CropSpec::Aspect {
+                aspect: parse_aspect(self.aspect.as_deref().ok_or_else(|| anyhow::anyhow!("the aspect ratio is needed in `aspect`"))?)?,
+            },
+            "bbox" => {
+                let bbox = self.bbox.as_deref().ok_or_else(|| anyhow::anyhow!("the box is needed in `bbox` as x0,y0,x1,y1"))?;
+                let corners: Vec<f32> = bbox.split(',').map(|c| c.trim().parse::<f32>()).collect::<Result<_, _>>()?;
+                let corners: [f32; 4] = corners.try_into().map_err(|_| anyhow::anyhow!("box {bbox} is not x0,y0,x1,y1"))?;
+                CropSpec::Bbox {
+                    corners,
+                    padding: self.padding.unwrap_or(0.0),
+                    units: units(Units::Normalized)?,
+                }
+            }
+            other => anyhow::bail!("unknown crop mode {other}"),
+        };
+
+        Ok(spec)
+    }
+}
+
+/// Rectangle cut out of the image, see `CropSpec`.
+pub struct CropOp {}
+
+impl ImageOp for CropOp {
+    type Params = CropParams;
+
+    fn name(&self) -> &'static str {
+        "crop"
+    }
+
+    fn description(&self) -> &'static str {
+        "Rectangle cut out of the image, given by its corner, around the center, by its aspect ratio or by a padded bounding box"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["/crop"]
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &CropParams) -> anyhow::Result<DynamicImage> {
+        Cropping::crop(image, &params.spec()?)
+    }
+}
+
+op_params! {
+    /// Parameters of `trim`, every one of them optional, see `TrimOptions` for the defaults.
+    pub struct TrimParams {
+        /// `black` (default), `white`, `auto`, `transparent`, `#rrggbb` or `r,g,b`
+        color: Option<String>,
+        /// largest difference of any channel (0 - 255) from the border color still trimmed, 40 by default
+        tolerance: Option<f32>,
+        /// comma separated list of `top`, `left`, `bottom`, `right`, or `all`
+        sides: Option<String>,
+        /// pixels of the border kept around the content
+        padding: Option<u32>,
+    }
+}
+
+impl TrimParams {
+    pub fn options(&self) -> anyhow::Result<TrimOptions> {
+        let defaults = TrimOptions::default();
+
+        Ok(TrimOptions {
+            color: self.color.as_deref().map(str::parse::<BorderColor>).transpose()?.unwrap_or(defaults.color),
+            tolerance: self.tolerance.unwrap_or(defaults.tolerance),
+            sides: self.sides.as_deref().map(str::parse::<Sides>).transpose()?.unwrap_or(defaults.sides),
+            padding: self.padding.unwrap_or(defaults.padding),
+        })
+    }
+}
+
+/// Removal of the uniform borders, see `Processing::remove_borders`.
+pub struct TrimOp {}
+
+impl ImageOp for TrimOp {
+    type Params = TrimParams;
+
+    fn name(&self) -> &'static str {
+        "trim"
+    }
+
+    fn description(&self) -> &'static str {
+        "Removal of the borders of a uniform color"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["/trim"]
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &TrimParams) -> anyhow::Result<DynamicImage> {
+        let options = params.options()?;
+        dynamic_map!(ref image, buf => Processing::remove_borders(buf, &options).map(DynamicImage::from))
+    }
+
+    // The content is found on the first frame, so that all the frames are trimmed alike
+    fn apply_frames(&self, frames: Vec<DynamicImage>, params: &TrimParams) -> anyhow::Result<Vec<DynamicImage>> {
+        let Some(first) = frames.first() else {
+            return Ok(frames);
+        };
+
+        let options = params.options()?;
+        let rect = dynamic_map!(ref first, buf => Processing::find_content(buf, &options))?;
+        frames
+            .iter()
+            .map(|frame| dynamic_map!(ref frame, buf => Processing::crop_image(buf, rect.x, rect.y, rect.width, rect.height).map(DynamicImage::from)))
+            .collect()
+    }
+
+    /// The content rectangle, which would be kept.
+    fn dry_run(&self, image: &DynamicImage, params: &TrimParams) -> anyhow::Result<Value> {
+        let options = params.options()?;
+        let rect = dynamic_map!(ref image, buf => Processing::find_content(buf, &options))?;
+        Ok(serde_json::to_value(rect)?)
+    }
+}
+
+op_params! {
+    /// Parameters of `distort`. Only the parameters of the chosen `effect` (`wobble` by default) are used,
+    /// the missing ones take the defaults below.
+    pub struct DistortParams {
+        /// `wobble` (default), `swirl`, `ripple`, `barrel`, `pincushion`, `fisheye`, `pixelate` or `glitch`
+        effect: Option<String>,
+        /// wobble: 100, ripple: 10
+        amplitude: Option<f32>,
+        /// `horizontal` (default), `vertical` or `both`, wobble and ripple
+        axis: Option<String>,
+        /// swirl: 180 (degrees), barrel and pincushion: 0.3, fisheye: 0.5
+        strength: Option<f32>,
+        /// swirl: 1.0
+        radius: Option<f32>,
+        /// ripple: 40
+        wavelength: Option<f32>,
+        /// pixelate: 16
+        block_size: Option<u32>,
+        /// glitch: 8
+        shift: Option<u32>,
+        /// glitch: 12
+        slices: Option<u32>,
+        /// seed of the random effects, picked at random for every request and reported when missing
+        pub seed: Option<u64>,
+    }
+}
+
+impl DistortParams {
+    // The glitch bands are drawn up front, and lengths beyond any image only take time
+    const MAX_SLICES: u32 = 1000;
+    const MAX_LENGTH: f32 = 10_000.0;
+
+    pub fn effect(&self) -> anyhow::Result<Effect> {
+        let axis = self.axis.as_deref().map(str::parse::<Axis>).transpose()?.unwrap_or(Axis::Horizontal);
+
+        let effect = match self.effect.as_deref().unwrap_or("wobble") {
+            "wobble" => Effect::Wobble {
+                amplitude: self.amplitude.unwrap_or(100.0) as u32,
+                axis,
+            },
+            "swirl" => Effect::Swirl {
+                strength: self.strength.unwrap_or(180.0),
+                radius: self.radius.unwrap_or(1.0),
+            },
+            "ripple" => Effect::Ripple {
+                amplitude: self.amplitude.unwrap_or(10.0),
+                wavelength: self.wavelength.unwrap_or(40.0),
+                axis,
+            },
+            "barrel" => Effect::Barrel {
+                strength: self.strength.unwrap_or(0.3),
+            },
+            "pincushion" => Effect::Pincushion {
+                strength: self.strength.unwrap_or(0.3),
+            },
+            "fisheye" => Effect::Fisheye {
+                strength: self.strength.unwrap_or(0.5),
+            },
+            "pixelate" => Effect::Pixelate {
+                block_size: self.block_size.unwrap_or(16),
+            },
+            "glitch" => Effect::Glitch {
+                shift: self.shift.unwrap_or(8),
+                slices: self.slices.unwrap_or(12),
+            },
+            other => anyhow::bail!("unknown effect {other}"),
+        };
+
+        if let Some(slices) = self.slices.filter(|&slices| slices > Self::MAX_SLICES) {
+            anyhow::bail!("slices {slices} is over the limit of {}", Self::MAX_SLICES);
+        }
+        let lengths = [self.block_size, self.shift].into_iter().flatten().map(|length| length as f32);
+        let longest = lengths.chain(self.amplitude.map(f32::abs)).fold(0.0, f32::max);
+        if longest > Self::MAX_LENGTH {
+            anyhow::bail!("length {longest} is over the limit of {} pixels", Self::MAX_LENGTH);
+        }
+
+        Ok(effect)
+    }
+}
+
+/// Distortion effects, see `Distortion::apply`.
+pub struct DistortOp {}
+
+impl ImageOp for DistortOp {
+    type Params = DistortParams;
+
+    fn name(&self) -> &'static str {
+        "distort"
+    }
+
+    fn description(&self) -> &'static str {
+        "Distortion effects, from lens distortions to glitches"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["/distort"]
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &DistortParams) -> anyhow::Result<DynamicImage> {
+        let effect = params.effect()?;
+        // The registry picks the seed of the requests without one, see `OpRegistry::seed`
+        Ok(dynamic_map!(image, buf => Distortion::apply(buf, &effect, params.seed.unwrap_or_default())))
+    }
+}
+
+op_params! {
+    /// Parameters of `chromakey`, see `KeyOptions` for the defaults.
+    pub struct ChromaKeyParams {
+        /// key color, `auto` (from the corners) or any color as for `/caption`, green by default
+        key: Option<String>,
+        /// chroma distance to the key fully transparent, 40 by default
+        tolerance: Option<f32>,
+        /// width of the ramp to opaque beyond the tolerance, 30 by default
+        softness: Option<f32>,
+        /// 0.0 - 1.0, strength of the spill suppression, 0.5 by default
+        spill: Option<f32>,
+    }
+}
+
+impl ChromaKeyParams {
+    pub fn options(&self) -> anyhow::Result<KeyOptions> {
+        let defaults = KeyOptions::default();
+
+        let color = match self.key.as_deref().map(str::trim) {
+            Some("auto") => None,
+            Some(key) => {
+                let [r, g, b, _] = parse_color(key)?;
+                Some([r, g, b])
+            }
+            None => defaults.color,
+        };
+
+        Ok(KeyOptions {
+            color,
+            tolerance: self.tolerance.unwrap_or(defaults.tolerance),
+            softness: self.softness.unwrap_or(defaults.softness),
+            spill: self.spill.unwrap_or(defaults.spill),
+        })
+    }
+}
+
+/// Color keyed out into the alpha, see `Keying::apply`.
+pub struct ChromaKeyOp {}
+
+impl ImageOp for ChromaKeyOp {
+    type Params = ChromaKeyParams;
+
+    fn name(&self) -> &'static str {
+        "chromakey"
+    }
+
+    fn description(&self) -> &'static str {
+        "Color keyed out into the alpha, with the spill of the key removed"
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &ChromaKeyParams) -> anyhow::Result<DynamicImage> {
+        let options = params.options()?;
+        let mut keyed = with_alpha(image.clone());
+        dynamic_map!(mut keyed, buf => Keying::apply(buf, &options));
+        Ok(keyed)
+    }
+}
+
+op_params! {
+    /// Parameters of `channel`.
+    pub struct ChannelParams {
+        /// `srgb` (default), `linear`, `hsv`, `hsl`, `lab`, `ycbcr` or `gray`
+        space: Option<String>,
+        /// `r`, `g`, `b` (srgb, linear), `h`, `s`, `v` (hsv), `h`, `s`, `l` (hsl), `l`, `a`, `b` (lab),
+        /// `y`, `cb`, `cr` (ycbcr) or `y` (gray)
+        channel: String,
+    }
+}
+
+/// One channel of the image in a color space as a grayscale image, see `Color::channel`.
+pub struct ChannelOp {}
+
+impl ImageOp for ChannelOp {
+    type Params = ChannelParams;
+
+    fn name(&self) -> &'static str {
+        "channel"
+    }
+
+    fn description(&self) -> &'static str {
+        "One channel of the image in a color space as a grayscale image, e.g. the L channel of Lab"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["/channel"]
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &ChannelParams) -> anyhow::Result<DynamicImage> {
+        let space = params.space.as_deref().map(str::parse::<ColorSpace>).transpose()?.unwrap_or(ColorSpace::Srgb);
+        let channel = space.channel(&params.channel)?;
+
+        // 16-bit and float images keep their precision in 16-bit
+        let deep = image.color().bytes_per_pixel() > image.color().channel_count();
+        Ok(dynamic_map!(ref image, buf => match deep {
+            true => DynamicImage::ImageLuma16(Color::channel(buf, space, channel)),
+            false => DynamicImage::ImageLuma8(Color::channel(buf, space, channel)),
+        }))
+    }
+}
+
+op_params! {
+    /// Parameters of `whitebalance`.
+    pub struct WhiteBalanceParams {
+        /// `grayworld` (default, the average is gray) or `whitepatch` (the brightest colors are white)
+        method: Option<String>,
+        /// whitepatch: percentile of every channel taken as white, 99 by default
+        percentile: Option<f32>,
+        /// 0.0 - 1.0, how much of the correction is applied, 1.0 by default
+        strength: Option<f32>,
+    }
+}
+
+impl WhiteBalanceParams {
+    pub fn method(&self) -> anyhow::Result<WhiteBalance> {
+        match self.method.as_deref().unwrap_or("grayworld") {
+            "grayworld" => Ok(WhiteBalance::GrayWorld),
+            "whitepatch" => Ok(WhiteBalance::WhitePatch {
+                percentile: self.percentile.unwrap_or(99.0),
+            }),
+            other => anyhow::bail!("unknown white balance {other}"),
+        }
+    }
+}
+
+/// Automatic white balance, see `Color::white_balance`.
+pub struct WhiteBalanceOp {}
+
+impl ImageOp for WhiteBalanceOp {
+    type Params = WhiteBalanceParams;
+
+    fn name(&self) -> &'static str {
+        "whitebalance"
+    }
+
+    fn description(&self) -> &'static str {
+        "Automatic white balance, removing the color cast of the light"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["/whitebalance"]
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &WhiteBalanceParams) -> anyhow::Result<DynamicImage> {
+        let method = params.method()?;
+        let mut balanced = image.clone();
+        dynamic_map!(mut balanced, buf => Color::white_balance(buf, method, params.strength.unwrap_or(1.0)));
+        Ok(balanced)
+    }
+}
+
+op_params! {
+    /// Parameters of `stylize`. Only the parameters of the chosen `filter` (`oil` by default) are used, the
+    /// missing ones take the defaults below.
+    pub struct StylizeParams {
+        /// `oil` (default), `sketch`, `cartoon`, `posterize`, `sepia`, `duotone`, `vignette`, `grain`, `halftone` or `ascii`
+        filter: Option<String>,
+        /// oil: 4 (pixels), vignette: 0.5 (of half of the diagonal)
+        radius: Option<f32>,
+        /// sketch: 8, width of the strokes
+        sigma: Option<f32>,
+        /// values of every channel, cartoon: 6, posterize: 4
+        levels: Option<u32>,
+        /// cartoon: 100, gradient of the edges drawn in black
+        edges: Option<f32>,
+        /// 0.0 - 1.0, sepia: 1.0, vignette: 0.6
+        strength: Option<f32>,
+        /// duotone: color of the shadows, any color as for `/caption`, `#1b1f5c`
+        shadows: Option<String>,
+        /// duotone: color of the highlights, `#ffd27f`
+        highlights: Option<String>,
+        /// grain: 0.08, deviation of the brightness
+        amount: Option<f32>,
+        /// halftone: 8, ascii: 8, size of the dots and characters
+        cell: Option<u32>,
+        /// halftone: 45 (degrees), angle of the grid
+        angle: Option<f32>,
+        /// ascii: false, characters in the colors of the image
+        color: Option<bool>,
+        /// seed of the grain, picked at random for every request and reported when missing
+        pub seed: Option<u64>,
+    }
+}
+
+impl StylizeParams {
+    pub fn style(&self) -> anyhow::Result<Style> {
+        let levels = |default| match self.levels.unwrap_or(default) {
+            levels if levels < 2 => Err(anyhow::anyhow!("at least 2 levels are needed")),
+            levels => Ok(levels),
+        };
+        let cell = match self.cell.unwrap_or(8) {
+            cell if cell < 2 => anyhow::bail!("cells are at least 2 pixels"),
+            cell => cell,
+        };
+        let rgb = |color: &Option<String>, default| -> anyhow::Result<[u8; 3]> {
+            let [r, g, b, _] = parse_color(color.as_deref().unwrap_or(default))?;
+            Ok([r, g, b])
+        };
+
+        let style = match self.filter.as_deref().unwrap_or("oil") {
+            "oil" => Style::Oil {
+                radius: self.radius.unwrap_or(4.0).max(1.0) as u32,
+            },
+            "sketch" => match self.sigma.unwrap_or(8.0) {
+                sigma if sigma > 0.0 => Style::Sketch { sigma },
+                _ => anyhow::bail!("sigma has to be positive"),
+            },
+            "cartoon" => Style::Cartoon {
+                levels: levels(6)?,
+                edges: self.edges.unwrap_or(100.0),
+            },
+            "posterize" => Style::Posterize { levels: levels(4)? },
+            "sepia" => Style::Sepia {
+                strength: self.strength.unwrap_or(1.0),
+            },
+            "duotone" => Style::Duotone {
+                shadows: rgb(&self.shadows, "#1b1f5c")?,
+                highlights: rgb(&self.highlights, "#ffd27f")?,
+            },
+            "vignette" => Style::Vignette {
+                strength: self.strength.unwrap_or(0.6),
+                radius: self.radius.unwrap_or(0.5),
+            },
+            "grain" => Style::Grain {
+                amount: self.amount.unwrap_or(0.08),
+            },
+            "halftone" => Style::Halftone {
+                cell,
+                angle: self.angle.unwrap_or(45.0),
+            },
+            "ascii" => Style::Ascii {
+                cell,
+                color: self.color.unwrap_or(false),
+            },
+            other => anyhow::bail!("unknown filter {other}"),
+        };
+
+        Ok(style)
+    }
+}
+
+/// Artistic filters, see `Stylize::apply`.
+pub struct StylizeOp {}
+
+impl ImageOp for StylizeOp {
+    type Params = StylizeParams;
+
+    fn name(&self) -> &'static str {
+        "stylize"
+    }
+
+    fn description(&self) -> &'static str {
+        "Artistic filters, from oil painting and pencil sketch to halftone and ASCII art"
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        &["/stylize"]
+    }
+
+    fn apply(&self, image: &DynamicImage, params: &StylizeParams) -> anyhow::Result<DynamicImage> {
+        let style = params.style()?;
+        // The registry picks the seed of the requests without one, see `OpRegistry::seed`
+        Ok(dynamic_map!(image, buf => Stylize::apply(buf, &style, params.seed.unwrap_or_default())))
+    }
+
+    /// The characters of the `ascii` filter, one line for every row of cells.
+    fn text(&self, image: &DynamicImage, params: &StylizeParams) -> anyhow::Result<String> {
+        let Style::Ascii { cell, color } = params.style()? else {
+            anyhow::bail!("only the ascii filter of stylize has a text output");
+        };
+        Ok(dynamic_map!(ref image, buf => Stylize::ascii_text(buf, cell, color)).join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, RgbImage};
+    use serde_json::json;
+
+    use super::*;
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(6, 4, |x, y| Rgb([(x * 40) as u8, (y * 60) as u8, 0])))
+    }
+
+    #[test]
+    fn schema_is_derived_from_the_fields() {
+        let registry = OpRegistry::with_builtins();
+        let schema = registry.schema("channel").unwrap();
+        let params: Vec<(&str, ParamKind, bool)> = schema.params.iter().map(|param| (param.name, param.kind, param.required)).collect();
+
+        assert_eq!(params, vec![("space", ParamKind::String, false), ("channel", ParamKind::String, true)]);
+        assert!(schema.params[1].description.starts_with("`r`, `g`, `b` (srgb, linear)"));
+        assert!(schema.params[1].description.ends_with("`y`, `cb`, `cr` (ycbcr) or `y` (gray)"));
+        let schemas = registry.schemas();
+        assert!(schemas.iter().flat_map(|schema| &schema.params).all(|param| !param.description.is_empty()));
+    }
+
+    #[test]
+    fn text_is_parsed_as_the_kinds_of_the_schema() {
+        let registry = OpRegistry::with_builtins();
+        let pairs = [("method", "bilateral"), ("radius", "2"), ("sigma_color", "12.5")];
+        let params = registry.parse_params("denoise", pairs).unwrap();
+
+        assert_eq!(params, json!({"method": "bilateral", "radius": 2, "sigma_color": 12.5}));
+        assert!(registry.parse_params("denoise", [("radius", "two")]).is_err());
+        assert!(registry.parse_params("denoise", [("radious", "2")]).is_err());
+    }
+
+    #[test]
+    fn unknown_and_missing_parameters_are_rejected() {
+        let registry = OpRegistry::with_builtins();
+
+        assert!(registry.apply("rotate", &image(), &json!({})).is_err());
+        assert!(registry.apply("rotate", &image(), &json!({"angle": 90, "angel": 90})).is_err());
+        assert!(registry.apply("invert", &image(), &json!({"strength": 1})).is_err());
+        assert!(registry.apply("rotate", &image(), &json!({"angle": 90})).is_ok());
+    }
+
+    #[test]
+    fn seed_is_picked_once_and_reported() {
+        let registry = OpRegistry::with_builtins();
+        let mut params = json!({"effect": "glitch"});
+        let seed = registry.seed("distort", &mut params).unwrap().unwrap();
+
+        assert_eq!(params["seed"], json!(seed));
+        assert_eq!(registry.seed("distort", &mut params).unwrap(), Some(seed));
+        let glitched = registry.apply("distort", &image(), &params).unwrap();
+        assert_eq!(registry.apply("distort", &image(), &params).unwrap(), glitched);
+
+        let mut params = json!({});
+        assert_eq!(registry.seed("invert", &mut params).unwrap(), None);
+        assert_eq!(params, json!({}));
+        assert!(registry.seed("distort", &mut json!({"seed": "abc"})).is_err());
+    }
+
+    #[test]
+    fn distortions_beyond_the_limits_are_rejected() {
+        let registry = OpRegistry::with_builtins();
+        let distort = |params| registry.apply("distort", &image(), &params);
+
+        assert!(distort(json!({"effect": "glitch", "slices": 4_000_000_000u32, "seed": 1})).is_err());
+        assert!(distort(json!({"effect": "glitch", "shift": 100_000, "seed": 1})).is_err());
+        assert!(distort(json!({"effect": "pixelate", "block_size": 100_000})).is_err());
+        assert!(distort(json!({"effect": "ripple", "amplitude": -1e9})).is_err());
+        assert!(distort(json!({"effect": "glitch", "slices": 1000, "shift": 10_000, "seed": 1})).is_ok());
+    }
+
+    #[test]
+    fn pipeline_steps_are_seeded_alike() {
+        let registry = OpRegistry::with_builtins();
+        let mut steps: Vec<PipelineStep> = serde_json::from_value(json!([{"op": "invert"}, {"op": "stylize", "filter": "grain", "seed": 7}])).unwrap();
+
+        assert_eq!(registry.seed_pipeline(&mut steps).unwrap(), vec![None, Some(7)]);
+    }
+
+    #[test]
+    fn operations_are_served_under_their_aliases() {
+        let routes = OpRegistry::with_builtins().routes();
+
+        assert!(routes.contains(&("/ops/trim".to_string(), "trim")));
+        assert!(routes.contains(&("/trim".to_string(), "trim")));
+        assert!(routes.contains(&("/stylize".to_string(), "stylize")));
+        assert!(!routes.iter().any(|(path, _)| path == "/rotate"));
+    }
+
+    #[test]
+    fn frames_are_trimmed_alike() {
+        let registry = OpRegistry::with_builtins();
+        let framed = |border| {
+            DynamicImage::ImageRgb8(RgbImage::from_fn(10, 10, |x, y| match x < border || y < border {
+                true => Rgb([0, 0, 0]),
+                false => Rgb([255, 255, 255]),
+            }))
+        };
+        let params = json!({"sides": "top,left"});
+
+        let frames = registry.apply_frames("trim", vec![framed(2), framed(4)], &params).unwrap();
+        assert!(frames.iter().all(|frame| frame.width() == 8 && frame.height() == 8));
+        let rect = registry.dry_run("trim", &framed(4), &params).unwrap();
+        assert_eq!(rect, json!({"x": 4, "y": 4, "width": 6, "height": 6}));
+        assert!(registry.dry_run("invert", &framed(4), &json!({})).is_err());
+    }
+
+    #[test]
+    fn ascii_art_is_given_as_text() {
+        let registry = OpRegistry::with_builtins();
+        let half = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, _| match x < 4 {
+            true => Rgb([0, 0, 0]),
+            false => Rgb([255, 255, 255]),
+        }));
+
+        let text = registry.text("stylize", &half, &json!({"filter": "ascii", "cell": 2})).unwrap();
+        assert_eq!(text, "@@  \n@@  ");
+        let text = registry.text("stylize", &half, &json!({"filter": "ascii", "cell": 2, "color": true})).unwrap();
+        assert_eq!(text, "  @@\n  @@");
+        assert!(registry.text("stylize", &half, &json!({"filter": "oil"})).is_err());
+        assert!(registry.text("invert", &half, &json!({})).is_err());
+    }
+
+    #[test]
+    fn pipeline_is_checked_before_it_runs() {
+        let registry = OpRegistry::with_builtins();
+        let steps: Vec<PipelineStep> = serde_json::from_value(json!([{"op": "invert"}, {"op": "crop", "w": 2, "h": 2, "z": 1}])).unwrap();
+        let err = registry.run_pipeline(vec![image()], &steps).unwrap_err();
+        assert!(err.to_string().starts_with("step 2 of the pipeline"), "{err}");
+
+        let steps: Vec<PipelineStep> = serde_json::from_value(json!([{"op": "invert"}, {"op": "crop", "x": 1, "w": 2, "h": 3}])).unwrap();
+        let result = registry.run_pipeline(vec![image()], &steps).unwrap();
+        assert_eq!((result[0].width(), result[0].height()), (2, 3));
+        assert_eq!(result[0].to_rgb8().get_pixel(0, 0), &Rgb([215, 255, 255]));
+    }
+}
@@ -0,0 +1,307 @@
+use image::{Pixel, Rgb, RgbImage};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::str::FromStr;
+
+use super::color::{lab_to_rgb, rgb_to_lab};
+use super::processing::{rgba_of, ContentRect, Image};
+
+/// Clustering the colors are reduced with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    KMeans,
+    MedianCut,
+}
+
+impl FromStr for Method {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('_', "-").as_str() {
+            "kmeans" | "k-means" => Ok(Method::KMeans),
+            "median-cut" => Ok(Method::MedianCut),
+            other => anyhow::bail!("unknown palette method {other}"),
+        }
+    }
+}
+
+/// Options of `Palette::extract`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaletteOptions {
+    /// Number of colors, fewer are returned when the image has fewer distinct colors.
+    pub colors: usize,
+    pub method: Method,
+    /// Largest number of pixels clustered, larger images are sampled evenly.
+    pub samples: usize,
+    /// Seed of the k-means initialization, the same seed gives the same palette.
+    pub seed: u64,
+}
+
+impl Default for PaletteOptions {
+    fn default() -> Self {
+        Self {
+            colors: 5,
+            method: Method::KMeans,
+            samples: 20_000,
+            seed: 0,
+        }
+    }
+}
+
+/// Color of a palette with its share of the pixels.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PaletteColor {
+    pub rgb: [u8; 3],
+    pub hex: String,
+    /// 0.0 - 1.0
+    pub proportion: f32,
+}
+
+impl PaletteColor {
+    fn new(lab: [f32; 3], proportion: f32) -> Self {
+        let rgb = lab_to_rgb(lab);
+        Self {
+            rgb,
+            hex: format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2]),
+            proportion,
+        }
+    }
+}
+
+pub struct Palette {}
+
+impl Palette {
+    /// Dominant colors of the image, or of a region of it, the most common first. Colors are clustered in
+    /// CIELAB, where distances follow the perceived differences, and transparent pixels are skipped.
+    pub fn extract<P>(buf: &Image<P>, region: Option<ContentRect>, options: &PaletteOptions) -> anyhow::Result<Vec<PaletteColor>>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = buf.dimensions();
+        let region = region.unwrap_or(ContentRect { x: 0, y: 0, width, height });
+        if region.width == 0 || region.height == 0 || region.x + region.width > width || region.y + region.height > height {
+            anyhow::bail!("region {region:?} is not within the {width}x{height} image");
+        }
+        if options.colors == 0 {
+            anyhow::bail!("a palette needs at least one color");
+        }
+
+        // Every n-th pixel of the region, so that large images take as long as small ones
+        let count = region.width as usize * region.height as usize;
+        let stride = count.div_ceil(options.samples.max(1));
+        let samples: Vec<[f32; 3]> = (0..count)
+            .step_by(stride)
+            .filter_map(|i| {
+                let (x, y) = (region.x + (i % region.width as usize) as u32, region.y + (i / region.width as usize) as u32);
+                let [r, g, b, a] = rgba_of(buf.get_pixel(x, y));
+                (a >= 0.5).then(|| rgb_to_lab([r, g, b]))
+            })
+            .collect();
+        if samples.is_empty() {
+            anyhow::bail!("the region is completely transparent");
+        }
+
+        let mut clusters = match options.method {
+            Method::KMeans => k_means(&samples, options.colors, options.seed),
+            Method::MedianCut => median_cut(samples, options.colors),
+        };
+        clusters.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        Ok(clusters.into_iter().map(|(lab, proportion)| PaletteColor::new(lab, proportion)).collect())
+    }
+
+    /// Swatch of the palette, a bar with every color as wide as its proportion.
+    pub fn render(palette: &[PaletteColor], width: u32, height: u32) -> RgbImage {
+        let mut swatch = RgbImage::new(width, height);
+        let mut left = 0.0;
+        for color in palette {
+            let right = left + color.proportion * width as f32;
+            for x in left.round() as u32..(right.round() as u32).min(width) {
+                for y in 0..height {
+                    swatch.put_pixel(x, y, Rgb(color.rgb));
+                }
+            }
+            left = right;
+        }
+
+        swatch
+    }
+}
+
+// Colors as `(lab, proportion)`, with the proportions summing to 1
+type Clusters = Vec<([f32; 3], f32)>;
+
+fn distance_squared(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+fn mean(colors: &[[f32; 3]]) -> [f32; 3] {
+    let sum = colors.iter().fold([0.0; 3], |sum, c| [sum[0] + c[0], sum[1] + c[1], sum[2] + c[2]]);
+    sum.map(|s| s / colors.len().max(1) as f32)
+}
+
+// Lloyd's algorithm seeded with k-means++ (https://theory.stanford.edu/~sergei/papers/kMeansPP-soda.pdf)
+fn k_means(samples: &[[f32; 3]], k: usize, seed: u64) -> Clusters {
+    const ITERATIONS: usize = 20;
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+    let mut centers = vec![samples[rng.gen_range(0..samples.len())]];
+    while centers.len() < k {
+        // the next center is picked with a probability growing with its distance to the closest center
+        let distances: Vec<f32> = samples.par_iter().map(|s| centers.iter().map(|c| distance_squared(s, c)).fold(f32::MAX, f32::min)).collect();
+        let total: f32 = distances.iter().sum();
+        if total <= 0.0 {
+            // fewer distinct colors than clusters
+            break;
+        }
+
+        let mut target = rng.gen_range(0.0..total);
+        let next = distances.iter().position(|&d| {
+            target -= d;
+            target < 0.0
+        });
+        centers.push(samples[next.unwrap_or(samples.len() - 1)]);
+    }
+
+    let mut assignments = vec![0usize; samples.len()];
+    for _ in 0..ITERATIONS {
+        let reassigned: Vec<usize> = samples
+            .par_iter()
+            .map(|s| (0..centers.len()).min_by(|&a, &b| distance_squared(s, &centers[a]).total_cmp(&distance_squared(s, &centers[b]))).unwrap_or(0))
+            .collect();
+        let converged = reassigned == assignments;
+        assignments = reassigned;
+
+        for (i, center) in centers.iter_mut().enumerate() {
+            let members: Vec<[f32; 3]> = samples.iter().zip(&assignments).filter(|(_, &a)| a == i).map(|(s, _)| *s).collect();
+            if !members.is_empty() {
+                *center = mean(&members);
+            }
+        }
+        if converged {
+            break;
+        }
+    }
+
+    let mut counts = vec![0usize; centers.len()];
+    for &a in assignments.iter() {
+        counts[a] += 1;
+    }
+
+    centers.into_iter().zip(counts).filter(|(_, n)| *n > 0).map(|(c, n)| (c, n as f32 / samples.len() as f32)).collect()
+}
+
+// Heckbert's median cut, splitting the box with the widest spread at the mean of its widest channel
+fn median_cut(samples: Vec<[f32; 3]>, k: usize) -> Clusters {
+    let total = samples.len() as f32;
+    let spread = |colors: &[[f32; 3]]| -> (usize, f32) {
+        (0..3)
+            .map(|c| {
+                let (min, max) = colors.iter().fold((f32::MAX, f32::MIN), |(min, max), color| (min.min(color[c]), max.max(color[c])));
+                (c, max - min)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((0, 0.0))
+    };
+
+    let mut boxes = vec![samples];
+    while boxes.len() < k {
+        let widest = boxes.iter().enumerate().filter(|(_, b)| b.len() > 1).max_by(|a, b| spread(a.1).1.total_cmp(&spread(b.1).1));
+        let Some((i, _)) = widest else {
+            break;
+        };
+        if spread(&boxes[i]).1 <= 0.0 {
+            break;
+        }
+
+        let mut colors = boxes.swap_remove(i);
+        let (channel, _) = spread(&colors);
+        colors.sort_by(|a, b| a[channel].total_cmp(&b[channel]));
+        // cutting at the mean rather than at the median keeps large areas of one color in one box,
+        // and with a spread there are colors on both sides of it
+        let average = colors.iter().map(|color| color[channel]).sum::<f32>() / colors.len() as f32;
+        let cut = colors.partition_point(|color| color[channel] < average).clamp(1, colors.len() - 1);
+        let upper = colors.split_off(cut);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(|colors| (mean(colors), colors.len() as f32 / total)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    // Stripes of red, blue and green over 60, 30 and 10 percent of the image
+    fn stripes() -> RgbaImage {
+        RgbaImage::from_fn(100, 10, |x, _| match x {
+            0..=59 => Rgba([200, 30, 30, 255]),
+            60..=89 => Rgba([30, 30, 200, 255]),
+            _ => Rgba([30, 200, 30, 255]),
+        })
+    }
+
+    fn summary(palette: &[PaletteColor]) -> Vec<([u8; 3], u32)> {
+        palette.iter().map(|color| (color.rgb, (color.proportion * 100.0).round() as u32)).collect()
+    }
+
+    #[test]
+    fn both_methods_find_the_colors_in_their_proportions() {
+        let expected = vec![([200, 30, 30], 60), ([30, 30, 200], 30), ([30, 200, 30], 10)];
+        for method in [Method::KMeans, Method::MedianCut] {
+            let options = PaletteOptions {
+                colors: 3,
+                method,
+                ..Default::default()
+            };
+            let palette = Palette::extract(&stripes(), None, &options).unwrap();
+            let close = summary(&palette).iter().zip(&expected).all(|((rgb, share), (expected_rgb, expected_share))| {
+                share == expected_share && rgb.iter().zip(expected_rgb).all(|(a, b)| a.abs_diff(*b) <= 2)
+            });
+            assert!(close, "{method:?}: {:?}", summary(&palette));
+            assert_eq!(palette[0].hex, format!("#{:02x}{:02x}{:02x}", palette[0].rgb[0], palette[0].rgb[1], palette[0].rgb[2]));
+        }
+    }
+
+    #[test]
+    fn regions_and_transparent_pixels_are_respected() {
+        let mut image = stripes();
+        for x in 0..30 {
+            for y in 0..10 {
+                image.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+            }
+        }
+        let options = PaletteOptions {
+            colors: 3,
+            ..Default::default()
+        };
+
+        // 30 red, 30 blue and 10 green columns are left
+        let palette = Palette::extract(&image, None, &options).unwrap();
+        assert_eq!(summary(&palette).iter().map(|(_, share)| *share).collect::<Vec<_>>(), vec![43, 43, 14]);
+        let region = ContentRect { x: 60, y: 0, width: 30, height: 10 };
+        let palette = Palette::extract(&image, Some(region), &options).unwrap();
+        assert_eq!(palette.len(), 1);
+
+        let outside = ContentRect { x: 90, y: 0, width: 20, height: 10 };
+        assert!(Palette::extract(&image, Some(outside), &options).is_err());
+        let transparent = ContentRect { x: 0, y: 0, width: 30, height: 10 };
+        assert!(Palette::extract(&image, Some(transparent), &options).is_err());
+    }
+
+    #[test]
+    fn swatch_widths_follow_the_proportions() {
+        let palette = vec![PaletteColor::new(rgb_to_lab([1.0, 0.0, 0.0]), 0.75), PaletteColor::new(rgb_to_lab([0.0, 0.0, 1.0]), 0.25)];
+        let swatch = Palette::render(&palette, 100, 4);
+
+        assert_eq!(swatch.get_pixel(74, 3), &Rgb(palette[0].rgb));
+        assert_eq!(swatch.get_pixel(75, 0), &Rgb(palette[1].rgb));
+        assert_eq!(swatch.get_pixel(99, 0), &Rgb(palette[1].rgb));
+    }
+}
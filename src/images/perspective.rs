@@ -0,0 +1,250 @@
+use image::Pixel;
+
+use super::distortion::Distortion;
+use super::processing::Image;
+
+/// Projective transformation of the plane, a 3x3 matrix in row-major order with the last element 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Homography(pub [f64; 9]);
+
+impl Homography {
+    /// Homography mapping each of the four `from` points onto the respective `to` point, no three
+    /// of the points of either quadrilateral may lie on a line.
+    pub fn from_points(from: [[f32; 2]; 4], to: [[f32; 2]; 4]) -> anyhow::Result<Self> {
+        // Every point pair gives two linear equations in the eight unknown matrix elements:
+        // x' = (h0 x + h1 y + h2) / (h6 x + h7 y + 1) and y' = (h3 x + h4 y + h5) / (h6 x + h7 y + 1)
+        let mut system = [[0.0f64; 9]; 8];
+        for (i, (&[x, y], &[tx, ty])) in from.iter().zip(to.iter()).enumerate() {
+            let (x, y, tx, ty) = (x as f64, y as f64, tx as f64, ty as f64);
+            system[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -x * tx, -y * tx, tx];
+            system[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -x * ty, -y * ty, ty];
+        }
+
+        let h = solve(system).ok_or_else(|| anyhow::anyhow!("degenerate points, three of them lie on a line"))?;
+        Ok(Self([h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.0]))
+    }
+
+    /// The transformation undoing this one.
+    pub fn inverse(&self) -> anyhow::Result<Self> {
+        let [a, b, c, d, e, f, g, h, i] = self.0;
+        let adjugate = [e * i - f * h, c * h - b * i, b * f - c * e, f * g - d * i, a * i - c * g, c * d - a * f, d * h - e * g, b * g - a * h, a * e - b * d];
+        let determinant = a * adjugate[0] + b * adjugate[3] + c * adjugate[6];
+        if determinant.abs() < f64::EPSILON {
+            anyhow::bail!("the homography is not invertible");
+        }
+
+        Ok(Self(adjugate.map(|element| element / determinant)))
+    }
+
+    /// Map a point, `None` for points sent to infinity (on the horizon line).
+    pub fn apply(&self, x: f32, y: f32) -> Option<(f32, f32)> {
+        let [a, b, c, d, e, f, g, h, i] = self.0;
+        let (x, y) = (x as f64, y as f64);
+        let w = g * x + h * y + i;
+        if w.abs() < f64::EPSILON {
+            return None;
+        }
+
+        Some((((a * x + b * y + c) / w) as f32, ((d * x + e * y + f) / w) as f32))
+    }
+}
+
+pub struct Perspective {}
+
+impl Perspective {
+    /// Largest output in pixels, about 400 MB as RGBA.
+    pub const MAX_PIXELS: u64 = 100_000_000;
+
+    /// Map the quadrilateral with the given corners (in any order, pixel coordinates) onto a rectangular image,
+    /// e.g. to straighten a photographed document. The size defaults to the longer of the opposite edges.
+    pub fn correct<P>(buf: &Image<P>, corners: [[f32; 2]; 4], size: Option<(u32, u32)>) -> anyhow::Result<Image<P>>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let corners = order_corners(corners);
+        let (width, height) = size.unwrap_or_else(|| rectified_size(&corners));
+        if width == 0 || height == 0 {
+            anyhow::bail!("the corners enclose no area");
+        }
+        Self::check_size(width, height)?;
+
+        // The output pixels are sampled backwards, from the rectangle to the quadrilateral
+        let homography = Homography::from_points(rectangle(width, height), corners)?;
+        Ok(Self::warp(buf, &homography, width, height))
+    }
+
+    /// Inverse of `correct`, map the whole image onto the quadrilateral with the given corners on a
+    /// `width` x `height` canvas, which is transparent (or black) around the quadrilateral.
+    pub fn project<P>(buf: &Image<P>, corners: [[f32; 2]; 4], width: u32, height: u32) -> anyhow::Result<Image<P>>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        if width == 0 || height == 0 {
+            anyhow::bail!("the canvas has no area");
+        }
+        Self::check_size(width, height)?;
+
+        let corners = order_corners(corners);
+        let homography = Homography::from_points(rectangle(buf.width(), buf.height()), corners)?.inverse()?;
+        Ok(Self::warp(buf, &homography, width, height))
+    }
+
+    /// Resample an image through a homography from the output to the source coordinates, bilinearly.
+    pub fn warp<P>(buf: &Image<P>, homography: &Homography, width: u32, height: u32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        // The points are pixel edges, while the pixels are sampled at their centers
+        Distortion::resample(buf, width, height, |x, y| {
+            homography
+                .apply(x + 0.5, y + 0.5)
+                .map(|(source_x, source_y)| (source_x - 0.5, source_y - 0.5))
+                .unwrap_or((-1.0, -1.0))
+        })
+    }
+
+    // Outputs are allocated up front, so their size is checked before anything else
+    fn check_size(width: u32, height: u32) -> anyhow::Result<()> {
+        if width as u64 * height as u64 > Self::MAX_PIXELS {
+            anyhow::bail!("the output of {width}x{height} would be larger than {} pixels", Self::MAX_PIXELS);
+        }
+        Ok(())
+    }
+}
+
+fn rectangle(width: u32, height: u32) -> [[f32; 2]; 4] {
+    let (width, height) = (width as f32, height as f32);
+    [[0.0, 0.0], [width, 0.0], [width, height], [0.0, height]]
+}
+
+/// Move the corners so their bounding box starts at the origin, with the size of the bounding box.
+pub fn fit_canvas(corners: [[f32; 2]; 4]) -> ([[f32; 2]; 4], u32, u32) {
+    let (min_x, max_x) = corners.iter().fold((f32::MAX, f32::MIN), |(min, max), c| (min.min(c[0]), max.max(c[0])));
+    let (min_y, max_y) = corners.iter().fold((f32::MAX, f32::MIN), |(min, max), c| (min.min(c[1]), max.max(c[1])));
+
+    let shifted = corners.map(|[x, y]| [x - min_x, y - min_y]);
+    (shifted, (max_x - min_x).ceil() as u32, (max_y - min_y).ceil() as u32)
+}
+
+/// Sort the corners clockwise starting from the top left one, the one with the smallest `x + y`.
+pub fn order_corners(mut corners: [[f32; 2]; 4]) -> [[f32; 2]; 4] {
+    let cx = corners.iter().map(|c| c[0]).sum::<f32>() / 4.0;
+    let cy = corners.iter().map(|c| c[1]).sum::<f32>() / 4.0;
+    // y grows downwards, so increasing angles go clockwise on the image
+    corners.sort_by(|a, b| (a[1] - cy).atan2(a[0] - cx).total_cmp(&(b[1] - cy).atan2(b[0] - cx)));
+
+    let top_left = (0..4).min_by(|&a, &b| (corners[a][0] + corners[a][1]).total_cmp(&(corners[b][0] + corners[b][1]))).unwrap_or(0);
+    corners.rotate_left(top_left);
+    corners
+}
+
+// Size of the rectangle the ordered corners are straightened into
+fn rectified_size(corners: &[[f32; 2]; 4]) -> (u32, u32) {
+    let length = |a: [f32; 2], b: [f32; 2]| (a[0] - b[0]).hypot(a[1] - b[1]);
+    let [tl, tr, br, bl] = *corners;
+
+    let width = length(tl, tr).max(length(bl, br)).round() as u32;
+    let height = length(tl, bl).max(length(tr, br)).round() as u32;
+    (width, height)
+}
+
+// Gauss-Jordan elimination with partial pivoting of an augmented 8x9 system
+fn solve(mut system: [[f64; 9]; 8]) -> Option<[f64; 8]> {
+    for column in 0..8 {
+        let pivot = (column..8).max_by(|&a, &b| system[a][column].abs().total_cmp(&system[b][column].abs()))?;
+        if system[pivot][column].abs() < 1e-10 {
+            return None;
+        }
+        system.swap(column, pivot);
+
+        let pivot_row = system[column];
+        for (i, row) in system.iter_mut().enumerate() {
+            if i != column {
+                let factor = row[column] / pivot_row[column];
+                for (value, pivot_value) in row.iter_mut().zip(pivot_row).skip(column) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        }
+    }
+
+    let mut solution = [0.0; 8];
+    for (i, row) in system.iter().enumerate() {
+        solution[i] = row[8] / row[i];
+    }
+
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+
+    use super::*;
+
+    const QUAD: [[f32; 2]; 4] = [[110.0, 205.0], [190.0, 215.0], [170.0, 290.0], [105.0, 270.0]];
+
+    fn assert_close((x, y): (f32, f32), [ex, ey]: [f32; 2]) {
+        assert!((x - ex).abs() < 1e-3 && (y - ey).abs() < 1e-3, "({x}, {y}) is not ({ex}, {ey})");
+    }
+
+    #[test]
+    fn homography_maps_the_points_and_back() {
+        let square = rectangle(100, 100);
+        let homography = Homography::from_points(square, QUAD).unwrap();
+        let inverse = homography.inverse().unwrap();
+
+        for (from, to) in square.iter().zip(QUAD) {
+            assert_close(homography.apply(from[0], from[1]).unwrap(), to);
+            assert_close(inverse.apply(to[0], to[1]).unwrap(), *from);
+        }
+        let (x, y) = homography.apply(37.0, 61.0).unwrap();
+        assert_close(inverse.apply(x, y).unwrap(), [37.0, 61.0]);
+    }
+
+    #[test]
+    fn points_on_a_line_are_degenerate() {
+        assert!(Homography::from_points(rectangle(10, 10), [[0.0, 0.0], [1.0, 1.0], [2.0, 2.0], [3.0, 3.0]]).is_err());
+    }
+
+    #[test]
+    fn corners_are_ordered_clockwise_from_the_top_left() {
+        assert_eq!(order_corners([QUAD[2], QUAD[0], QUAD[3], QUAD[1]]), QUAD);
+    }
+
+    #[test]
+    fn canvas_is_the_bounding_box_at_the_origin() {
+        let (corners, width, height) = fit_canvas(QUAD);
+
+        assert_eq!(corners[0], [5.0, 0.0]);
+        assert_eq!((width, height), (85, 85));
+    }
+
+    #[test]
+    fn projection_undoes_the_correction() {
+        let image = GrayImage::from_fn(40, 40, |x, y| Luma([if (x / 10 + y / 10) % 2 == 0 { 255 } else { 0 }]));
+        let corners = [[0.0, 0.0], [40.0, 0.0], [40.0, 40.0], [0.0, 40.0]];
+
+        let corrected = Perspective::correct(&image, corners, None).unwrap();
+        let (corners, width, height) = fit_canvas(corners);
+        let projected = Perspective::project(&corrected, corners, width, height).unwrap();
+
+        assert_eq!(corrected, image);
+        assert_eq!(projected, image);
+    }
+
+    #[test]
+    fn outputs_beyond_the_pixel_limit_are_rejected() {
+        let image = GrayImage::new(10, 10);
+        let far = [[0.0, 0.0], [1e7, 0.0], [1e7, 1e7], [0.0, 1e7]];
+
+        assert!(Perspective::correct(&image, QUAD, Some((200_000, 200_000))).is_err());
+        assert!(Perspective::correct(&image, far, None).is_err());
+        let (corners, width, height) = fit_canvas(far);
+        assert!(Perspective::project(&image, corners, width, height).is_err());
+        assert_eq!(Perspective::correct(&image, QUAD, Some((1000, 1000))).unwrap().width(), 1000);
+    }
+}
@@ -1,61 +1,76 @@
-use image::{ImageBuffer, Pixel, Rgb, RgbImage};
-use rand::Rng;
-use std::{cmp::min, thread};
+use image::{ImageBuffer, Luma, Pixel, Primitive};
+use num_traits::NumCast;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{cmp::min, str::FromStr};
+
+/// Image buffer of any pixel type, so the operations work for every color type and bit depth of a
+/// `DynamicImage` (grayscale, alpha, 16-bit and float images).
+pub type Image<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
 
 pub struct Processing {}
 
 impl Processing {
-    // Basic negative directly through enumeration
-    pub fn negative_basic(buf: &mut RgbImage) {
-        for (_x, _y, px) in buf.enumerate_pixels_mut() {
-            // pixel.invert();
-            // let slice = &mut px.0; // we can use directly the rgba enum
-            px[0] = 255 - px[0];
-            px[1] = 255 - px[1];
-            px[2] = 255 - px[2];
-            // keep alpha
-        }
-    }
-
-    pub fn wobble(buf: &mut RgbImage) {
-        let diff = 100;
-        let prev = buf.clone();
-        let mut rng = rand::thread_rng();
-        for (x, y, px) in buf.enumerate_pixels_mut() {
-            let dx: i32 = diff - (rng.gen::<u32>() as i32 % (diff * 2));
-            if let Some(target) = prev.get_pixel_checked((x as i32 + dx).try_into().unwrap_or(x), y) {
-                *px = *target;
+    // Basic negative, rows are inverted in parallel on the processing thread pool
+    pub fn negative_basic<P>(buf: &mut Image<P>)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let channels = P::CHANNEL_COUNT as usize;
+        let row_len = buf.width() as usize * channels;
+        buf.par_chunks_mut(row_len).for_each(|row| {
+            for px in row.chunks_exact_mut(channels) {
+                // inverts the color channels and keeps alpha
+                P::from_slice_mut(px).invert();
             }
-        }
+        });
     }
 
     // `crop_image` takes an image and the dimensions of the desired crop and returns a new image that is the cropped portion of the original image
-    // x, y -> coordinates of the upper left edge of desired cropped rectangle. Width/height represent the width/height of this rectangle.
-    pub fn crop_image(img: &RgbImage, x: u32, y: u32, width: u32, height: u32) -> RgbImage {
+    // x, y -> coordinates of the upper left edge of desired cropped rectangle. Width/height represent the width/height of this rectangle,
+    // which is cut short at the right and bottom edges of the image. Origins outside of the image and empty rectangles are errors.
+    pub fn crop_image<P>(img: &Image<P>, x: u32, y: u32, width: u32, height: u32) -> anyhow::Result<Image<P>>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        if x >= img.width() || y >= img.height() {
+            anyhow::bail!("crop origin {x},{y} is outside of the {}x{} image", img.width(), img.height());
+        }
+        if width == 0 || height == 0 {
+            anyhow::bail!("crop of {width}x{height} pixels is empty");
+        }
+
+        let channels = P::CHANNEL_COUNT as usize;
         // Determine the x-coordinate of the right edge of the crop area
-        let x_end = min(x + width, img.width());
+        let x_end = min(x.saturating_add(width), img.width());
         // Determine the y-coordinate of the bottom edge of the crop area
-        let y_end = min(y + height, img.height());
+        let y_end = min(y.saturating_add(height), img.height());
         // Create a new image buffer to hold the cropped image
-        let mut cropped_img = ImageBuffer::new(x_end - x, y_end - y);
+        let mut cropped_img: Image<P> = ImageBuffer::new(x_end - x, y_end - y);
 
-        // Iterate over the pixels in the cropped image buffer and copy the corresponding pixels from the original image
-        for (x_cropped, y_cropped, pixel) in cropped_img.enumerate_pixels_mut() {
-            // Determine the corresponding pixel coordinates in the original image
-            let x_original = x + x_cropped;
-            let y_original = y + y_cropped;
+        // Every row of the cropped image is a contiguous slice of a row in the original image,
+        // so the rows can be copied in parallel
+        let src_row_len = img.width() as usize * channels;
+        let (start, end) = (x as usize * channels, x_end as usize * channels);
+        let cropped_row_len = end - start;
 
-            // If the original pixel is within the crop area, copy its value to the cropped image
-            if x_original < x_end && y_original < y_end {
-                *pixel = img.get_pixel(x_original, y_original).to_rgb();
-            }
-        }
+        cropped_img.par_chunks_mut(cropped_row_len).enumerate().for_each(|(y_cropped, row)| {
+            let row_offset = (y as usize + y_cropped) * src_row_len;
+            row.copy_from_slice(&img.as_raw()[row_offset + start..row_offset + end]);
+        });
 
-        cropped_img
+        Ok(cropped_img)
     }
 
-    // angle is in degrees
-    pub fn rotate(img: &RgbImage, angle: f32) -> RgbImage {
+    // angle is in degrees, the uncovered corners are left zeroed (black, or transparent with alpha)
+    pub fn rotate<P>(img: &Image<P>, angle: f32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let channels = P::CHANNEL_COUNT as usize;
         let (orig_width, orig_height) = img.dimensions();
         let mut rotated_width = orig_width;
         let mut rotated_height = orig_height;
@@ -67,135 +82,777 @@ impl Processing {
             rotated_height = orig_width;
         }
 
-        let mut rotated = ImageBuffer::new(rotated_width, rotated_height);
+        let mut rotated: Image<P> = ImageBuffer::new(rotated_width, rotated_height);
 
         let sin_a = angle.to_radians().sin();
         let cos_a = angle.to_radians().cos();
 
         let width_center = orig_width as f32 / 2.0;
         let height_center = orig_height as f32 / 2.0;
-
-        for (x, y, pixel) in img.enumerate_pixels() {
-            // Compute the new position of the pixel in the rotated image
-            // (rotation formulas were taken from https://homepages.inf.ed.ac.uk/rbf/HIPR2/rotate.htm)
-            let new_x = (cos_a * (x as f32 - width_center) - sin_a * (y as f32 - height_center) + rotated_width as f32 / 2.0) as u32;
-            let new_y = (sin_a * (x as f32 - width_center) + cos_a * (y as f32 - height_center) + rotated_height as f32 / 2.0) as u32;
-
-            if new_x < rotated_width && new_y < rotated_height {
-                // Copy the pixel from the original image to the rotated image
-                rotated.put_pixel(new_x, new_y, pixel.to_rgb());
+        let rotated_width_center = rotated_width as f32 / 2.0;
+        let rotated_height_center = rotated_height as f32 / 2.0;
+
+        // Every pixel of the rotated image looks up its source pixel through the inverse rotation,
+        // which lets the rows be filled independently of each other (and leaves no holes)
+        // (rotation formulas were taken from https://homepages.inf.ed.ac.uk/rbf/HIPR2/rotate.htm)
+        let row_len = rotated_width as usize * channels;
+        rotated.par_chunks_mut(row_len).enumerate().for_each(|(y, row)| {
+            let dy = y as f32 + 0.5 - rotated_height_center;
+            for (x, pixel) in row.chunks_exact_mut(channels).enumerate() {
+                let dx = x as f32 + 0.5 - rotated_width_center;
+                let orig_x = cos_a * dx + sin_a * dy + width_center;
+                let orig_y = -sin_a * dx + cos_a * dy + height_center;
+
+                if orig_x >= 0.0 && orig_y >= 0.0 && (orig_x as u32) < orig_width && (orig_y as u32) < orig_height {
+                    // Copy the pixel from the original image to the rotated image
+                    pixel.copy_from_slice(img.get_pixel(orig_x as u32, orig_y as u32).channels());
+                }
             }
-        }
+        });
 
         rotated
     }
 
-    fn is_a_shade_of_black(pixel: &Rgb<u8>, threshold: u8) -> bool {
-        pixel[0] <= threshold && pixel[1] <= threshold && pixel[2] <= threshold
+    /// Color the border is trimmed by on the 0 - 255 scale, taking `options.color` or detecting it from the corners.
+    pub fn border_color<P: Pixel>(image: &Image<P>, options: &TrimOptions) -> [f32; 3] {
+        match options.color {
+            BorderColor::Black => [0.0, 0.0, 0.0],
+            BorderColor::White => [255.0, 255.0, 255.0],
+            BorderColor::Rgb(color) => color.map(|c| c as f32),
+            BorderColor::Auto => Self::detect_border_color(image, options.tolerance),
+            // only the transparency matters, see `find_content`
+            BorderColor::Transparent => [0.0, 0.0, 0.0],
+        }
     }
 
-    fn find_top_edge(image: &RgbImage, threshold: u8) -> u32 {
+    // The border color is the corner color which the most other corners agree with (within tolerance),
+    // on a tie the top left corner wins.
+    fn detect_border_color<P: Pixel>(image: &Image<P>, tolerance: f32) -> [f32; 3] {
         let (width, height) = image.dimensions();
-        let mut top = height - 1;
-        for x in 0..width {
-            for y in 0..height {
-                if !Self::is_a_shade_of_black(image.get_pixel(x, y), threshold) && y < top {
-                    top = y;
-                    break;
-                }
-                if y == top {
-                    break;
-                }
+        let corners = [
+            rgb_of(image.get_pixel(0, 0)),
+            rgb_of(image.get_pixel(width - 1, 0)),
+            rgb_of(image.get_pixel(0, height - 1)),
+            rgb_of(image.get_pixel(width - 1, height - 1)),
+        ];
+
+        let mut best = corners[0];
+        let mut best_votes = 0;
+        for candidate in corners.iter() {
+            let votes = corners.iter().filter(|corner| channel_distance(*candidate, **corner) <= tolerance).count();
+            if votes > best_votes {
+                best = *candidate;
+                best_votes = votes;
             }
         }
 
-        top
+        best
     }
 
-    fn find_left_edge(image: &RgbImage, threshold: u8) -> u32 {
+    /// Find the rectangle holding the content of the image, i.e. everything that is not border colored
+    /// nor fully transparent, trimming only the sides enabled in `options` and keeping `options.padding`
+    /// pixels around it.
+    pub fn find_content<P>(image: &Image<P>, options: &TrimOptions) -> anyhow::Result<ContentRect>
+    where
+        P: Pixel + Sync,
+        P::Subpixel: Sync,
+    {
         let (width, height) = image.dimensions();
-        let mut left = width - 1;
-        for y in 0..height {
-            for x in 0..width {
-                if !Self::is_a_shade_of_black(image.get_pixel(x, y), threshold) && x < left {
-                    left = x;
-                    break;
+        if width == 0 || height == 0 {
+            anyhow::bail!("provided image is empty");
+        }
+        if options.tolerance.is_nan() || options.tolerance < 0.0 {
+            anyhow::bail!("tolerance {} is not a number of at least 0", options.tolerance);
+        }
+
+        let border = Self::border_color(image, options);
+        let is_content = |x: u32, y: u32| {
+            let px = image.get_pixel(x, y);
+            !is_transparent(px) && (options.color == BorderColor::Transparent || channel_distance(rgb_of(px), border) > options.tolerance)
+        };
+
+        // The rows are searched first, the columns then only need to be searched between the found rows
+        let (top, bottom) = rayon::join(
+            || (0..height).into_par_iter().find_first(|&y| (0..width).any(|x| is_content(x, y))),
+            || (0..height).into_par_iter().find_last(|&y| (0..width).any(|x| is_content(x, y))),
+        );
+        let (top, bottom) = match (top, bottom) {
+            (Some(top), Some(bottom)) => (top, bottom),
+            _ => anyhow::bail!("provided image is completely covered by the border color {:?}", border),
+        };
+
+        let (left, right) = rayon::join(
+            || (0..width).into_par_iter().find_first(|&x| (top..=bottom).any(|y| is_content(x, y))),
+            || (0..width).into_par_iter().find_last(|&x| (top..=bottom).any(|y| is_content(x, y))),
+        );
+        // A row with content always has a column with content
+        let (left, right) = (left.unwrap_or(0), right.unwrap_or(width - 1));
+
+        let sides = &options.sides;
+        let padding = options.padding;
+        let left = if sides.left { left.saturating_sub(padding) } else { 0 };
+        let top = if sides.top { top.saturating_sub(padding) } else { 0 };
+        let right = if sides.right { min(right.saturating_add(padding), width - 1) } else { width - 1 };
+        let bottom = if sides.bottom { min(bottom.saturating_add(padding), height - 1) } else { height - 1 };
+
+        Ok(ContentRect {
+            x: left,
+            y: top,
+            width: right - left + 1,
+            height: bottom - top + 1,
+        })
+    }
+
+    pub fn remove_borders<P>(image: &Image<P>, options: &TrimOptions) -> anyhow::Result<Image<P>>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let ContentRect { x, y, width, height } = Self::find_content(image, options)?;
+        println!("Trimming {}x{} image to {width}x{height} at ({x}, {y})", image.width(), image.height());
+
+        Self::crop_image(image, x, y, width, height)
+    }
+
+    /// Reduce the noise of the image with the given filter, every channel is filtered and alpha is kept
+    /// out of the color differences.
+    pub fn denoise<P>(buf: &Image<P>, filter: &Denoise) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let channels = P::CHANNEL_COUNT as usize;
+        let (width, height) = buf.dimensions();
+        let samples = Samples {
+            values: buf.as_raw().iter().map(|&v| normalized(v) * 255.0).collect(),
+            width: width as isize,
+            height: height as isize,
+            channels,
+            colors: if P::COLOR_MODEL.ends_with('A') { channels - 1 } else { channels },
+        };
+
+        // Weights of the bilateral filter by the offset from the center, the same for every pixel, and the
+        // non-local means, which are filtered for the whole image at once
+        let spatial: Vec<f32> = match *filter {
+            Denoise::Bilateral { radius, sigma_space, .. } => {
+                let (r, spread) = (radius as isize, 2.0 * sigma_space.powi(2).max(f32::EPSILON));
+                (-r..=r).flat_map(|dy| (-r..=r).map(move |dx| (-((dx * dx + dy * dy) as f32) / spread).exp())).collect()
+            }
+            _ => Vec::new(),
+        };
+
+        let means = match *filter {
+            Denoise::NonLocalMeans {
+                strength,
+                patch_radius,
+                search_radius,
+            } => samples.non_local_means(strength, patch_radius as isize, search_radius as isize),
+            _ => Vec::new(),
+        };
+
+        let mut denoised: Image<P> = ImageBuffer::new(width, height);
+        let row_len = width as usize * channels;
+        if row_len == 0 {
+            return denoised;
+        }
+
+        denoised.par_chunks_mut(row_len).enumerate().for_each(|(y, row)| {
+            for (x, px) in row.chunks_exact_mut(channels).enumerate() {
+                let (x, y) = (x as isize, y as isize);
+                let filtered = match *filter {
+                    Denoise::Median { radius } => samples.median(x, y, radius as isize),
+                    Denoise::Bilateral { radius, sigma_color, .. } => samples.bilateral(x, y, radius as isize, sigma_color, &spatial),
+                    Denoise::NonLocalMeans { .. } => means[(y * samples.width + x) as usize],
+                };
+
+                for (subpixel, value) in px.iter_mut().zip(filtered) {
+                    *subpixel = denormalized(value / 255.0);
                 }
-                if x == left {
-                    break;
+            }
+        });
+
+        denoised
+    }
+}
+
+// Subpixels of an image on the 0 - 255 scale, read with the coordinates clamped to the edges
+struct Samples {
+    values: Vec<f32>,
+    width: isize,
+    height: isize,
+    channels: usize,
+    // leading channels compared as color, all but alpha
+    colors: usize,
+}
+
+impl Samples {
+    fn pixel(&self, x: isize, y: isize) -> &[f32] {
+        let i = (y.clamp(0, self.height - 1) * self.width + x.clamp(0, self.width - 1)) as usize * self.channels;
+        &self.values[i..i + self.channels]
+    }
+
+    fn color_distance_squared(&self, a: &[f32], b: &[f32]) -> f32 {
+        a[..self.colors].iter().zip(&b[..self.colors]).map(|(a, b)| (a - b).powi(2)).sum::<f32>() / self.colors as f32
+    }
+
+    fn median(&self, x: isize, y: isize, radius: isize) -> [f32; 4] {
+        let mut window = Vec::with_capacity(((2 * radius + 1) * (2 * radius + 1)) as usize);
+        let mut filtered = [0.0; 4];
+        for (c, value) in filtered.iter_mut().enumerate().take(self.channels) {
+            window.clear();
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    window.push(self.pixel(x + dx, y + dy)[c]);
                 }
             }
+            let middle = window.len() / 2;
+            *value = *window.select_nth_unstable_by(middle, f32::total_cmp).1;
         }
 
-        left
+        filtered
     }
 
-    fn find_bottom_edge(image: &RgbImage, threshold: u8) -> u32 {
-        let (width, height) = image.dimensions();
-        let mut bottom = 0;
-        for x in 0..width {
-            for y in (0..height).rev() {
-                if !Self::is_a_shade_of_black(image.get_pixel(x, y), threshold) && y > bottom {
-                    bottom = y;
-                    break;
-                }
-                if y == bottom {
-                    break;
-                }
+    fn bilateral(&self, x: isize, y: isize, radius: isize, sigma_color: f32, spatial: &[f32]) -> [f32; 4] {
+        let center = self.pixel(x, y);
+        let range = 2.0 * sigma_color.powi(2).max(f32::EPSILON);
+
+        let (mut sum, mut total) = ([0.0; 4], 0.0);
+        let offsets = (-radius..=radius).flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)));
+        for ((dx, dy), spatial) in offsets.zip(spatial) {
+            let neighbour = self.pixel(x + dx, y + dy);
+            let weight = spatial * (-self.color_distance_squared(center, neighbour) / range).exp();
+            for (s, v) in sum.iter_mut().zip(neighbour) {
+                *s += weight * v;
             }
+            total += weight;
         }
 
-        bottom
+        sum.map(|s| s / total)
     }
 
-    fn find_right_edge(image: &RgbImage, threshold: u8) -> u32 {
-        let (width, height) = image.dimensions();
-        let mut right = 0;
-        for y in 0..height {
-            for x in (0..width).rev() {
-                if !Self::is_a_shade_of_black(image.get_pixel(x, y), threshold) && x > right {
-                    right = x;
-                    break;
+    // Computed for the whole image at once, one offset of the search window at a time: the differences to
+    // the pixels at that offset summed over the patches are the patch distances of every pixel
+    fn non_local_means(&self, strength: f32, patch_radius: isize, search_radius: isize) -> Vec<[f32; 4]> {
+        let (width, count) = (self.width as usize, (self.width * self.height) as usize);
+        let h = strength.powi(2).max(f32::EPSILON);
+        let patch_size = ((2 * patch_radius + 1) * (2 * patch_radius + 1)) as f32;
+        let position = |i: usize| ((i % width) as isize, (i / width) as isize);
+
+        let (mut sums, mut totals, mut strongest) = (vec![[0.0f32; 4]; count], vec![0.0f32; count], vec![0.0f32; count]);
+        for dy in -search_radius..=search_radius {
+            for dx in -search_radius..=search_radius {
+                if (dx, dy) == (0, 0) {
+                    continue;
                 }
-                if x == right {
-                    break;
+
+                let differences: Vec<f32> = (0..count)
+                    .into_par_iter()
+                    .map(|i| {
+                        let (x, y) = position(i);
+                        self.color_distance_squared(self.pixel(x, y), self.pixel(x + dx, y + dy))
+                    })
+                    .collect();
+                let distances = self.box_sum(&differences, patch_radius);
+
+                sums.par_iter_mut().zip(&mut totals).zip(&mut strongest).zip(distances).enumerate().for_each(|(i, (((sum, total), strongest), distance))| {
+                    let (x, y) = position(i);
+                    let weight = (-(distance / patch_size) / h).exp();
+                    for (s, v) in sum.iter_mut().zip(self.pixel(x + dx, y + dy)) {
+                        *s += weight * v;
+                    }
+                    *total += weight;
+                    *strongest = strongest.max(weight);
+                });
+            }
+        }
+
+        // The pixel itself would always be the best match, it counts as much as its best neighbour
+        sums.into_par_iter()
+            .zip(totals)
+            .zip(strongest)
+            .enumerate()
+            .map(|(i, ((mut sum, total), strongest))| {
+                let (x, y) = position(i);
+                let weight = if total > 0.0 { strongest } else { 1.0 };
+                for (s, v) in sum.iter_mut().zip(self.pixel(x, y)) {
+                    *s += weight * v;
                 }
+                sum.map(|s| s / (total + weight))
+            })
+            .collect()
+    }
+
+    // Sums of the values over the square windows of `radius`, with the edges clamped, in two passes
+    fn box_sum(&self, values: &[f32], radius: isize) -> Vec<f32> {
+        let (width, height) = (self.width, self.height);
+        let at = |x: isize, y: isize| (y.clamp(0, height - 1) * width + x.clamp(0, width - 1)) as usize;
+
+        let rows: Vec<f32> = (0..values.len())
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = (i as isize % width, i as isize / width);
+                (-radius..=radius).map(|d| values[at(x + d, y)]).sum()
+            })
+            .collect();
+        (0..values.len())
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = (i as isize % width, i as isize / width);
+                (-radius..=radius).map(|d| rows[at(x, y + d)]).sum()
+            })
+            .collect()
+    }
+}
+
+/// Euclidean distance of two colors in the 0 - 255 RGB cube, ranging from 0 to ~441.7.
+pub fn color_distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    let dr = a[0] - b[0];
+    let dg = a[1] - b[1];
+    let db = a[2] - b[2];
+
+    (dr * dr + dg * dg + db * db).sqrt()
+}
+
+/// Largest difference of the channels of two colors on the 0 - 255 scale, how far a pixel is from the
+/// border color for `Processing::find_content`.
+pub fn channel_distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (a[0] - b[0]).abs().max((a[1] - b[1]).abs()).max((a[2] - b[2]).abs())
+}
+
+/// Value of a subpixel scaled to 0.0 - 1.0, whatever its bit depth.
+pub fn normalized<T: Primitive>(value: T) -> f32 {
+    let value: f32 = NumCast::from(value).unwrap_or(0.0);
+    let max: f32 = NumCast::from(T::DEFAULT_MAX_VALUE).unwrap_or(1.0);
+
+    value / max
+}
+
+/// Subpixel of the value given on the 0.0 - 1.0 scale, rounded and clamped to the range of the type.
+pub fn denormalized<T: Primitive>(value: f32) -> T {
+    let max: f32 = NumCast::from(T::DEFAULT_MAX_VALUE).unwrap_or(1.0);
+    let scaled = value.clamp(0.0, 1.0) * max;
+    // integer subpixels are rounded, float ones are kept as they are
+    let scaled = if max > 1.0 { scaled.round() } else { scaled };
+
+    NumCast::from(scaled).unwrap_or(T::DEFAULT_MIN_VALUE)
+}
+
+/// Color of a pixel in RGB on the 0 - 255 scale, whatever its color type and bit depth.
+pub fn rgb_of<P: Pixel>(px: &P) -> [f32; 3] {
+    px.to_rgb().0.map(|c| normalized(c) * 255.0)
+}
+
+/// Color of a pixel in RGBA on the 0.0 - 1.0 scale, whatever its color type and bit depth.
+pub fn rgba_of<P: Pixel>(px: &P) -> [f32; 4] {
+    px.to_rgba().0.map(normalized)
+}
+
+/// Set a pixel of any color type from RGBA on the 0.0 - 1.0 scale, grayscale pixels take the luminance
+/// and pixels without alpha drop it.
+pub fn set_rgba<P: Pixel>(px: &mut P, rgba: [f32; 4]) {
+    let luma = 0.2126 * rgba[0] + 0.7152 * rgba[1] + 0.0722 * rgba[2];
+    let channels = px.channels_mut();
+    let values: &[f32] = match P::COLOR_MODEL {
+        "Y" => &[luma],
+        "YA" => &[luma, rgba[3]],
+        "RGB" => &rgba[..3],
+        _ => &rgba,
+    };
+
+    for (channel, value) in channels.iter_mut().zip(values) {
+        *channel = denormalized(*value);
+    }
+}
+
+/// Gaussian blur of a single float channel with a kernel summing to 1, the one of imageproc's
+/// `gaussian_blur_f32` is cut at 2 sigma without being normalized, which darkens even a flat image by about 3 %.
+pub fn gaussian_blur(buf: &Image<Luma<f32>>, sigma: f32) -> Image<Luma<f32>> {
+    let sigma = sigma.max(0.1);
+    let radius = (3.0 * sigma).ceil() as i32;
+    let kernel: Vec<f32> = (-radius..=radius).map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    let total: f32 = kernel.iter().sum();
+    let kernel: Vec<f32> = kernel.iter().map(|k| k / total).collect();
+    imageproc::filter::separable_filter_equal(buf, &kernel)
+}
+
+fn is_transparent<P: Pixel>(px: &P) -> bool {
+    px.to_rgba()[3] == <P::Subpixel as Primitive>::DEFAULT_MIN_VALUE
+}
+
+/// Color of the border removed by `Processing::remove_borders`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BorderColor {
+    Black,
+    White,
+    Rgb([u8; 3]),
+    /// Detected from the corners of the image.
+    Auto,
+    /// Only fully transparent pixels, whatever their color.
+    Transparent,
+}
+
+impl FromStr for BorderColor {
+    type Err = anyhow::Error;
+
+    /// Parse `auto`, `transparent` or any color accepted by `parse_color`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "black" => Ok(BorderColor::Black),
+            "white" => Ok(BorderColor::White),
+            "auto" => Ok(BorderColor::Auto),
+            "transparent" => Ok(BorderColor::Transparent),
+            _ => {
+                let [r, g, b, _] = parse_color(s)?;
+                Ok(BorderColor::Rgb([r, g, b]))
+            }
+        }
+    }
+}
+
+/// Parse a RGBA color given by name (`black`, `white`, `red`, `green`, `blue`, `yellow`, `cyan`, `magenta`,
+/// `gray`), as hex `#rrggbb` / `#rrggbbaa` (the `#` is optional) or as `r,g,b` / `r,g,b,a`.
+pub fn parse_color(s: &str) -> anyhow::Result<[u8; 4]> {
+    let s = s.trim();
+    let named = match s.to_ascii_lowercase().as_str() {
+        "black" => Some([0, 0, 0, 255]),
+        "white" => Some([255, 255, 255, 255]),
+        "red" => Some([255, 0, 0, 255]),
+        "green" => Some([0, 255, 0, 255]),
+        "blue" => Some([0, 0, 255, 255]),
+        "yellow" => Some([255, 255, 0, 255]),
+        "cyan" => Some([0, 255, 255, 255]),
+        "magenta" => Some([255, 0, 255, 255]),
+        "gray" | "grey" => Some([128, 128, 128, 255]),
+        _ => None,
+    };
+    if let Some(color) = named {
+        return Ok(color);
+    }
+
+    let channels: Vec<u8> = if s.contains(',') {
+        s.split(',').map(|c| c.trim().parse::<u8>()).collect::<Result<_, _>>()?
+    } else {
+        let hex = s.trim_start_matches('#');
+        if !hex.is_ascii() || !matches!(hex.len(), 6 | 8) {
+            anyhow::bail!("unknown color {s}");
+        }
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16)).collect::<Result<_, _>>()?
+    };
+
+    match channels[..] {
+        [r, g, b] => Ok([r, g, b, 255]),
+        [r, g, b, a] => Ok([r, g, b, a]),
+        _ => anyhow::bail!("expected 3 or 4 channels in color {s}"),
+    }
+}
+
+/// Sides of the image which get trimmed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sides {
+    pub top: bool,
+    pub left: bool,
+    pub bottom: bool,
+    pub right: bool,
+}
+
+impl Default for Sides {
+    fn default() -> Self {
+        Self {
+            top: true,
+            left: true,
+            bottom: true,
+            right: true,
+        }
+    }
+}
+
+impl FromStr for Sides {
+    type Err = anyhow::Error;
+
+    /// Parse a comma separated list of sides, e.g. `top,bottom`, or `all`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "all" {
+            return Ok(Sides::default());
+        }
+
+        let mut sides = Sides {
+            top: false,
+            left: false,
+            bottom: false,
+            right: false,
+        };
+        for side in s.split(',') {
+            match side.trim() {
+                "top" => sides.top = true,
+                "left" => sides.left = true,
+                "bottom" => sides.bottom = true,
+                "right" => sides.right = true,
+                other => anyhow::bail!("unknown side {other}"),
             }
         }
 
-        right
+        Ok(sides)
+    }
+}
+
+/// Options of `Processing::remove_borders`, by default trimming near-black borders on all sides.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrimOptions {
+    pub color: BorderColor,
+    /// Maximum `channel_distance` from the border color (0 - 255, of every channel) for a pixel to still
+    /// count as border.
+    pub tolerance: f32,
+    pub sides: Sides,
+    /// Pixels of border kept around the content after trimming.
+    pub padding: u32,
+}
+
+impl Default for TrimOptions {
+    fn default() -> Self {
+        Self {
+            color: BorderColor::Black,
+            tolerance: 40.0,
+            sides: Sides::default(),
+            padding: 0,
+        }
+    }
+}
+
+/// Rectangle of the image content found by `Processing::find_content`, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ContentRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Noise reduction filter of `Processing::denoise` with its parameters. Radiuses are in pixels, color
+/// differences on the 0 - 255 scale.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Denoise {
+    /// Median of every channel over the square window of `radius`, for speckles and salt and pepper noise.
+    Median { radius: u32 },
+    /// Average of the window of `radius` weighted by the distance (`sigma_space`) and by the difference
+    /// of color (`sigma_color`) to the center, smoothing flat areas while keeping edges sharp.
+    Bilateral { radius: u32, sigma_color: f32, sigma_space: f32 },
+    /// Average of the pixels within `search_radius` weighted by how alike the patches of `patch_radius`
+    /// around them are, `strength` being the typical difference of alike patches
+    /// (https://www.ipol.im/pub/art/2011/bcm_nlm/). The best filter for fine textures, and the slowest.
+    NonLocalMeans { strength: f32, patch_radius: u32, search_radius: u32 },
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Luma, LumaA, Rgb, RgbImage, Rgba};
+
+    use super::*;
+
+    // Content of `color` in the middle of a border of `border`, 10 pixels wide on every side
+    fn framed(border: [u8; 3], color: [u8; 3]) -> RgbImage {
+        RgbImage::from_fn(40, 30, |x, y| {
+            let inside = (10..30).contains(&x) && (10..20).contains(&y);
+            Rgb(if inside { color } else { border })
+        })
+    }
+
+    #[test]
+    fn negative_keeps_the_alpha_of_every_pixel_type() {
+        let mut gray = ImageBuffer::from_pixel(2, 2, LumaA([1000u16, 40000]));
+        Processing::negative_basic(&mut gray);
+        assert_eq!(gray.get_pixel(1, 1), &LumaA([64535, 40000]));
+
+        let mut float = ImageBuffer::from_pixel(2, 2, Rgba([0.25f32, 0.5, 1.0, 0.5]));
+        Processing::negative_basic(&mut float);
+        assert_eq!(float.get_pixel(0, 0), &Rgba([0.75, 0.5, 0.0, 0.5]));
+    }
+
+    #[test]
+    fn subpixels_are_scaled_to_their_depth() {
+        assert_eq!((normalized(255u8), normalized(0u16), normalized(0.25f32)), (1.0, 0.0, 0.25));
+        assert_eq!((denormalized::<u8>(0.5), denormalized::<u16>(1.5), denormalized::<f32>(0.3)), (128, 65535, 0.3));
+
+        // grayscale pixels take the luminance, pixels without alpha drop it
+        let mut gray = Luma([0u8]);
+        set_rgba(&mut gray, [0.0, 1.0, 0.0, 0.5]);
+        assert_eq!(gray, Luma([182]));
+        let mut rgb = Rgb([0u16; 3]);
+        set_rgba(&mut rgb, [1.0, 0.5, 0.0, 0.0]);
+        assert_eq!(rgb, Rgb([65535, 32768, 0]));
+        assert_eq!(rgb_of(&LumaA([51u8, 0])), [51.0, 51.0, 51.0]);
+    }
+
+    #[test]
+    fn crop_keeps_the_pixel_type_and_is_cut_short() {
+        let image = ImageBuffer::from_fn(10, 8, |x, y| Luma([(x * 1000 + y) as u16]));
+        let cropped = Processing::crop_image(&image, 6, 5, 10, 10).unwrap();
+
+        assert_eq!(cropped.dimensions(), (4, 3));
+        assert_eq!(cropped.get_pixel(1, 2), &Luma([7007]));
+        assert!(Processing::crop_image(&image, 10, 0, 2, 2).is_err());
+        assert!(Processing::crop_image(&image, 0, 0, 0, 2).is_err());
+    }
+
+    #[test]
+    fn transparent_borders_are_trimmed() {
+        let image = ImageBuffer::from_fn(20, 10, |x, y| match (5..15).contains(&x) && (2..8).contains(&y) {
+            true => Rgba([0u8, 0, 0, 255]),
+            false => Rgba([255, 255, 255, 0]),
+        });
+        let options = TrimOptions {
+            color: BorderColor::Transparent,
+            ..Default::default()
+        };
+
+        let trimmed = Processing::remove_borders(&image, &options).unwrap();
+        assert_eq!(trimmed.dimensions(), (10, 6));
+        assert!(trimmed.pixels().all(|px| px == &Rgba([0, 0, 0, 255])));
+    }
+
+    // Gray level with a deterministic noise of up to `amount` either way
+    fn noisy(x: u32, y: u32, level: u8, amount: i32) -> u8 {
+        let hash = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)) % (2 * amount as u32 + 1);
+        (level as i32 + hash as i32 - amount).clamp(0, 255) as u8
+    }
+
+    fn deviation(image: &RgbImage, level: f32) -> f32 {
+        let sum: f32 = image.pixels().map(|px| (px[0] as f32 - level).powi(2)).sum();
+        (sum / image.pixels().len() as f32).sqrt()
+    }
+
+    #[test]
+    fn median_removes_salt_and_pepper() {
+        let image = RgbImage::from_fn(20, 20, |x, y| match (x * 7 + y * 3) % 23 {
+            0 => Rgb([255, 255, 255]),
+            1 => Rgb([0, 0, 0]),
+            _ => Rgb([120, 120, 120]),
+        });
+
+        let denoised = Processing::denoise(&image, &Denoise::Median { radius: 1 });
+        assert!(denoised.pixels().all(|px| px == &Rgb([120, 120, 120])));
     }
 
-    pub fn remove_borders(image: &RgbImage) -> Result<RgbImage, &'static str> {
-        let threshold = 40;
+    #[test]
+    fn bilateral_smooths_flat_areas_and_keeps_edges() {
+        let image = RgbImage::from_fn(20, 20, |x, y| {
+            let level = noisy(x, y, if x < 10 { 40 } else { 210 }, 15);
+            Rgb([level, level, level])
+        });
+        let filter = Denoise::Bilateral {
+            radius: 3,
+            sigma_color: 40.0,
+            sigma_space: 2.0,
+        };
+
+        let denoised = Processing::denoise(&image, &filter);
+        let left = RgbImage::from_fn(8, 20, |x, y| *denoised.get_pixel(x, y));
+        assert!(deviation(&left, 40.0) < deviation(&RgbImage::from_fn(8, 20, |x, y| *image.get_pixel(x, y)), 40.0) / 2.0);
+        assert!(denoised.get_pixel(9, 10)[0] < 70 && denoised.get_pixel(10, 10)[0] > 180);
+    }
+
+    #[test]
+    fn non_local_means_reduces_the_noise_and_keeps_alpha() {
+        let image = RgbImage::from_fn(24, 24, |x, y| {
+            let level = noisy(x, y, 128, 20);
+            Rgb([level, level, level])
+        });
+        let filter = Denoise::NonLocalMeans {
+            strength: 20.0,
+            patch_radius: 1,
+            search_radius: 5,
+        };
+
+        let denoised = Processing::denoise(&image, &filter);
+        assert!(deviation(&denoised, 128.0) < deviation(&image, 128.0) / 2.0);
+
+        let translucent = ImageBuffer::from_fn(8, 8, |x, y| Rgba([noisy(x, y, 128, 20), 128, 128, 77]));
+        let denoised = Processing::denoise(&translucent, &filter);
+        assert!(denoised.pixels().all(|px| px[3] == 77));
+    }
+
+    #[test]
+    fn quarter_turns_move_every_pixel_exactly() {
+        // no two pixels alike, so that any pixel taken from a neighbor shows
+        let image = RgbImage::from_fn(5, 3, |x, y| Rgb([x as u8 * 50, y as u8 * 100, 0]));
+
+        assert_eq!(Processing::rotate(&image, 0.0), image);
+        assert_eq!(Processing::rotate(&image, 90.0), image::imageops::rotate90(&image));
+        assert_eq!(Processing::rotate(&image, 180.0), image::imageops::rotate180(&image));
+        assert_eq!(Processing::rotate(&image, 270.0), image::imageops::rotate270(&image));
+    }
+
+    #[test]
+    fn rotation_leaves_no_holes() {
+        // the inverse mapping gives every pixel within the turned image a source, the forward one left gaps
+        let image = RgbImage::from_pixel(40, 40, Rgb([255, 255, 255]));
+        let rotated = Processing::rotate(&image, 30.0);
+
+        let (center, radius) = (20.0, 19.0 * 30f32.to_radians().cos() - 1.0);
+        for (x, y, px) in rotated.enumerate_pixels() {
+            if (x as f32 + 0.5 - center).hypot(y as f32 + 0.5 - center) < radius {
+                assert_eq!(px, &Rgb([255, 255, 255]), "hole at {x},{y}");
+            }
+        }
+    }
 
-        // Find top edge
-        let image2 = image.clone();
-        let top_edge_handle = thread::spawn(move || Self::find_top_edge(&image2, threshold));
+    #[test]
+    fn near_black_border_is_trimmed_by_default() {
+        // every channel within 40 of black, as the fixed threshold before the tolerance was configurable
+        let image = framed([30, 30, 30], [200, 120, 50]);
 
-        // Find left edge
-        let image3 = image.clone();
-        let left_edge_handle = thread::spawn(move || Self::find_left_edge(&image3, threshold));
+        let rect = Processing::find_content(&image, &TrimOptions::default()).unwrap();
+        assert_eq!((rect.x, rect.y, rect.width, rect.height), (10, 10, 20, 10));
+    }
 
-        // Find bottom edge
-        let image4 = image.clone();
-        let bottom_edge_handle = thread::spawn(move || Self::find_bottom_edge(&image4, threshold));
+    #[test]
+    fn border_beyond_the_tolerance_is_content() {
+        let image = framed([41, 0, 0], [200, 120, 50]);
 
-        // Find right edge
-        let image5 = image.clone();
-        let right_edge_handle = thread::spawn(move || Self::find_right_edge(&image5, threshold));
+        let rect = Processing::find_content(&image, &TrimOptions::default()).unwrap();
+        assert_eq!((rect.x, rect.y, rect.width, rect.height), (0, 0, 40, 30));
+    }
 
-        let top = top_edge_handle.join().unwrap();
-        let left = left_edge_handle.join().unwrap();
-        let bottom = bottom_edge_handle.join().unwrap();
-        let right = right_edge_handle.join().unwrap();
+    #[test]
+    fn padding_and_sides_are_kept() {
+        let image = framed([0, 0, 0], [255, 255, 255]);
+        let options = TrimOptions {
+            padding: 2,
+            sides: "left,top".parse().unwrap(),
+            ..Default::default()
+        };
+
+        let rect = Processing::find_content(&image, &options).unwrap();
+        assert_eq!((rect.x, rect.y, rect.width, rect.height), (8, 8, 32, 22));
+    }
 
-        println!("LOG: {} {} {} {}", left, top, bottom, right);
+    #[test]
+    fn auto_border_color_is_taken_from_the_corners() {
+        let image = framed([250, 250, 250], [0, 0, 0]);
+        let options = TrimOptions {
+            color: BorderColor::Auto,
+            ..Default::default()
+        };
 
-        if right < left {
-            return Err("provided image is completely black");
+        let rect = Processing::find_content(&image, &options).unwrap();
+        assert_eq!((rect.x, rect.y, rect.width, rect.height), (10, 10, 20, 10));
+    }
+
+    #[test]
+    fn negative_or_nan_tolerance_is_rejected() {
+        let image = framed([0, 0, 0], [255, 255, 255]);
+
+        for tolerance in [-1.0, f32::NAN] {
+            let options = TrimOptions {
+                tolerance,
+                ..Default::default()
+            };
+            assert!(Processing::find_content(&image, &options).is_err());
         }
+    }
 
-        Ok(Self::crop_image(image, left, top, right - left + 1, bottom - top + 1))
+    #[test]
+    fn image_of_only_border_is_an_error() {
+        let image = RgbImage::from_pixel(8, 8, Rgb([10, 10, 10]));
+        assert!(Processing::remove_borders(&image, &TrimOptions::default()).is_err());
     }
 }
@@ -0,0 +1,358 @@
+use image::{imageops, GrayImage, Luma, Pixel};
+use rayon::prelude::*;
+
+use super::distortion::sample_bilinear;
+use super::processing::{rgb_of, ContentRect, Image};
+
+/// Energy added to the pixels under a fully white protection mask, far above any gradient so that seams
+/// only go through protected areas when there is no way around them.
+const PROTECTED_ENERGY: f32 = 1000.0;
+
+pub struct Retarget {}
+
+impl Retarget {
+    /// Largest output in pixels.
+    pub const MAX_PIXELS: u64 = 100_000_000;
+    /// Most seams times the pixels they are searched in, every seam being a pass over the whole image. About
+    /// ten seconds of carving.
+    pub const MAX_SEAM_PIXELS: u64 = 1_000_000_000;
+
+    /// Resize the image to `width` x `height` by seam carving (https://faculty.idc.ac.il/arik/SCWeb/imret/),
+    /// removing or duplicating the paths of least visible detail instead of scaling everything alike.
+    /// Pixels under the white areas of `protect` (of the size of the image) are kept out of the seams.
+    /// Targets over twice the size of the image, over `MAX_PIXELS` or needing more than `MAX_SEAM_PIXELS`
+    /// of work are rejected.
+    pub fn seam_carve<P>(buf: &Image<P>, width: u32, height: u32, protect: Option<&GrayImage>) -> anyhow::Result<Image<P>>
+    where
+        P: Pixel + Send + Sync + 'static,
+        P::Subpixel: Send + Sync,
+    {
+        if width == 0 || height == 0 {
+            anyhow::bail!("target size {width}x{height} has no area");
+        }
+        if buf.width() < 2 || buf.height() < 2 {
+            anyhow::bail!("a {}x{} image has no seams to carve", buf.width(), buf.height());
+        }
+
+        let (source_width, source_height) = (buf.width() as u64, buf.height() as u64);
+        let (target_width, target_height) = (width as u64, height as u64);
+        if target_width > 2 * source_width || target_height > 2 * source_height {
+            anyhow::bail!("target size {width}x{height} is more than twice the size of the {source_width}x{source_height} image");
+        }
+        if target_width * target_height > Self::MAX_PIXELS {
+            anyhow::bail!("target size {width}x{height} is larger than {} pixels", Self::MAX_PIXELS);
+        }
+        // The columns are carved on the image, the rows on the image of the target width
+        let work = source_width.abs_diff(target_width) * source_width.max(target_width) * source_height
+            + source_height.abs_diff(target_height) * source_height.max(target_height) * target_width;
+        if work > Self::MAX_SEAM_PIXELS {
+            anyhow::bail!("carving {source_width}x{source_height} to {width}x{height} takes too many seams, resize the image closer to the target first");
+        }
+
+        let empty = GrayImage::new(buf.width(), buf.height());
+        let protect = match protect {
+            Some(mask) if mask.dimensions() != buf.dimensions() => {
+                anyhow::bail!("the protection mask has to be of the size of the image")
+            }
+            Some(mask) => mask,
+            None => &empty,
+        };
+
+        // Columns first, then rows as the columns of the image turned on its side
+        let (carved, protect) = Self::carve_columns(buf, protect, width);
+        let (carved, _) = Self::carve_columns(&imageops::rotate90(&carved), &imageops::rotate90(&protect), height);
+
+        Ok(imageops::rotate270(&carved))
+    }
+
+    // Carve the image and its protection mask to `target_width` columns
+    fn carve_columns<P>(buf: &Image<P>, protect: &GrayImage, target_width: u32) -> (Image<P>, GrayImage)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let map = match target_width.cmp(&buf.width()) {
+            std::cmp::Ordering::Equal => return (buf.clone(), protect.clone()),
+            std::cmp::Ordering::Less => Self::removal_map(buf, protect, target_width),
+            std::cmp::Ordering::Greater => Self::insertion_map(buf, protect, target_width),
+        };
+
+        (resample_rows(buf, &map), resample_rows(protect, &map))
+    }
+
+    // Source columns of every row after removing the lowest energy seams one by one
+    fn removal_map<P: Pixel>(buf: &Image<P>, protect: &GrayImage, target_width: u32) -> Vec<Vec<f32>> {
+        let mut carver = Carver::new(buf, protect);
+        while carver.width() > target_width as usize {
+            carver.remove_seam();
+        }
+
+        carver.columns.into_iter().map(|row| row.into_iter().map(|x| x as f32).collect()).collect()
+    }
+
+    // Source columns of every row with the seams which would be removed first duplicated, the duplicates
+    // sampled halfway to the next column. Large enlargements are done in steps of at most half the width,
+    // since the same seams would be picked over and over.
+    fn insertion_map<P>(buf: &Image<P>, protect: &GrayImage, target_width: u32) -> Vec<Vec<f32>>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let mut map: Vec<Vec<f32>> = (0..buf.height()).map(|_| (0..buf.width()).map(|x| x as f32).collect()).collect();
+
+        let mut width = buf.width() as usize;
+        while width < target_width as usize {
+            let count = (target_width as usize - width).min(width / 2);
+
+            // The seams are found on the current state of the image, as mapped from the source
+            let current: Image<P> = resample_rows(buf, &map);
+            let current_protect = resample_rows(protect, &map);
+            let mut carver = Carver::new(&current, &current_protect);
+            let mut duplicated = vec![vec![false; width]; buf.height() as usize];
+            for _ in 0..count {
+                for (y, x) in carver.remove_seam().into_iter().enumerate() {
+                    duplicated[y][x as usize] = true;
+                }
+            }
+
+            for (row, duplicated) in map.iter_mut().zip(duplicated) {
+                let last = row.len() - 1;
+                let mut widened = Vec::with_capacity(row.len() + count);
+                for (x, &source) in row.iter().enumerate() {
+                    widened.push(source);
+                    if duplicated[x] {
+                        let next = row[(x + 1).min(last)];
+                        widened.push((source + next) / 2.0);
+                    }
+                }
+                *row = widened;
+            }
+            width += count;
+        }
+
+        map
+    }
+}
+
+// Shrinking state of an image, each row holding the source columns still left with their luminance,
+// protection and energy
+struct Carver {
+    columns: Vec<Vec<u32>>,
+    luma: Vec<Vec<f32>>,
+    protect: Vec<Vec<f32>>,
+    energy: Vec<Vec<f32>>,
+}
+
+impl Carver {
+    fn new<P: Pixel>(buf: &Image<P>, protect: &GrayImage) -> Self {
+        let (width, height) = buf.dimensions();
+        let luma = (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        let [r, g, b] = rgb_of(buf.get_pixel(x, y));
+                        (0.2126 * r + 0.7152 * g + 0.0722 * b) / 255.0
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut carver = Self {
+            columns: (0..height).map(|_| (0..width).collect()).collect(),
+            luma,
+            protect: (0..height)
+                .map(|y| (0..width).map(|x| protect.get_pixel(x, y)[0] as f32 / 255.0 * PROTECTED_ENERGY).collect())
+                .collect(),
+            energy: vec![vec![0.0; width as usize]; height as usize],
+        };
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                carver.energy[y][x] = carver.energy_at(x, y);
+            }
+        }
+
+        carver
+    }
+
+    fn width(&self) -> usize {
+        self.columns[0].len()
+    }
+
+    // Sum of the absolute luminance differences between the neighbours, plus the protection
+    fn energy_at(&self, x: usize, y: usize) -> f32 {
+        let (width, height) = (self.width(), self.luma.len());
+        let (left, right) = (x.saturating_sub(1), (x + 1).min(width - 1));
+        let (up, down) = (y.saturating_sub(1), (y + 1).min(height - 1));
+
+        let dx = (self.luma[y][right] - self.luma[y][left]).abs();
+        let dy = (self.luma[down][x] - self.luma[up][x]).abs();
+        dx + dy + self.protect[y][x]
+    }
+
+    // Remove the connected top to bottom path of least total energy, returning the source column it took
+    // in every row
+    fn remove_seam(&mut self) -> Vec<u32> {
+        let (width, height) = (self.width(), self.luma.len());
+
+        // Least energy of a path from the top row to every pixel
+        let mut cost = self.energy[0].clone();
+        let mut from = vec![vec![0u8; width]; height];
+        for (y, from) in from.iter_mut().enumerate().skip(1) {
+            let previous = cost;
+            cost = (0..width)
+                .map(|x| {
+                    let (step, least) = [(0u8, x.wrapping_sub(1)), (1, x), (2, x + 1)]
+                        .into_iter()
+                        .filter(|&(_, px)| px < width)
+                        .map(|(step, px)| (step, previous[px]))
+                        .min_by(|a, b| a.1.total_cmp(&b.1))
+                        .unwrap_or((1, 0.0));
+                    from[x] = step;
+                    least + self.energy[y][x]
+                })
+                .collect();
+        }
+
+        let mut seam = vec![0usize; height];
+        seam[height - 1] = (0..width).min_by(|&a, &b| cost[a].total_cmp(&cost[b])).unwrap_or(0);
+        for y in (1..height).rev() {
+            seam[y - 1] = (seam[y] + from[y][seam[y]] as usize).saturating_sub(1).min(width - 1);
+        }
+
+        let removed = seam.iter().enumerate().map(|(y, &x)| self.columns[y][x]).collect();
+        self.columns.par_iter_mut().zip(&seam).for_each(|(row, &x)| {
+            row.remove(x);
+        });
+        for rows in [&mut self.luma, &mut self.protect, &mut self.energy] {
+            rows.par_iter_mut().zip(&seam).for_each(|(row, &x)| {
+                row.remove(x);
+            });
+        }
+
+        // Only the energy around the seam changed
+        for (y, &x) in seam.iter().enumerate() {
+            for x in x.saturating_sub(2)..(x + 2).min(width - 1) {
+                self.energy[y][x] = self.energy_at(x, y);
+            }
+        }
+
+        removed
+    }
+}
+
+// Build an image whose rows are sampled from the given source columns of the same rows
+fn resample_rows<P>(buf: &Image<P>, map: &[Vec<f32>]) -> Image<P>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Send + Sync,
+{
+    let width = map[0].len() as u32;
+    let channels = P::CHANNEL_COUNT as usize;
+    let mut resampled: Image<P> = Image::new(width, buf.height());
+
+    resampled
+        .par_chunks_mut(width as usize * channels)
+        .zip(map.par_iter())
+        .enumerate()
+        .for_each(|(y, (row, sources))| {
+            for (px, &source) in row.chunks_exact_mut(channels).zip(sources) {
+                if let Some(sampled) = sample_bilinear(buf, source, y as f32) {
+                    px.copy_from_slice(sampled.channels());
+                }
+            }
+        });
+
+    resampled
+}
+
+/// Protection mask of the size of the image with the given regions in white.
+pub fn protection_mask(width: u32, height: u32, regions: &[ContentRect]) -> GrayImage {
+    let mut mask = GrayImage::new(width, height);
+    for region in regions {
+        for y in region.y.min(height)..(region.y + region.height).min(height) {
+            for x in region.x.min(width)..(region.x + region.width).min(width) {
+                mask.put_pixel(x, y, Luma([255]));
+            }
+        }
+    }
+
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    // Flat gray with a checkered block of detail in columns 20 - 29
+    fn detail() -> RgbImage {
+        RgbImage::from_fn(50, 20, |x, y| match (20..30).contains(&x) && (x + y) % 2 == 0 {
+            true => Rgb([0, 0, 0]),
+            false => Rgb([128, 128, 128]),
+        })
+    }
+
+    fn columns(image: &RgbImage, columns: std::ops::Range<u32>) -> Vec<Rgb<u8>> {
+        columns.flat_map(|x| (0..image.height()).map(move |y| *image.get_pixel(x, y))).collect()
+    }
+
+    #[test]
+    fn seams_go_around_the_detail() {
+        let image = detail();
+        let carved = Retarget::seam_carve(&image, 35, 20, None).unwrap();
+
+        assert_eq!(carved.dimensions(), (35, 20));
+        let kept = (0..=25).any(|x| columns(&carved, x..x + 10) == columns(&image, 20..30));
+        assert!(kept, "the checkered block was carved");
+    }
+
+    #[test]
+    fn images_shrink_and_grow_in_both_directions() {
+        let image = detail();
+
+        assert_eq!(Retarget::seam_carve(&image, 60, 15, None).unwrap().dimensions(), (60, 15));
+        assert_eq!(Retarget::seam_carve(&image, 40, 25, None).unwrap().dimensions(), (40, 25));
+        assert!(Retarget::seam_carve(&image, 0, 20, None).is_err());
+        assert!(Retarget::seam_carve(&RgbImage::new(1, 5), 1, 3, None).is_err());
+    }
+
+    #[test]
+    fn targets_beyond_the_limits_are_rejected() {
+        assert_eq!(detail().dimensions(), (50, 20));
+        assert!(Retarget::seam_carve(&detail(), 101, 20, None).is_err());
+        assert!(Retarget::seam_carve(&detail(), 50, 41, None).is_err());
+        assert!(Retarget::seam_carve(&detail(), 100, 40, None).is_ok());
+
+        // over the pixel limit, and seams through 5 million pixels for almost every column
+        assert!(Retarget::seam_carve(&GrayImage::new(8000, 8000), 15000, 8000, None).is_err());
+        assert!(Retarget::seam_carve(&GrayImage::new(5000, 1000), 1, 1000, None).is_err());
+    }
+
+    #[test]
+    fn protected_pixels_are_kept_out_of_the_seams() {
+        // faint columns on the left, whose seams cost less than those through the detail everywhere else
+        let image = RgbImage::from_fn(50, 20, |x, y| match (x, (x + y) % 2) {
+            (0..=9, _) => Rgb([100 + x as u8, 100, 100]),
+            (_, 0) => Rgb([0, 0, 0]),
+            _ => Rgb([255, 255, 255]),
+        });
+        let protect = protection_mask(
+            50,
+            20,
+            &[ContentRect {
+                x: 0,
+                y: 0,
+                width: 10,
+                height: 20,
+            }],
+        );
+        assert_eq!((protect.get_pixel(9, 19)[0], protect.get_pixel(10, 0)[0]), (255, 0));
+
+        let carved = Retarget::seam_carve(&image, 45, 20, None).unwrap();
+        assert_ne!(columns(&carved, 0..10), columns(&image, 0..10));
+        let carved = Retarget::seam_carve(&image, 45, 20, Some(&protect)).unwrap();
+        assert_eq!(columns(&carved, 0..10), columns(&image, 0..10));
+        assert!(Retarget::seam_carve(&image, 45, 20, Some(&GrayImage::new(10, 10))).is_err());
+    }
+}
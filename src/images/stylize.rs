@@ -0,0 +1,468 @@
+use image::{imageops::FilterType, DynamicImage, GrayImage, ImageBuffer, Luma, Pixel};
+use imageproc::gradients::sobel_gradients;
+use rand::Rng;
+use rayon::prelude::*;
+use std::f32::consts::PI;
+
+use super::color::luminance;
+use super::distortion::row_rng;
+use super::processing::{gaussian_blur, rgba_of, set_rgba, Denoise, Image, Processing};
+use super::text::{Text, TextStyle};
+
+/// Artistic filter with its parameters. Lengths are in pixels, colors are sRGB.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Style {
+    /// Oil painting, every pixel taking the mean color of the least varied of the four quadrants of `radius`
+    /// around it (Kuwahara filter), which flattens the details into brush strokes while keeping the edges.
+    Oil { radius: u32 },
+    /// Pencil sketch, the grayscale image divided by its blurred negative (color dodge), `sigma` setting the
+    /// width of the strokes.
+    Sketch { sigma: f32 },
+    /// Cartoon, the colors smoothed by a bilateral filter and reduced to `levels` per channel, with the edges
+    /// of a gradient stronger than `edges` (Sobel, on the 0 - 255 scale) drawn in black.
+    Cartoon { levels: u32, edges: f32 },
+    /// Every channel reduced to `levels` values.
+    Posterize { levels: u32 },
+    /// Brown tone of old photos, `strength` 0.0 - 1.0.
+    Sepia { strength: f32 },
+    /// Luminance mapped from the `shadows` color to the `highlights` color.
+    Duotone { shadows: [u8; 3], highlights: [u8; 3] },
+    /// Corners darkened by `strength` (0.0 - 1.0), from `radius` (relative to half of the diagonal) outwards.
+    Vignette { strength: f32, radius: f32 },
+    /// Random film grain, `amount` being the deviation of the brightness (0.0 - 1.0).
+    Grain { amount: f32 },
+    /// Black dots on white as in print, on a grid of `cell` pixels turned by `angle` degrees, the area of the
+    /// dots following the darkness of the image.
+    Halftone { cell: u32, angle: f32 },
+    /// The image drawn with characters from light to dark, one for every `cell` pixels wide and twice as high,
+    /// black on white or, with `color`, in the colors of the image on black.
+    Ascii { cell: u32, color: bool },
+}
+
+pub struct Stylize {}
+
+impl Stylize {
+    /// Apply the filter, `Grain` draws from a generator seeded by `seed`, so the same image, filter and seed
+    /// always give the same output. Alpha is kept.
+    pub fn apply<P>(buf: &Image<P>, style: &Style, seed: u64) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        if buf.width() == 0 || buf.height() == 0 {
+            return buf.clone();
+        }
+
+        match *style {
+            Style::Oil { radius } => Self::oil(buf, radius),
+            Style::Sketch { sigma } => Self::sketch(buf, sigma),
+            Style::Cartoon { levels, edges } => Self::cartoon(buf, levels, edges),
+            Style::Posterize { levels } => map_pixels(buf, |_, _, [r, g, b, a]| {
+                let [r, g, b] = [r, g, b].map(|c| posterize(c, levels));
+                [r, g, b, a]
+            }),
+            Style::Sepia { strength } => {
+                let strength = strength.clamp(0.0, 1.0);
+                map_pixels(buf, |_, _, [r, g, b, a]| {
+                    let sepia = [
+                        0.393 * r + 0.769 * g + 0.189 * b,
+                        0.349 * r + 0.686 * g + 0.168 * b,
+                        0.272 * r + 0.534 * g + 0.131 * b,
+                    ];
+                    let [r, g, b] = [(r, sepia[0]), (g, sepia[1]), (b, sepia[2])].map(|(c, s)| c + (s.min(1.0) - c) * strength);
+                    [r, g, b, a]
+                })
+            }
+            Style::Duotone { shadows, highlights } => map_pixels(buf, |_, _, [r, g, b, a]| {
+                let l = luminance([r, g, b]);
+                let [r, g, b] = [0, 1, 2].map(|c| (shadows[c] as f32 + (highlights[c] as f32 - shadows[c] as f32) * l) / 255.0);
+                [r, g, b, a]
+            }),
+            Style::Vignette { strength, radius } => {
+                let (cx, cy) = (buf.width() as f32 / 2.0, buf.height() as f32 / 2.0);
+                let half_diagonal = cx.hypot(cy);
+                let (strength, radius) = (strength.clamp(0.0, 1.0), radius.clamp(0.0, 1.0));
+                map_pixels(buf, |x, y, [r, g, b, a]| {
+                    let distance = (x as f32 + 0.5 - cx).hypot(y as f32 + 0.5 - cy) / half_diagonal;
+                    let t = ((distance - radius) / (1.0 - radius).max(f32::EPSILON)).clamp(0.0, 1.0);
+                    // smoothstep, so that the darkening starts without a visible edge
+                    let factor = 1.0 - strength * t * t * (3.0 - 2.0 * t);
+                    [r * factor, g * factor, b * factor, a]
+                })
+            }
+            Style::Grain { amount } => Self::grain(buf, amount, seed),
+            Style::Halftone { cell, angle } => Self::halftone(buf, cell, angle),
+            Style::Ascii { cell, color } => Self::ascii(buf, cell, color),
+        }
+    }
+
+    pub fn oil<P>(buf: &Image<P>, radius: u32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = (buf.width() as usize, buf.height() as usize);
+        let pixels = rgba_pixels(buf);
+        let luma: Vec<f32> = pixels.iter().map(|px| luminance([px[0], px[1], px[2]])).collect();
+        let colors = [0, 1, 2].map(|c| Integral::new(width, height, |i| pixels[i][c]));
+        let (lumas, squares) = (Integral::new(width, height, |i| luma[i]), Integral::new(width, height, |i| luma[i] * luma[i]));
+
+        let r = radius.max(1) as usize;
+        map_pixels(buf, |x, y, [_, _, _, a]| {
+            let (x, y) = (x as usize, y as usize);
+            let (left, top) = (x.saturating_sub(r), y.saturating_sub(r));
+            let (right, bottom) = ((x + r + 1).min(width), (y + r + 1).min(height));
+            let quadrants = [(left, top, x + 1, y + 1), (x, top, right, y + 1), (left, y, x + 1, bottom), (x, y, right, bottom)];
+
+            let (_, [r, g, b]) = quadrants
+                .iter()
+                .map(|&quadrant| {
+                    let count = ((quadrant.2 - quadrant.0) * (quadrant.3 - quadrant.1)) as f64;
+                    let mean = lumas.sum(quadrant) / count;
+                    let variance = squares.sum(quadrant) / count - mean * mean;
+                    (variance, colors.each_ref().map(|table| (table.sum(quadrant) / count) as f32))
+                })
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+                .expect("four quadrants");
+            [r, g, b, a]
+        })
+    }
+
+    pub fn sketch<P>(buf: &Image<P>, sigma: f32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = buf.dimensions();
+        let luma: Vec<f32> = rgba_pixels(buf).iter().map(|px| luminance([px[0], px[1], px[2]])).collect();
+        let negative = blur(luma.iter().map(|l| 1.0 - l).collect(), width, height, sigma);
+
+        map_pixels(buf, |x, y, [_, _, _, a]| {
+            let i = (y * width + x) as usize;
+            let dodged = (luma[i] / (1.0 - negative[i]).max(f32::EPSILON)).min(1.0);
+            [dodged, dodged, dodged, a]
+        })
+    }
+
+    pub fn cartoon<P>(buf: &Image<P>, levels: u32, edges: f32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let smoothed = Processing::denoise(
+            buf,
+            &Denoise::Bilateral {
+                radius: 4,
+                sigma_color: 40.0,
+                sigma_space: 3.0,
+            },
+        );
+        let gray = GrayImage::from_fn(buf.width(), buf.height(), |x, y| {
+            let [r, g, b, _] = rgba_of(smoothed.get_pixel(x, y));
+            Luma([(luminance([r, g, b]) * 255.0).round() as u8])
+        });
+        let gradients = sobel_gradients(&gray);
+
+        map_pixels(&smoothed, |x, y, [r, g, b, a]| {
+            if gradients.get_pixel(x, y)[0] as f32 > edges {
+                return [0.0, 0.0, 0.0, a];
+            }
+            let [r, g, b] = [r, g, b].map(|c| posterize(c, levels));
+            [r, g, b, a]
+        })
+    }
+
+    pub fn grain<P>(buf: &Image<P>, amount: f32, seed: u64) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let channels = P::CHANNEL_COUNT as usize;
+        let mut grainy = buf.clone();
+
+        grainy.par_chunks_mut(buf.width() as usize * channels).enumerate().for_each(|(y, row)| {
+            // Every row has its own generator, so the output does not depend on the thread scheduling
+            let mut rng = row_rng(seed, y);
+            for px in row.chunks_exact_mut(channels) {
+                let px = P::from_slice_mut(px);
+                let [r, g, b, a] = rgba_of(px);
+                // sum of three uniform draws, close to a normal distribution with a deviation of 1
+                let noise = (rng.gen::<f32>() + rng.gen::<f32>() + rng.gen::<f32>() - 1.5) * 2.0 * amount;
+                set_rgba(px, [r + noise, g + noise, b + noise, a]);
+            }
+        });
+
+        grainy
+    }
+
+    pub fn halftone<P>(buf: &Image<P>, cell: u32, angle: f32) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = buf.dimensions();
+        let cell = cell.max(2) as f32;
+        // brightness averaged over about a cell, read at the centers of the cells
+        let luma: Vec<f32> = rgba_pixels(buf).iter().map(|px| luminance([px[0], px[1], px[2]])).collect();
+        let luma = blur(luma, width, height, cell / 3.0);
+        let (sin, cos) = angle.to_radians().sin_cos();
+
+        map_pixels(buf, |x, y, [_, _, _, a]| {
+            let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+            // coordinates on the turned grid, and the center of the cell there
+            let (u, v) = (x * cos + y * sin, -x * sin + y * cos);
+            let (cu, cv) = (((u / cell).floor() + 0.5) * cell, ((v / cell).floor() + 0.5) * cell);
+            let (cx, cy) = (cu * cos - cv * sin, cu * sin + cv * cos);
+            let i = (cy.clamp(0.0, height as f32 - 1.0) as u32 * width + cx.clamp(0.0, width as f32 - 1.0) as u32) as usize;
+
+            let radius = cell * ((1.0 - luma[i]).clamp(0.0, 1.0) / PI).sqrt();
+            let coverage = (radius - (u - cu).hypot(v - cv) + 0.5).clamp(0.0, 1.0);
+            [1.0 - coverage, 1.0 - coverage, 1.0 - coverage, a]
+        })
+    }
+
+    pub fn ascii<P>(buf: &Image<P>, cell: u32, color: bool) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let (width, height) = buf.dimensions();
+        let text = Self::ascii_text(buf, cell, color);
+
+        // The monospace characters are about half as wide as high, so the text scaled to the image is hardly
+        // stretched
+        let style = TextStyle {
+            size: 2.0 * cell.max(2) as f32,
+            color: [255, 255, 255, 255],
+            background: Some([0, 0, 0, 255]),
+            padding: 0,
+            ..Default::default()
+        };
+        let rendered = Text::render(&text.join("\n"), &style, None);
+        let coverage = DynamicImage::ImageRgba32F(rendered).resize_exact(width, height, FilterType::Triangle).into_luma8();
+
+        map_pixels(buf, |x, y, [r, g, b, a]| {
+            let ink = coverage.get_pixel(x, y)[0] as f32 / 255.0;
+            match color {
+                true => [r * ink, g * ink, b * ink, a],
+                false => [1.0 - ink, 1.0 - ink, 1.0 - ink, a],
+            }
+        })
+    }
+
+    /// Lines of characters of the `Ascii` style, one for every `cell` pixels wide and twice as high. The
+    /// characters get denser with the darkness of the image or, with `color`, with its brightness (for text
+    /// on black).
+    pub fn ascii_text<P>(buf: &Image<P>, cell: u32, color: bool) -> Vec<String>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        const RAMP: &[u8] = b" .:-=+*#%@";
+        let (width, height) = (buf.width() as usize, buf.height() as usize);
+        let (cell_width, cell_height) = (cell.max(2) as usize, 2 * cell.max(2) as usize);
+
+        let luma: Vec<f32> = rgba_pixels(buf).iter().map(|px| luminance([px[0], px[1], px[2]])).collect();
+        let lumas = Integral::new(width, height, |i| luma[i]);
+        (0..height.div_ceil(cell_height))
+            .map(|row| {
+                (0..width.div_ceil(cell_width))
+                    .map(|column| {
+                        let (x0, y0) = (column * cell_width, row * cell_height);
+                        let (x1, y1) = ((x0 + cell_width).min(width), (y0 + cell_height).min(height));
+                        let mean = (lumas.sum((x0, y0, x1, y1)) / ((x1 - x0) * (y1 - y0)) as f64) as f32;
+                        // dense characters for the dark cells on white, for the bright ones on black
+                        let ink = if color { mean } else { 1.0 - mean };
+                        RAMP[((ink * RAMP.len() as f32) as usize).min(RAMP.len() - 1)] as char
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+// Image of the same type with every pixel set from its coordinates and its RGBA (0.0 - 1.0)
+fn map_pixels<P, F>(buf: &Image<P>, map: F) -> Image<P>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Send + Sync,
+    F: Fn(u32, u32, [f32; 4]) -> [f32; 4] + Sync,
+{
+    let channels = P::CHANNEL_COUNT as usize;
+    let mut mapped = buf.clone();
+
+    mapped.par_chunks_mut(buf.width() as usize * channels).enumerate().for_each(|(y, row)| {
+        for (x, px) in row.chunks_exact_mut(channels).enumerate() {
+            let px = P::from_slice_mut(px);
+            let rgba = map(x as u32, y as u32, rgba_of(px));
+            set_rgba(px, rgba);
+        }
+    });
+
+    mapped
+}
+
+fn rgba_pixels<P>(buf: &Image<P>) -> Vec<[f32; 4]>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Send + Sync,
+{
+    buf.par_chunks_exact(P::CHANNEL_COUNT as usize).map(|px| rgba_of(P::from_slice(px))).collect()
+}
+
+fn posterize(c: f32, levels: u32) -> f32 {
+    let steps = levels.max(2) as f32 - 1.0;
+    (c * steps).round() / steps
+}
+
+fn blur(values: Vec<f32>, width: u32, height: u32, sigma: f32) -> Vec<f32> {
+    let buf: ImageBuffer<Luma<f32>, Vec<f32>> = ImageBuffer::from_raw(width, height, values).expect("one value per pixel");
+    gaussian_blur(&buf, sigma).into_raw()
+}
+
+// Summed area table of one value per pixel, one row and column larger than the image, to sum any
+// rectangle in constant time
+struct Integral {
+    sums: Vec<f64>,
+    stride: usize,
+}
+
+impl Integral {
+    fn new(width: usize, height: usize, value: impl Fn(usize) -> f32) -> Self {
+        let stride = width + 1;
+        let mut sums = vec![0.0; stride * (height + 1)];
+        for y in 0..height {
+            let mut row = 0.0;
+            for x in 0..width {
+                row += value(y * width + x) as f64;
+                sums[(y + 1) * stride + x + 1] = sums[y * stride + x + 1] + row;
+            }
+        }
+
+        Self { sums, stride }
+    }
+
+    // Sum over the rectangle from (x0, y0) to (x1, y1), the ends excluded
+    fn sum(&self, (x0, y0, x1, y1): (usize, usize, usize, usize)) -> f64 {
+        let at = |x: usize, y: usize| self.sums[y * self.stride + x];
+        at(x1, y1) - at(x0, y1) - at(x1, y0) + at(x0, y0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    // Dark left half and light right half, split at x = 10
+    fn halves() -> RgbImage {
+        RgbImage::from_fn(20, 10, |x, _| if x < 10 { Rgb([40, 40, 40]) } else { Rgb([210, 210, 210]) })
+    }
+
+    #[test]
+    fn every_filter_keeps_alpha() {
+        let image = RgbaImage::from_fn(16, 12, |x, y| Rgba([(x * 16) as u8, (y * 20) as u8, 90, 77]));
+        let styles = [
+            Style::Oil { radius: 2 },
+            Style::Sketch { sigma: 2.0 },
+            Style::Cartoon { levels: 4, edges: 100.0 },
+            Style::Posterize { levels: 3 },
+            Style::Sepia { strength: 1.0 },
+            Style::Duotone {
+                shadows: [20, 0, 60],
+                highlights: [250, 220, 120],
+            },
+            Style::Vignette { strength: 1.0, radius: 0.2 },
+            Style::Grain { amount: 0.2 },
+            Style::Halftone { cell: 4, angle: 45.0 },
+            Style::Ascii { cell: 4, color: true },
+        ];
+
+        for style in &styles {
+            let styled = Stylize::apply(&image, style, 1);
+            assert!(styled.pixels().all(|px| px[3] == 77), "{style:?}");
+        }
+    }
+
+    #[test]
+    fn colors_are_mapped_pixel_by_pixel() {
+        let grays = RgbImage::from_fn(3, 1, |x, _| Rgb([[0, 100, 255][x as usize]; 3]));
+
+        let posterized = Stylize::apply(&grays, &Style::Posterize { levels: 2 }, 0);
+        assert_eq!(posterized.pixels().map(|px| px[0]).collect::<Vec<_>>(), vec![0, 0, 255]);
+
+        assert_eq!(Stylize::apply(&grays, &Style::Sepia { strength: 0.0 }, 0), grays);
+        assert_eq!(
+            *Stylize::apply(&grays, &Style::Sepia { strength: 1.0 }, 0).get_pixel(2, 0),
+            Rgb([255, 255, 239])
+        );
+
+        let duotone = Stylize::apply(
+            &grays,
+            &Style::Duotone {
+                shadows: [20, 0, 60],
+                highlights: [250, 220, 120],
+            },
+            0,
+        );
+        assert_eq!((duotone.get_pixel(0, 0), duotone.get_pixel(2, 0)), (&Rgb([20, 0, 60]), &Rgb([250, 220, 120])));
+    }
+
+    #[test]
+    fn vignette_darkens_the_corners_only() {
+        let white = RgbImage::from_pixel(21, 21, Rgb([255, 255, 255]));
+        let vignetted = Stylize::apply(&white, &Style::Vignette { strength: 1.0, radius: 0.5 }, 0);
+
+        assert_eq!(*vignetted.get_pixel(10, 10), Rgb([255, 255, 255]));
+        assert_eq!(*vignetted.get_pixel(10, 5), Rgb([255, 255, 255]));
+        assert!(vignetted.get_pixel(0, 0)[0] < 20);
+    }
+
+    #[test]
+    fn grain_follows_the_seed_and_keeps_the_brightness() {
+        let gray = RgbImage::from_pixel(64, 64, Rgb([128, 128, 128]));
+        let grainy = Stylize::grain(&gray, 0.1, 5);
+
+        assert_eq!(Stylize::grain(&gray, 0.1, 5), grainy);
+        assert_ne!(Stylize::grain(&gray, 0.1, 6), grainy);
+        assert!(grainy.pixels().any(|px| px[0] != 128));
+        let mean = grainy.pixels().map(|px| px[0] as f32).sum::<f32>() / (64.0 * 64.0);
+        assert!((mean - 128.0).abs() < 2.0, "mean {mean}");
+    }
+
+    #[test]
+    fn flat_areas_stay_flat_and_edges_are_kept() {
+        let oil = Stylize::oil(&halves(), 3);
+        assert_eq!(oil, halves());
+
+        let sketch = Stylize::sketch(&halves(), 2.0);
+        assert_eq!(
+            (sketch.get_pixel(0, 5), sketch.get_pixel(19, 5)),
+            (&Rgb([255, 255, 255]), &Rgb([255, 255, 255]))
+        );
+        assert!(sketch.get_pixel(9, 5)[0] < 200);
+
+        let cartoon = Stylize::cartoon(&halves(), 2, 100.0);
+        assert_eq!((cartoon.get_pixel(15, 5), cartoon.get_pixel(10, 5)), (&Rgb([255, 255, 255]), &Rgb([0, 0, 0])));
+    }
+
+    #[test]
+    fn halftone_dots_follow_the_darkness() {
+        let white = RgbImage::from_pixel(16, 16, Rgb([255, 255, 255]));
+        assert_eq!(Stylize::halftone(&white, 8, 0.0), white);
+
+        let black = Stylize::halftone(&RgbImage::new(16, 16), 8, 0.0);
+        assert_eq!(*black.get_pixel(4, 4), Rgb([0, 0, 0]));
+        let ink = |image: &RgbImage| image.pixels().filter(|px| px[0] < 128).count();
+        let gray = Stylize::halftone(&RgbImage::from_pixel(16, 16, Rgb([128, 128, 128])), 8, 0.0);
+        assert!(0 < ink(&gray) && ink(&gray) < ink(&black));
+    }
+
+    #[test]
+    fn ascii_text_has_a_character_for_every_cell() {
+        let text = Stylize::ascii_text(&halves(), 3, false);
+        // 7 cells of 3 pixels across and 2 of 6 down, the last ones cut short, the fourth column half dark
+        assert_eq!(text, vec!["%%%-..."; 2]);
+    }
+}
@@ -0,0 +1,310 @@
+use image::{Pixel, Rgba, Rgba32FImage};
+use rusttype::{point, Font, Scale};
+use std::{str::FromStr, sync::OnceLock};
+
+use super::compositing::{blend_pixel, BlendMode, Compositing};
+use super::processing::Image;
+
+/// DejaVu Sans Mono, embedded so that the server renders text without any fonts installed.
+const FONT_DATA: &[u8] = include_bytes!("../../assets/fonts/DejaVuSansMono.ttf");
+
+/// The embedded font, parsed on first use.
+pub fn font() -> &'static Font<'static> {
+    static FONT: OnceLock<Font<'static>> = OnceLock::new();
+    FONT.get_or_init(|| Font::try_from_bytes(FONT_DATA).expect("embedded font is valid"))
+}
+
+/// Horizontal alignment of the lines, and of the text box within its rectangle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+impl FromStr for Align {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "left" => Ok(Align::Left),
+            "center" => Ok(Align::Center),
+            "right" => Ok(Align::Right),
+            other => anyhow::bail!("unknown alignment {other}"),
+        }
+    }
+}
+
+/// Vertical alignment of the text box within its rectangle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VAlign {
+    Top,
+    Middle,
+    Bottom,
+}
+
+impl FromStr for VAlign {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "top" => Ok(VAlign::Top),
+            "middle" => Ok(VAlign::Middle),
+            "bottom" => Ok(VAlign::Bottom),
+            other => anyhow::bail!("unknown vertical alignment {other}"),
+        }
+    }
+}
+
+/// Look of rendered text, colors are RGBA. Sizes beyond the `MAX_` limits are rendered at the limits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextStyle {
+    /// Font size in pixels.
+    pub size: f32,
+    pub color: [u8; 4],
+    /// Width of the outline in pixels, no outline when 0.
+    pub outline: u32,
+    pub outline_color: [u8; 4],
+    /// Box drawn behind the text.
+    pub background: Option<[u8; 4]>,
+    /// Space between the text and the edges of the background box, in pixels.
+    pub padding: u32,
+    pub align: Align,
+}
+
+impl TextStyle {
+    pub const MAX_SIZE: f32 = 500.0;
+    /// The outline costs the square of its width for every pixel covered by a glyph.
+    pub const MAX_OUTLINE: u32 = 16;
+    pub const MAX_PADDING: u32 = 500;
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            size: 32.0,
+            color: [255, 255, 255, 255],
+            outline: 0,
+            outline_color: [0, 0, 0, 255],
+            background: None,
+            padding: 4,
+            align: Align::Left,
+        }
+    }
+}
+
+/// Rectangle text is placed in, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextRect {
+    pub x: i64,
+    pub y: i64,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub struct Text {}
+
+impl Text {
+    /// Draw text on the image, word wrapped to the width of `rect` and aligned within it.
+    pub fn draw<P>(base: &mut Image<P>, text: &str, style: &TextStyle, rect: TextRect, valign: VAlign)
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let label = Self::render(text, style, Some(rect.width));
+
+        let free_x = rect.width as i64 - label.width() as i64;
+        let free_y = rect.height as i64 - label.height() as i64;
+        let x = match style.align {
+            Align::Left => rect.x,
+            Align::Center => rect.x + free_x / 2,
+            Align::Right => rect.x + free_x,
+        };
+        let y = match valign {
+            VAlign::Top => rect.y,
+            VAlign::Middle => rect.y + free_y / 2,
+            VAlign::Bottom => rect.y + free_y,
+        };
+
+        Compositing::draw(base, &label, x, y, 1.0, BlendMode::Normal);
+    }
+
+    /// Render text with its outline and background box into a float RGBA image just large enough for it,
+    /// wrapping the words to fit in `max_width` pixels (box included).
+    pub fn render(text: &str, style: &TextStyle, max_width: Option<u32>) -> Rgba32FImage {
+        let font = font();
+        let (outline_width, padding) = (style.outline.min(TextStyle::MAX_OUTLINE), style.padding.min(TextStyle::MAX_PADDING));
+        let scale = Scale::uniform(style.size.max(1.0).clamp(1.0, TextStyle::MAX_SIZE));
+        let v_metrics = font.v_metrics(scale);
+        let line_height = (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap).ceil();
+
+        let inset = padding + outline_width;
+        let lines = match max_width {
+            Some(max_width) => Self::wrap(font, scale, text, max_width.saturating_sub(2 * inset) as f32),
+            None => text.lines().map(str::to_string).collect(),
+        };
+
+        let widths: Vec<f32> = lines.iter().map(|line| Self::measure(font, scale, line)).collect();
+        let text_width = widths.iter().cloned().fold(0.0, f32::max).ceil() as u32;
+        let width = text_width + 2 * inset;
+        let height = (line_height * lines.len() as f32) as u32 + 2 * inset;
+
+        // Coverage of the glyphs, 0.0 - 1.0 for every pixel of the label
+        let mut coverage = vec![0.0f32; (width * height) as usize];
+        for (i, (line, line_width)) in lines.iter().zip(widths).enumerate() {
+            let offset = match style.align {
+                Align::Left => 0.0,
+                Align::Center => (text_width as f32 - line_width) / 2.0,
+                Align::Right => text_width as f32 - line_width,
+            };
+            let origin = point(inset as f32 + offset, inset as f32 + i as f32 * line_height + v_metrics.ascent);
+
+            for glyph in font.layout(line, scale, origin) {
+                if let Some(bounds) = glyph.pixel_bounding_box() {
+                    glyph.draw(|gx, gy, v| {
+                        let (x, y) = (bounds.min.x + gx as i32, bounds.min.y + gy as i32);
+                        if x >= 0 && y >= 0 && (x as u32) < width && (y as u32) < height {
+                            let covered = &mut coverage[(y as u32 * width + x as u32) as usize];
+                            *covered = covered.max(v);
+                        }
+                    });
+                }
+            }
+        }
+
+        let outline = dilate(&coverage, width, height, outline_width);
+        let normalized = |color: [u8; 4]| color.map(|c| c as f32 / 255.0);
+        let (fill, outline_color) = (normalized(style.color), normalized(style.outline_color));
+        let background = style.background.map(normalized).unwrap_or([0.0; 4]);
+
+        Rgba32FImage::from_fn(width, height, |x, y| {
+            let i = (y * width + x) as usize;
+            let mut px = background;
+            if outline_width > 0 {
+                px = blend_pixel(px, outline_color, outline[i], BlendMode::Normal);
+            }
+            Rgba(blend_pixel(px, fill, coverage[i], BlendMode::Normal))
+        })
+    }
+
+    /// Width of a single line of text in pixels.
+    pub fn measure(font: &Font, scale: Scale, line: &str) -> f32 {
+        font.layout(line, scale, point(0.0, 0.0))
+            .last()
+            .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+            .unwrap_or(0.0)
+    }
+
+    /// Greedily wrap the words of the text into lines at most `max_width` pixels wide, breaking words
+    /// which do not fit on a line by themselves. Line breaks of the text are kept.
+    pub fn wrap(font: &Font, scale: Scale, text: &str, max_width: f32) -> Vec<String> {
+        let fits = |line: &str| Self::measure(font, scale, line) <= max_width;
+        let mut lines = Vec::new();
+
+        for paragraph in text.lines() {
+            let mut line = String::new();
+            for word in paragraph.split_whitespace() {
+                let candidate = if line.is_empty() { word.to_string() } else { format!("{line} {word}") };
+                if fits(&candidate) {
+                    line = candidate;
+                    continue;
+                }
+
+                if !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                }
+                // the word alone is too long, it is split at the last character which still fits
+                for c in word.chars() {
+                    line.push(c);
+                    if !fits(&line) && line.chars().count() > 1 {
+                        line.pop();
+                        lines.push(std::mem::replace(&mut line, c.to_string()));
+                    }
+                }
+            }
+            lines.push(line);
+        }
+
+        lines
+    }
+}
+
+// Grow the coverage by `radius` pixels in every direction, taking the maximum over a disc.
+// Every covered pixel is spread over its disc, so the empty padding around the text costs nothing.
+fn dilate(coverage: &[f32], width: u32, height: u32, radius: u32) -> Vec<f32> {
+    if radius == 0 {
+        return coverage.to_vec();
+    }
+
+    let r = radius as i64;
+    let disc: Vec<(i64, i64)> = (-r..=r)
+        .flat_map(|dy| (-r..=r).map(move |dx| (dx, dy)))
+        .filter(|(dx, dy)| dx * dx + dy * dy <= r * r)
+        .collect();
+    let mut dilated = vec![0.0f32; coverage.len()];
+    for (i, &v) in coverage.iter().enumerate() {
+        if v <= 0.0 {
+            continue;
+        }
+
+        let (x, y) = ((i as u32 % width) as i64, (i as u32 / width) as i64);
+        for (dx, dy) in &disc {
+            let (tx, ty) = (x + dx, y + dy);
+            if tx >= 0 && ty >= 0 && tx < width as i64 && ty < height as i64 {
+                let target = &mut dilated[(ty * width as i64 + tx) as usize];
+                *target = target.max(v);
+            }
+        }
+    }
+
+    dilated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn font_is_parsed_once() {
+        assert!(std::ptr::eq(font(), font()));
+    }
+
+    #[test]
+    fn wrapped_lines_fit_the_width() {
+        let scale = Scale::uniform(20.0);
+        let lines = Text::wrap(font(), scale, "the quick brown fox jumps over the lazy dog", 120.0);
+
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| Text::measure(font(), scale, line) <= 120.0));
+        assert_eq!(lines.join(" "), "the quick brown fox jumps over the lazy dog");
+    }
+
+    #[test]
+    fn outline_grows_the_coverage_by_a_disc() {
+        let mut coverage = vec![0.0; 25];
+        coverage[12] = 0.5;
+        let dilated = dilate(&coverage, 5, 5, 1);
+
+        let covered: Vec<usize> = (0..25).filter(|&i| dilated[i] > 0.0).collect();
+        assert_eq!(covered, vec![7, 11, 12, 13, 17]);
+        assert_eq!(dilated[7], 0.5);
+    }
+
+    #[test]
+    fn oversized_styles_are_rendered_at_the_limits() {
+        let limits = TextStyle {
+            size: TextStyle::MAX_SIZE,
+            outline: TextStyle::MAX_OUTLINE,
+            padding: TextStyle::MAX_PADDING,
+            ..Default::default()
+        };
+        let oversized = TextStyle {
+            size: 5000.0,
+            outline: 500,
+            padding: 100_000,
+            ..Default::default()
+        };
+
+        assert_eq!(Text::render("a", &oversized, None).dimensions(), Text::render("a", &limits, None).dimensions());
+    }
+}
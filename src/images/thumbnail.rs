@@ -0,0 +1,276 @@
+use image::{imageops::FilterType, DynamicImage, GrayImage};
+use imageproc::gradients::sobel_gradients;
+use std::str::FromStr;
+
+use super::processing::ContentRect;
+
+/// Measure of how interesting the parts of an image are, used to place the thumbnails of images without
+/// faces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Saliency {
+    /// Strength of the edges, drawn to detail and texture.
+    Edges,
+    /// Variety of the brightness in small blocks, drawn to busy areas while ignoring flat gradients.
+    Entropy,
+}
+
+impl FromStr for Saliency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "edges" => Ok(Saliency::Edges),
+            "entropy" => Ok(Saliency::Entropy),
+            other => anyhow::bail!("unknown saliency {other}"),
+        }
+    }
+}
+
+/// Saliency of an image scaled down, summed so that any window of it is measured in constant time.
+pub struct SaliencyMap {
+    // summed area tables of the saliency and of the saliency weighted by x and by y, for the centroids,
+    // one row and column larger than the scaled image
+    sums: [Vec<f64>; 3],
+    width: u32,
+    height: u32,
+    // size of the pixels of the map in the pixels of the image
+    scale: f32,
+}
+
+impl SaliencyMap {
+    /// Longest side of the scaled image the saliency is measured on.
+    const SIZE: u32 = 256;
+
+    pub fn new(image: &DynamicImage, saliency: Saliency) -> Self {
+        let scale = (image.width().max(image.height()) as f32 / Self::SIZE as f32).max(1.0);
+        let (width, height) = (((image.width() as f32 / scale).round() as u32).max(1), ((image.height() as f32 / scale).round() as u32).max(1));
+        let gray = image.resize_exact(width, height, FilterType::Triangle).into_luma8();
+
+        let values = match saliency {
+            Saliency::Edges => sobel_gradients(&gray).pixels().map(|px| px[0] as f64).collect(),
+            Saliency::Entropy => block_entropy(&gray),
+        };
+
+        let stride = width as usize + 1;
+        let table = |weight: &dyn Fn(usize, usize) -> f64| {
+            let mut sums = vec![0.0; stride * (height as usize + 1)];
+            for y in 0..height as usize {
+                let mut row = 0.0;
+                for x in 0..width as usize {
+                    row += values[y * width as usize + x] * weight(x, y);
+                    sums[(y + 1) * stride + x + 1] = sums[y * stride + x + 1] + row;
+                }
+            }
+            sums
+        };
+        let sums = [table(&|_, _| 1.0), table(&|x, _| x as f64 + 0.5), table(&|_, y| y as f64 + 0.5)];
+
+        Self { sums, width, height, scale }
+    }
+
+    /// Largest window of the image with the `aspect` ratio (width / height) holding the most saliency,
+    /// centered on the saliency it holds as far as the image allows.
+    pub fn best_window(&self, image_width: u32, image_height: u32, aspect: f32) -> ContentRect {
+        let full = largest_window(image_width, image_height, aspect);
+        let (window_width, window_height) = (
+            ((full.width as f32 / self.scale).round() as u32).clamp(1, self.width),
+            ((full.height as f32 / self.scale).round() as u32).clamp(1, self.height),
+        );
+
+        let (center_x, center_y) = ((self.width - window_width) as f32 / 2.0, (self.height - window_height) as f32 / 2.0);
+        let mut best = (f64::MIN, f32::MAX, 0, 0);
+        for y in 0..=self.height - window_height {
+            for x in 0..=self.width - window_width {
+                let sum = self.sum(0, x, y, window_width, window_height);
+                let off_center = (x as f32 - center_x).hypot(y as f32 - center_y);
+                if sum > best.0 + 1e-6 || (sum >= best.0 - 1e-6 && off_center < best.1) {
+                    best = (sum, off_center, x, y);
+                }
+            }
+        }
+
+        // Any of several equally salient windows could be the best, e.g. all those holding a small object,
+        // the one centered on it is, found from the centroid in the pixels of the image
+        let (sum, x, y) = (best.0, best.2, best.3);
+        if sum <= 0.0 {
+            let image = ContentRect {
+                x: 0,
+                y: 0,
+                width: image_width,
+                height: image_height,
+            };
+            return Thumbnail::window_around(image_width, image_height, aspect, &image);
+        }
+        let centroid = |table| (self.sum(table, x, y, window_width, window_height) / sum) as f32 * self.scale;
+        let focus = ContentRect {
+            x: centroid(1).round() as u32,
+            y: centroid(2).round() as u32,
+            width: 0,
+            height: 0,
+        };
+        Thumbnail::window_around(image_width, image_height, aspect, &focus)
+    }
+
+    fn sum(&self, table: usize, x: u32, y: u32, width: u32, height: u32) -> f64 {
+        let stride = self.width as usize + 1;
+        let at = |x: u32, y: u32| self.sums[table][y as usize * stride + x as usize];
+        at(x + width, y + height) - at(x, y + height) - at(x + width, y) + at(x, y)
+    }
+}
+
+pub struct Thumbnail {}
+
+impl Thumbnail {
+    /// Largest window of the image with the `aspect` ratio (width / height) centered on the focus region,
+    /// shifted as little as needed to stay within the image.
+    pub fn window_around(image_width: u32, image_height: u32, aspect: f32, focus: &ContentRect) -> ContentRect {
+        let window = largest_window(image_width, image_height, aspect);
+        let center = |start: u32, length: u32, window: u32, limit: u32| {
+            let start = (start as f32 + length as f32 / 2.0 - window as f32 / 2.0).round().max(0.0) as u32;
+            start.min(limit - window)
+        };
+
+        ContentRect {
+            x: center(focus.x, focus.width, window.width, image_width),
+            y: center(focus.y, focus.height, window.height, image_height),
+            ..window
+        }
+    }
+
+    /// Region to center the thumbnails of the `aspect` ratio on: all the faces when they fit in the window
+    /// together, otherwise the largest one.
+    pub fn face_focus(image_width: u32, image_height: u32, aspect: f32, faces: &[ContentRect]) -> Option<ContentRect> {
+        let all = union(faces)?;
+        let window = largest_window(image_width, image_height, aspect);
+        if all.width <= window.width && all.height <= window.height {
+            return Some(all);
+        }
+
+        faces.iter().max_by_key(|face| face.width as u64 * face.height as u64).copied()
+    }
+
+    /// Crop the window out of the image and scale it to the thumbnail size.
+    pub fn render(image: &DynamicImage, window: &ContentRect, width: u32, height: u32) -> DynamicImage {
+        image.crop_imm(window.x, window.y, window.width, window.height).resize_exact(width, height, FilterType::Triangle)
+    }
+}
+
+/// Smallest rectangle holding all the regions.
+pub fn union(regions: &[ContentRect]) -> Option<ContentRect> {
+    let first = regions.first()?;
+    let (mut left, mut top, mut right, mut bottom) = (first.x, first.y, first.x + first.width, first.y + first.height);
+    for region in &regions[1..] {
+        left = left.min(region.x);
+        top = top.min(region.y);
+        right = right.max(region.x + region.width);
+        bottom = bottom.max(region.y + region.height);
+    }
+
+    Some(ContentRect {
+        x: left,
+        y: top,
+        width: right - left,
+        height: bottom - top,
+    })
+}
+
+// Largest window of the aspect ratio fitting in the image, at its top left corner
+fn largest_window(width: u32, height: u32, aspect: f32) -> ContentRect {
+    let (window_width, window_height) = if width as f32 / height as f32 > aspect {
+        (((height as f32 * aspect).round() as u32).clamp(1, width), height)
+    } else {
+        (width, ((width as f32 / aspect).round() as u32).clamp(1, height))
+    };
+
+    ContentRect {
+        x: 0,
+        y: 0,
+        width: window_width,
+        height: window_height,
+    }
+}
+
+// Shannon entropy of the brightness histogram of the 8x8 block every pixel is in
+fn block_entropy(gray: &GrayImage) -> Vec<f64> {
+    const BLOCK: u32 = 8;
+    const BINS: usize = 16;
+    let (width, height) = gray.dimensions();
+    let (blocks_x, blocks_y) = (width.div_ceil(BLOCK), height.div_ceil(BLOCK));
+
+    let mut entropies = vec![0.0; (blocks_x * blocks_y) as usize];
+    for (i, entropy) in entropies.iter_mut().enumerate() {
+        let (bx, by) = (i as u32 % blocks_x * BLOCK, i as u32 / blocks_x * BLOCK);
+        let mut histogram = [0u32; BINS];
+        let mut count = 0;
+        for y in by..(by + BLOCK).min(height) {
+            for x in bx..(bx + BLOCK).min(width) {
+                histogram[gray.get_pixel(x, y)[0] as usize * BINS / 256] += 1;
+                count += 1;
+            }
+        }
+
+        *entropy = histogram
+            .iter()
+            .filter(|&&n| n > 0)
+            .map(|&n| {
+                let p = n as f64 / count as f64;
+                -p * p.log2()
+            })
+            .sum();
+    }
+
+    (0..width * height).map(|i| entropies[((i / width / BLOCK) * blocks_x + i % width / BLOCK) as usize]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    use super::*;
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> ContentRect {
+        ContentRect { x, y, width, height }
+    }
+
+    fn tuple(rect: ContentRect) -> (u32, u32, u32, u32) {
+        (rect.x, rect.y, rect.width, rect.height)
+    }
+
+    #[test]
+    fn windows_are_centered_on_the_focus_within_the_image() {
+        assert_eq!(tuple(Thumbnail::window_around(400, 200, 1.0, &rect(150, 50, 20, 20))), (60, 0, 200, 200));
+        assert_eq!(tuple(Thumbnail::window_around(400, 200, 1.0, &rect(350, 50, 20, 20))), (200, 0, 200, 200));
+        assert_eq!(tuple(Thumbnail::window_around(400, 200, 4.0, &rect(0, 150, 10, 10))), (0, 100, 400, 100));
+    }
+
+    #[test]
+    fn faces_are_framed_together_when_they_fit() {
+        let faces = [rect(20, 20, 30, 30), rect(100, 40, 40, 40)];
+        assert_eq!(tuple(Thumbnail::face_focus(400, 200, 1.0, &faces).unwrap()), (20, 20, 120, 60));
+
+        // too far apart for a square window, the largest face is the focus
+        let faces = [rect(0, 0, 30, 30), rect(350, 100, 40, 40)];
+        assert_eq!(tuple(Thumbnail::face_focus(400, 200, 1.0, &faces).unwrap()), (350, 100, 40, 40));
+        assert!(Thumbnail::face_focus(400, 200, 1.0, &[]).is_none());
+    }
+
+    #[test]
+    fn saliency_finds_the_detail() {
+        // flat gray with a checkered patch near the right edge
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 100, |x, y| match (240..270).contains(&x) && (30..60).contains(&y) {
+            true if (x / 3 + y / 3) % 2 == 0 => Rgb([0, 0, 0]),
+            true => Rgb([255, 255, 255]),
+            false => Rgb([128, 128, 128]),
+        }));
+
+        for saliency in [Saliency::Edges, Saliency::Entropy] {
+            let window = SaliencyMap::new(&image, saliency).best_window(300, 100, 1.0);
+            assert_eq!((window.width, window.height), (100, 100));
+            assert!(window.x <= 240 && window.x + window.width >= 270, "{saliency:?}: {:?}", tuple(window));
+        }
+
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_pixel(300, 100, Rgb([128, 128, 128])));
+        assert_eq!(tuple(SaliencyMap::new(&flat, Saliency::Edges).best_window(300, 100, 1.0)), (100, 0, 100, 100));
+        assert_eq!(Thumbnail::render(&image, &rect(200, 0, 100, 100), 32, 32).dimensions(), (32, 32));
+    }
+}
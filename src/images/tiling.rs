@@ -0,0 +1,274 @@
+use image::{DynamicImage, ImageBuffer, Luma, LumaA, Pixel, Primitive, Rgb, Rgba};
+use png::{BitDepth, ColorType, Transformations};
+use std::{
+    collections::VecDeque,
+    io::{Cursor, Read, Write},
+    time::Instant,
+};
+
+use super::conversion::Conversion;
+use super::processing::{Denoise, Image, Processing};
+use crate::dynamic_map;
+
+/// How far the output pixels of an operation look into the input, which decides whether it can run on
+/// strips of the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Locality {
+    /// Output pixels depend on the input within `halo` pixels of them only, the strips are processed with
+    /// that many rows of the neighbouring strips above and below.
+    Local { halo: u32 },
+    /// Output pixels may depend on any part of the image (trimming, rotations, distortions around the
+    /// center, statistics of the whole image), which is processed at once.
+    Global,
+}
+
+/// Operation which can run on horizontal strips of an image, keeping their size.
+pub trait StripOperation: Sync {
+    fn locality(&self) -> Locality;
+
+    fn apply<P>(&self, strip: &Image<P>) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync;
+}
+
+/// Negative of the image, see `Processing::negative_basic`.
+pub struct Invert {}
+
+impl StripOperation for Invert {
+    fn locality(&self) -> Locality {
+        Locality::Local { halo: 0 }
+    }
+
+    fn apply<P>(&self, strip: &Image<P>) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        let mut inverted = strip.clone();
+        Processing::negative_basic(&mut inverted);
+        inverted
+    }
+}
+
+impl StripOperation for Denoise {
+    fn locality(&self) -> Locality {
+        let halo = match *self {
+            Denoise::Median { radius } | Denoise::Bilateral { radius, .. } => radius,
+            Denoise::NonLocalMeans { patch_radius, search_radius, .. } => patch_radius + search_radius,
+        };
+        Locality::Local { halo }
+    }
+
+    fn apply<P>(&self, strip: &Image<P>) -> Image<P>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: Send + Sync,
+    {
+        Processing::denoise(strip, self)
+    }
+}
+
+pub struct Tiling {}
+
+impl Tiling {
+    /// Whether the image can be processed strip by strip by `op`, which needs a local operation and a
+    /// still, non-interlaced PNG (the format of large scans the rows of which can be decoded one by one).
+    pub fn is_streamable(data: &[u8], op: &impl StripOperation) -> bool {
+        if op.locality() == Locality::Global {
+            return false;
+        }
+
+        match png::Decoder::new(Cursor::new(data)).read_info() {
+            Ok(reader) => !reader.info().interlaced && reader.info().animation_control.is_none(),
+            Err(_) => false,
+        }
+    }
+
+    /// Process a PNG strip by strip, writing the output PNG to `output` as the strips are done. Only
+    /// `strip_height` rows and the halo of the operation are decoded at a time, so the memory used is
+    /// bounded by the width of the image and not its height. See `is_streamable` for the supported input.
+    pub fn process_png<W: Write>(data: &[u8], op: &impl StripOperation, strip_height: u32, output: W) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let Locality::Local { halo } = op.locality() else {
+            anyhow::bail!("the operation needs the whole image");
+        };
+
+        let mut decoder = png::Decoder::new(Cursor::new(data));
+        // palettes and low bit depths are expanded to 8-bit, 16-bit stays as it is
+        decoder.set_transformations(Transformations::EXPAND);
+        let mut reader = decoder.read_info()?;
+        if reader.info().interlaced {
+            anyhow::bail!("interlaced PNGs cannot be decoded row by row");
+        }
+
+        let (width, height) = (reader.info().width, reader.info().height);
+        let (color, depth) = reader.output_color_type();
+        let mut encoder = png::Encoder::new(output, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        let mut png_writer = encoder.write_header()?;
+        let mut writer = png_writer.stream_writer()?;
+
+        let mut strips = Strips {
+            reader: &mut reader,
+            writer: &mut writer,
+            strip_height: strip_height.max(1),
+            halo,
+        };
+        match (color, depth) {
+            (ColorType::Grayscale, BitDepth::Eight) => strips.run::<Luma<u8>>(op)?,
+            (ColorType::GrayscaleAlpha, BitDepth::Eight) => strips.run::<LumaA<u8>>(op)?,
+            (ColorType::Rgb, BitDepth::Eight) => strips.run::<Rgb<u8>>(op)?,
+            (ColorType::Rgba, BitDepth::Eight) => strips.run::<Rgba<u8>>(op)?,
+            (ColorType::Grayscale, BitDepth::Sixteen) => strips.run::<Luma<u16>>(op)?,
+            (ColorType::GrayscaleAlpha, BitDepth::Sixteen) => strips.run::<LumaA<u16>>(op)?,
+            (ColorType::Rgb, BitDepth::Sixteen) => strips.run::<Rgb<u16>>(op)?,
+            (ColorType::Rgba, BitDepth::Sixteen) => strips.run::<Rgba<u16>>(op)?,
+            (color, depth) => anyhow::bail!("unsupported PNG pixels {color:?} of {depth:?}"),
+        }
+        writer.finish()?;
+        png_writer.finish()?;
+
+        println!("Processed {width}x{height} image in strips of {strip_height} rows in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    /// Fallback for the images and operations which cannot be processed in strips, the whole image is
+    /// decoded and processed at once. The file name tells the formats which cannot be guessed from the data.
+    pub fn process_whole(data: &[u8], file_name: Option<&str>, op: &impl StripOperation) -> anyhow::Result<DynamicImage> {
+        let (image, _) = Conversion::decode(data, file_name)?;
+        Ok(dynamic_map!(image, buf => op.apply(buf)))
+    }
+}
+
+// Rolling window of decoded rows, from which the strips and their halos are cut
+struct Strips<'a, R: Read> {
+    reader: &'a mut png::Reader<R>,
+    writer: &'a mut dyn Write,
+    strip_height: u32,
+    halo: u32,
+}
+
+impl<R: Read> Strips<'_, R> {
+    fn run<P>(&mut self, op: &impl StripOperation) -> anyhow::Result<()>
+    where
+        P: Pixel + Send + Sync,
+        P::Subpixel: PngSample + Send + Sync,
+    {
+        let (width, height) = (self.reader.info().width, self.reader.info().height);
+        let mut rows: VecDeque<Vec<P::Subpixel>> = VecDeque::new();
+        // index of the first row in the window
+        let mut first = 0;
+        let mut bytes = Vec::new();
+
+        for top in (0..height).step_by(self.strip_height as usize) {
+            let bottom = (top + self.strip_height).min(height);
+            let (from, to) = (top.saturating_sub(self.halo), (bottom + self.halo).min(height));
+
+            while first + (rows.len() as u32) < to {
+                let row = self.reader.next_row()?.ok_or_else(|| anyhow::anyhow!("PNG ended after {} rows", first + rows.len() as u32))?;
+                rows.push_back(P::Subpixel::from_png(row.data()));
+            }
+            while first < from {
+                rows.pop_front();
+                first += 1;
+            }
+
+            let window: Image<P> = ImageBuffer::from_raw(width, to - from, rows.iter().flatten().copied().collect())
+                .ok_or_else(|| anyhow::anyhow!("decoded rows do not match the {width} pixels wide image"))?;
+            let processed = op.apply(&window);
+            if processed.dimensions() != window.dimensions() {
+                anyhow::bail!("the operation changed the size of the strip");
+            }
+
+            // Only the rows of the strip are written, the halo belongs to the neighbouring strips
+            let row_len = width as usize * P::CHANNEL_COUNT as usize;
+            let strip = &processed.as_raw()[(top - from) as usize * row_len..(bottom - from) as usize * row_len];
+            bytes.clear();
+            P::Subpixel::to_png(strip, &mut bytes);
+            self.writer.write_all(&bytes)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Subpixels as stored in the rows of a PNG, 16-bit ones in big-endian
+trait PngSample: Primitive {
+    fn from_png(bytes: &[u8]) -> Vec<Self>;
+    fn to_png(values: &[Self], bytes: &mut Vec<u8>);
+}
+
+impl PngSample for u8 {
+    fn from_png(bytes: &[u8]) -> Vec<Self> {
+        bytes.to_vec()
+    }
+
+    fn to_png(values: &[Self], bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(values);
+    }
+}
+
+impl PngSample for u16 {
+    fn from_png(bytes: &[u8]) -> Vec<Self> {
+        bytes.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()
+    }
+
+    fn to_png(values: &[Self], bytes: &mut Vec<u8>) {
+        bytes.extend(values.iter().flat_map(|value| value.to_be_bytes()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, ImageOutputFormat, RgbImage, Rgba};
+
+    use super::*;
+
+    fn encode(image: DynamicImage, format: ImageOutputFormat) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    fn in_strips(data: &[u8], op: &impl StripOperation, strip_height: u32) -> DynamicImage {
+        let mut output = Vec::new();
+        Tiling::process_png(data, op, strip_height, &mut output).unwrap();
+        image::load_from_memory(&output).unwrap()
+    }
+
+    #[test]
+    fn strips_give_the_output_of_the_whole_image() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(23, 31, |x, y| Rgb([(x * 11) as u8, (y * 7) as u8, ((x * y) % 256) as u8])));
+        let data = encode(image.clone(), ImageOutputFormat::Png);
+        let median = Denoise::Median { radius: 2 };
+
+        // strips shorter than the halo, and not dividing the height
+        for strip_height in [1, 7, 100] {
+            assert_eq!(in_strips(&data, &median, strip_height), Tiling::process_whole(&data, None, &median).unwrap());
+        }
+    }
+
+    #[test]
+    fn deep_pixels_are_streamed_as_they_are() {
+        let image = ImageBuffer::from_fn(9, 10, |x, y| Rgba([x as u16 * 5000, y as u16 * 6000, 1, 30000]));
+        let data = encode(DynamicImage::ImageRgba16(image.clone()), ImageOutputFormat::Png);
+
+        let inverted = in_strips(&data, &Invert {}, 3);
+        let mut expected = image;
+        Processing::negative_basic(&mut expected);
+        assert_eq!(inverted, DynamicImage::ImageRgba16(expected));
+    }
+
+    #[test]
+    fn only_still_pngs_are_streamed() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(8, 8));
+        let png = encode(image.clone(), ImageOutputFormat::Png);
+        let jpeg = encode(image, ImageOutputFormat::Jpeg(90));
+
+        assert!(Tiling::is_streamable(&png, &Invert {}));
+        assert!(!Tiling::is_streamable(&jpeg, &Invert {}));
+        assert_eq!(Tiling::process_whole(&jpeg, Some("scan.jpg"), &Invert {}).unwrap().dimensions(), (8, 8));
+    }
+}
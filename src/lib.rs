@@ -0,0 +1,6 @@
+pub mod camera;
+pub mod cli;
+pub mod core;
+pub mod images;
+pub mod neural;
+pub mod web;
@@ -1,18 +1,20 @@
-pub mod camera;
-pub mod core;
-pub mod images;
-pub mod neural;
-pub mod web;
-
-use neural::NeuralInferrer;
+use rust101_project::{cli, core, neural::NeuralInferrer, web::routes};
 use std::net::SocketAddr;
-use web::routes;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    core::init_thread_pool()?;
+
+    // With arguments the operations run on image files instead of being served
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args);
+    }
+
+    let hash_index = core::open_hash_index()?;
     let inferrer = NeuralInferrer::new().await?;
 
-    let routes = routes(inferrer);
+    let routes = routes(inferrer, hash_index);
     let address = SocketAddr::from(([127, 0, 0, 1], 8080));
 
     println!("->> LISTENING on {address}\n");
@@ -27,7 +27,7 @@ impl NeuralInferrer {
     pub fn infer_face(&self, image: &RgbImage) -> Vec<(Bbox, f32)> {
         let start = Instant::now();
         let model = self.model.lock().unwrap();
-        let bboxes_and_confidences = model.run(image.clone()).unwrap();
+        let bboxes_and_confidences = model.run(image).unwrap();
 
         // accepty only > 95% confidence
         let filtered: Vec<(Bbox, f32)> = bboxes_and_confidences.into_iter().filter(|(_, confidence)| *confidence > 0.95).collect();
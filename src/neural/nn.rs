@@ -21,7 +21,7 @@ const ULTRAFACE_LINK_640: &str = "https://github.com/onnx/models/raw/main/vision
 const ULTRAFACE_LINK_320: &str = "https://github.com/onnx/models/raw/main/vision/body_analysis/ultraface/models/version-RFB-320.onnx";
 
 pub trait InferModel {
-    fn run(&self, input: RgbImage) -> Result<Vec<(Bbox, f32)>, Error>;
+    fn run(&self, input: &RgbImage) -> Result<Vec<(Bbox, f32)>, Error>;
 }
 
 /// Supported variants of the Ultraface model.
@@ -66,9 +66,9 @@ impl UltrafaceModel {
     }
 
     /// Pre-process an image to be used as inference input.
-    fn preproc(&self, input: RgbImage) -> TValue {
+    fn preproc(&self, input: &RgbImage) -> TValue {
         let resized: RgbImage = image::imageops::resize(
-            &input,
+            input,
             self.width,
             self.height,
             // TODO: Test different filters
@@ -169,7 +169,7 @@ impl UltrafaceModel {
 }
 
 impl InferModel for UltrafaceModel {
-    fn run(&self, input: RgbImage) -> Result<Vec<(Bbox, f32)>, Error> {
+    fn run(&self, input: &RgbImage) -> Result<Vec<(Bbox, f32)>, Error> {
         let valid_input = tvec!(self.preproc(input));
         let raw_nn_out = self.model.run(valid_input)?;
         let selected_bboxes = self.postproc(raw_nn_out)?;
@@ -0,0 +1,22 @@
+use axum::response::{IntoResponse, Response};
+use reqwest::StatusCode;
+
+/// Error returned from the handlers, rendered as a HTTP 400 with the error message in the body,
+/// since failures come from the uploaded image or from the request parameters.
+pub struct AppError(anyhow::Error);
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        println!("Request failed: {:#}", self.0);
+        (StatusCode::BAD_REQUEST, format!("{:#}", self.0)).into_response()
+    }
+}
+
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
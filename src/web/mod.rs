@@ -1,17 +1,91 @@
+pub mod error;
 pub mod routes;
+pub mod sessions;
+
+use std::sync::{Arc, Mutex};
 
 use self::routes::*;
+use self::sessions::Sessions;
+use crate::images::hashing::HashIndex;
+use crate::images::ops::OpRegistry;
 use crate::neural::NeuralInferrer;
-use axum::{routing::post, Router};
+use axum::{
+    extract::DefaultBodyLimit,
+    routing::{get, post},
+    Router,
+};
+use axum_macros::FromRef;
+
+/// Hash index shared by the requests.
+pub type SharedHashIndex = Arc<Mutex<HashIndex>>;
+
+/// Editing sessions shared by the requests.
+pub type SharedSessions = Arc<Mutex<Sessions>>;
+
+/// Registry of the operations served at `/ops/<name>`, in pipelines and in editing sessions.
+pub type SharedOps = Arc<OpRegistry>;
+
+/// State of the server, the handlers extract the parts they need with `State<NeuralInferrer>` and such.
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub inferrer: NeuralInferrer,
+    pub hash_index: SharedHashIndex,
+    pub sessions: SharedSessions,
+    pub ops: SharedOps,
+}
+
+pub fn routes(inferrer: NeuralInferrer, hash_index: HashIndex) -> Router {
+    let state = AppState {
+        inferrer,
+        hash_index: Arc::new(Mutex::new(hash_index)),
+        sessions: Arc::new(Mutex::new(Sessions::new(SESSION_IDLE))),
+        ops: Arc::new(OpRegistry::with_builtins()),
+    };
+
+    // The idle sessions are also dropped while no requests come in to expire them
+    let sessions = state.sessions.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SESSION_IDLE / 4);
+        loop {
+            interval.tick().await;
+            if let Ok(mut sessions) = sessions.lock() {
+                sessions.expire();
+            }
+        }
+    });
 
-pub fn routes(inferrer: NeuralInferrer) -> Router {
-    Router::new()
+    let mut router = Router::new()
         .route("/detect", post(detect))
         .route("/detect-bbox", post(detect_bbox))
-        .route("/distort", post(distort))
-        .route("/invert", post(invert))
-        .route("/trim", post(trim))
+        .route("/detect-frames", post(detect_frames))
+        .route("/frames", post(frames))
+        .route("/tiled", post(tiled).layer(DefaultBodyLimit::max(TILED_UPLOAD_LIMIT)))
         .route("/rotate/:angle", post(rotate))
-        .route("/crop", post(crop))
-        .with_state(inferrer)
+        .route("/crops", post(crops))
+        .route("/overlay", post(overlay))
+        .route("/caption", post(caption))
+        .route("/collage", post(collage))
+        .route("/perspective", post(perspective))
+        .route("/autodeskew", post(autodeskew))
+        .route("/compare", post(compare))
+        .route("/hash", post(hash))
+        .route("/palette", post(palette))
+        .route("/chromakey", post(chromakey))
+        .route("/retarget", post(retarget))
+        .route("/thumbnail", post(thumbnail))
+        .route("/convert", post(convert))
+        .route("/sessions", post(create_session))
+        .route("/sessions/:id", get(session_image).delete(delete_session))
+        .route("/sessions/:id/ops", post(session_op))
+        .route("/sessions/:id/undo", post(undo_session))
+        .route("/sessions/:id/redo", post(redo_session))
+        .route("/ops", get(list_ops))
+        .route("/pipeline", post(pipeline));
+
+    // Every registered operation is served under its name and its aliases, without a handler of its own
+    for (path, name) in state.ops.routes() {
+        router = router.route(&path, op_route(name));
+    }
+
+    router.with_state(state)
 }
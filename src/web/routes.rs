@@ -1,39 +1,102 @@
 use axum::{
+    body::{self, Body, Bytes},
     extract::{Multipart, Path, Query, State},
     response::{IntoResponse, Response},
+    routing::{post, MethodRouter},
+    Json,
 };
 use axum_macros::debug_handler;
-use image::imageops::resize;
-use reqwest::{header::CONTENT_TYPE, StatusCode};
-use serde::{Deserialize};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::{imageops, imageops::FilterType, DynamicImage, ImageFormat};
+use reqwest::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::{Arc, MutexGuard},
+    time::Duration,
+};
+use tokio::runtime::Handle;
 
-use crate::images::{draw_bboxes_on_image, get_image_as_bytes, load_image_from_bytes};
+use super::error::AppError;
+use super::sessions::{Sessions, Step};
+use super::{AppState, SharedHashIndex, SharedOps, SharedSessions};
+use crate::dynamic_map;
+use crate::core::{Bbox, Detection};
+use crate::images::animation::{Animation, AnimationFormat};
+use crate::images::annotation::{Annotation, AnnotationOptions};
+use crate::images::collage::{Collage, CollageOptions, Fit, Layout};
+use crate::images::comparison::{CompareOptions, Comparison};
+use crate::images::compositing::{Anchor, BlendMode, Compositing, OverlayOptions, Position};
+use crate::images::conversion::{parse_png_compression, parse_png_filter, Conversion, Encoding, Subsampling, ToneMap};
+use crate::images::cropping::{tar_archive, Cropping};
+use crate::images::deskew::{Deskew, DeskewOptions};
+use crate::images::hashing::{HashKind, Hashes, Hashing, Match};
+use crate::images::keying::{with_alpha, Keying};
+use crate::images::palette::{Method, Palette, PaletteColor, PaletteOptions};
+use crate::images::ops::{ChromaKeyParams, CropParams, DenoiseParams, OpSchema, PipelineStep};
+use crate::images::perspective::{fit_canvas, Perspective};
+use crate::images::processing::{parse_color, BorderColor, ContentRect, TrimOptions};
+use crate::images::retarget::{protection_mask, Retarget};
+use crate::images::text::{Align, Text, TextRect, TextStyle, VAlign};
+use crate::images::thumbnail::{Saliency, SaliencyMap, Thumbnail};
+use crate::images::tiling::{Invert, StripOperation, Tiling};
+use crate::images::{get_image_as_bytes, load_animation_from_bytes, load_image_from_bytes, load_images_from_multipart, take_upload};
 use crate::{images::processing::Processing, neural::NeuralInferrer};
 
+/// Response header with the seed used by the random distortion effects.
+const SEED_HEADER: HeaderName = HeaderName::from_static("x-seed");
+
+/// Response header with the skew angle found by `/autodeskew`, in degrees.
+const SKEW_ANGLE_HEADER: HeaderName = HeaderName::from_static("x-skew-angle");
+
+/// Response header with the metrics of `/compare` when the response is an image, as `name=value` pairs.
+const METRICS_HEADER: HeaderName = HeaderName::from_static("x-metrics");
+
 #[debug_handler]
-pub async fn detect(State(inferrer): State<NeuralInferrer>, mut data: Multipart) -> Response {
-    if let Some(field) = data.next_field().await.unwrap() {
-        let buf = load_image_from_bytes(field).await.unwrap();
+pub async fn detect(
+    State(inferrer): State<NeuralInferrer>,
+    Query(params): Query<AnnotationParams>,
+    Query(output): Query<AnimationParams>,
+    mut data: Multipart,
+) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let animation = load_animation_from_bytes(field).await?;
 
-        let bboxes = inferrer.infer_face(&buf);
-        let detected = draw_bboxes_on_image(
-            resize(&buf, buf.width() / 4, buf.height() / 4, image::imageops::FilterType::Triangle),
-            bboxes,
-        );
+        // Every frame of an animation is detected on its own
+        let options = params.options()?;
+        let frames = animation.frames.len();
+        return blocking(move || {
+            let detected = animation.map(|buf| {
+                // Full resolution unless a size is asked for, the relative detections fit any size
+                let size = params.size(buf.width(), buf.height(), frames)?;
+                let detections: Vec<Detection> = inferrer.infer_face(&buf.to_rgb8()).into_iter().map(Detection::from).collect();
 
-        let bytes = get_image_as_bytes(detected).unwrap();
+                let mut detected = match size {
+                    Some((width, height)) => buf.resize_exact(width, height, FilterType::Triangle),
+                    None => buf,
+                };
+                dynamic_map!(mut detected, frame => Annotation::draw(frame, &detections, &options));
+                Ok(detected)
+            })?;
 
-        return (StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response();
+            output.respond(detected)
+        })
+        .await;
     }
 
-    (StatusCode::BAD_REQUEST).into_response()
+    Ok((StatusCode::BAD_REQUEST).into_response())
 }
 
 #[debug_handler]
 pub async fn detect_bbox(State(inferrer): State<NeuralInferrer>, mut data: Multipart) -> Response {
     if let Some(field) = data.next_field().await.unwrap() {
-        let buf = load_image_from_bytes(field).await.unwrap();
-        let bboxes = inferrer.infer_face(&buf);
+        let buf = load_image_from_bytes(field).await.unwrap().into_rgb8();
+        let bboxes = tokio::task::spawn_blocking(move || inferrer.infer_face(&buf)).await.unwrap();
 
         return (StatusCode::OK, axum::Json::from(bboxes)).into_response();
     }
@@ -41,80 +104,1338 @@ pub async fn detect_bbox(State(inferrer): State<NeuralInferrer>, mut data: Multi
     (StatusCode::BAD_REQUEST).into_response()
 }
 
+/// Faces detected in a frame, in the response of `/detect-frames`.
+#[derive(Serialize)]
+pub struct FrameDetections {
+    index: usize,
+    delay_ms: u32,
+    bboxes: Vec<(Bbox, f32)>,
+}
+
 #[debug_handler]
-pub async fn distort(mut data: Multipart) -> Response {
-    if let Some(field) = data.next_field().await.unwrap() {
-        let mut buf = load_image_from_bytes(field).await.unwrap();
+pub async fn detect_frames(State(inferrer): State<NeuralInferrer>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let animation = load_animation_from_bytes(field).await?;
 
-        Processing::wobble(&mut buf);
-        let bytes = get_image_as_bytes(buf).unwrap();
+        let detections: Vec<FrameDetections> = blocking(move || {
+            let detections = animation
+                .frames
+                .iter()
+                .enumerate()
+                .map(|(index, frame)| FrameDetections {
+                    index,
+                    delay_ms: frame.delay_ms,
+                    bboxes: inferrer.infer_face(&frame.image.to_rgb8()),
+                })
+                .collect();
+            Ok(detections)
+        })
+        .await?;
 
-        return (StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response();
+        return Ok((StatusCode::OK, Json(detections)).into_response());
     }
 
-    (StatusCode::BAD_REQUEST).into_response()
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+/// Frame of an animation, in the response of `/frames`.
+#[derive(Serialize)]
+pub struct FrameInfo {
+    index: usize,
+    delay_ms: u32,
+    width: u32,
+    height: u32,
 }
 
 #[debug_handler]
-pub async fn invert(mut data: Multipart) -> Response {
-    if let Some(field) = data.next_field().await.unwrap() {
-        let mut buf = load_image_from_bytes(field).await.unwrap();
+pub async fn frames(Query(params): Query<FramesParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let mut animation = load_animation_from_bytes(field).await?;
+
+        let Some(index) = params.index else {
+            let frames: Vec<FrameInfo> = animation
+                .frames
+                .iter()
+                .enumerate()
+                .map(|(index, frame)| FrameInfo {
+                    index,
+                    delay_ms: frame.delay_ms,
+                    width: frame.image.width(),
+                    height: frame.image.height(),
+                })
+                .collect();
+            return Ok((StatusCode::OK, Json(frames)).into_response());
+        };
 
-        Processing::negative_basic(&mut buf);
-        let bytes = get_image_as_bytes(buf).unwrap();
+        if index >= animation.frames.len() {
+            return Err(anyhow::anyhow!("frame {index} does not exist, the image has {} frames", animation.frames.len()).into());
+        }
+        let frame = animation.frames.swap_remove(index).image;
+        let bytes = blocking(move || Ok(get_image_as_bytes(frame)?)).await?;
 
-        return (StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response();
+        return Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response());
     }
 
-    (StatusCode::BAD_REQUEST).into_response()
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+/// Route of a registered operation, with the parameters of the operation in the query. Every frame of an
+/// animation is processed, a dry run answers for the first one.
+pub fn op_route(name: &'static str) -> MethodRouter<AppState> {
+    post(
+        move |State(ops): State<SharedOps>,
+              Query(query): Query<HashMap<String, String>>,
+              Query(dry_run): Query<DryRunParams>,
+              Query(output): Query<AnimationParams>,
+              data: Multipart| { apply_op(ops, name, query, dry_run, output, data) },
+    )
 }
 
+async fn apply_op(
+    ops: SharedOps,
+    name: &'static str,
+    query: HashMap<String, String>,
+    dry_run: DryRunParams,
+    output: AnimationParams,
+    mut data: Multipart,
+) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let animation = load_animation_from_bytes(field).await?;
+
+        // The query also holds the output parameters, every other key has to be a parameter of the operation
+        let output_keys = [AnimationParams::KEYS.as_slice(), DryRunParams::KEYS.as_slice()].concat();
+        let pairs = query.iter().filter(|(key, _)| !output_keys.contains(&key.as_str()));
+        let mut params = ops.parse_params(name, pairs.map(|(key, value)| (key.as_str(), value.as_str())))?;
+        if dry_run.dry_run.unwrap_or(false) {
+            let rect = blocking(move || Ok(ops.dry_run(name, &animation.frames[0].image, &params)?)).await?;
+            return Ok((StatusCode::OK, Json(rect)).into_response());
+        }
+        if output.is_text() {
+            let text = blocking(move || Ok(ops.text(name, &animation.frames[0].image, &params)?)).await?;
+            return Ok((StatusCode::OK, [(CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response());
+        }
+        let seed = ops.seed(name, &mut params)?;
+
+        let response = blocking(move || {
+            let processed = animation.map_frames(|frames| ops.apply_frames(name, frames, &params))?;
+            output.respond(processed)
+        })
+        .await?;
+        return Ok(with_seeds(response, seed));
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+/// Names, descriptions and parameters of the registered operations.
 #[debug_handler]
-pub async fn trim(mut data: Multipart) -> Response {
-    if let Some(field) = data.next_field().await.unwrap() {
-        let buf = load_image_from_bytes(field).await.unwrap();
+pub async fn list_ops(State(ops): State<SharedOps>) -> Json<Vec<OpSchema>> {
+    Json(ops.schemas())
+}
 
-        let trimmed = Processing::remove_borders(&buf).unwrap();
-        let bytes = get_image_as_bytes(trimmed).unwrap();
+#[debug_handler]
+pub async fn pipeline(State(ops): State<SharedOps>, Query(output): Query<AnimationParams>, mut data: Multipart) -> Result<Response, AppError> {
+    let (mut animation, mut steps) = (None, None);
+    while let Some(field) = data.next_field().await? {
+        match field.name() {
+            Some("steps") => steps = Some(serde_json::from_str::<Vec<PipelineStep>>(&field.text().await?)?),
+            _ => animation = Some(load_animation_from_bytes(field).await?),
+        }
+    }
+    let (Some(animation), Some(mut steps)) = (animation, steps) else {
+        return Err(anyhow::anyhow!("an image and the `steps` of the pipeline are needed").into());
+    };
+    let seeds = ops.seed_pipeline(&mut steps)?;
+
+    let response = blocking(move || {
+        let processed = animation.map_frames(|frames| ops.run_pipeline(frames, &steps))?;
+        output.respond(processed)
+    })
+    .await?;
+    Ok(with_seeds(response, seeds.into_iter().flatten()))
+}
 
-        return (StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response();
+// Run the processing of a request on a blocking thread, so that the CPU-bound work does not hold up the
+// other requests served by the threads of the async runtime
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> Result<T, AppError> + Send + 'static) -> Result<T, AppError> {
+    tokio::task::spawn_blocking(work).await?
+}
+
+// Report the seeds of the random operations in their order, comma separated, so that the output can be reproduced
+fn with_seeds(mut response: Response, seeds: impl IntoIterator<Item = u64>) -> Response {
+    let seeds: Vec<String> = seeds.into_iter().map(|seed| seed.to_string()).collect();
+    if seeds.is_empty() {
+        return response;
     }
 
-    (StatusCode::BAD_REQUEST).into_response()
+    if let Ok(value) = HeaderValue::from_str(&seeds.join(",")) {
+        response.headers_mut().insert(SEED_HEADER, value);
+    }
+    response
 }
 
+/// Largest upload of `/tiled`, which takes scans far larger than the default request limit.
+pub const TILED_UPLOAD_LIMIT: usize = 1 << 30;
+
 #[debug_handler]
-pub async fn rotate(Path(angle): Path<f32>, mut data: Multipart) -> Response {
-    while let Some(field) = data.next_field().await.unwrap() {
-        let buf = load_image_from_bytes(field).await.unwrap();
+pub async fn tiled(Query(params): Query<TiledParams>, Query(denoise): Query<DenoiseParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let file_name = field.file_name().map(str::to_string);
+        let bytes = field.bytes().await?;
+        let strip_height = params.strip.unwrap_or(256).clamp(1, TiledParams::MAX_STRIP);
 
-        let rotated = Processing::rotate(&buf, angle);
-        let bytes = get_image_as_bytes(rotated).unwrap();
+        return match params.op.as_deref().unwrap_or("invert") {
+            "invert" => stream_strips(bytes, file_name, Invert {}, strip_height).await,
+            "denoise" => stream_strips(bytes, file_name, denoise.filter()?, strip_height).await,
+            other => Err(anyhow::anyhow!("unknown tiled operation {other}").into()),
+        };
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
 
-        return (StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response();
+// The strips are processed on a blocking thread and sent as the body of the response while the next ones
+// are done, images which cannot be split are processed whole
+async fn stream_strips<O: StripOperation + Send + 'static>(data: Bytes, file_name: Option<String>, op: O, strip_height: u32) -> Result<Response, AppError> {
+    if !Tiling::is_streamable(&data, &op) {
+        println!("Processing the whole image, it cannot be split into strips");
+        let bytes = blocking(move || Ok(get_image_as_bytes(Tiling::process_whole(&data, file_name.as_deref(), &op)?)?)).await?;
+        return Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response());
     }
 
-    (StatusCode::BAD_REQUEST).into_response()
+    let (sender, body) = Body::channel();
+    let mut output = BodyWriter {
+        sender,
+        runtime: Handle::current(),
+        buffer: Vec::with_capacity(BodyWriter::CHUNK_SIZE),
+    };
+    tokio::task::spawn_blocking(move || {
+        // The status is already sent, a failure can only cut the body short
+        if let Err(err) = Tiling::process_png(&data, &op, strip_height, &mut output).and_then(|_| Ok(output.flush()?)) {
+            println!("Streaming the strips failed: {err:#}");
+            output.sender.abort();
+        }
+    });
+
+    Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], body::boxed(body)).into_response())
+}
+
+// Writer sending what is written to the body of a response in chunks, from outside of the async runtime
+struct BodyWriter {
+    sender: hyper::body::Sender,
+    runtime: Handle,
+    buffer: Vec<u8>,
+}
+
+impl BodyWriter {
+    const CHUNK_SIZE: usize = 64 * 1024;
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= Self::CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = Bytes::from(std::mem::replace(&mut self.buffer, Vec::with_capacity(Self::CHUNK_SIZE)));
+        self.runtime.block_on(self.sender.send_data(chunk)).map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))
+    }
 }
 
 #[debug_handler]
-pub async fn crop(Query(params): Query<CropParams>, mut data: Multipart) -> Response {
-    while let Some(field) = data.next_field().await.unwrap() {
-        let buf = load_image_from_bytes(field).await.unwrap();
+pub async fn autodeskew(Query(params): Query<DeskewParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let buf = load_image_from_bytes(field).await?;
 
-        let cropped = Processing::crop_image(&buf, params.x, params.y, params.w, params.h);
-        let bytes = get_image_as_bytes(cropped).unwrap();
+        let options = params.options()?;
+        let dry_run = params.dry_run.unwrap_or(false);
+        let (bytes, skew) = blocking(move || {
+            let (deskewed, skew) = dynamic_map!(ref buf, buf => Deskew::apply(buf, &options).map(|(deskewed, skew)| (DynamicImage::from(deskewed), skew)))?;
+            match dry_run {
+                true => Ok((None, skew)),
+                false => Ok((Some(get_image_as_bytes(deskewed)?), skew)),
+            }
+        })
+        .await?;
+        let Some(bytes) = bytes else {
+            return Ok((StatusCode::OK, Json(skew)).into_response());
+        };
 
-        return (StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response();
+        return Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png".to_string()), (SKEW_ANGLE_HEADER, skew.angle.to_string())], bytes).into_response());
     }
 
-    (StatusCode::BAD_REQUEST).into_response()
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+#[debug_handler]
+pub async fn rotate(Path(angle): Path<f32>, Query(output): Query<AnimationParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let animation = load_animation_from_bytes(field).await?;
+
+        return blocking(move || {
+            let rotated = animation.map(|buf| Ok(dynamic_map!(buf, buf => Processing::rotate(buf, angle))))?;
+            output.respond(rotated)
+        })
+        .await;
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+#[debug_handler]
+pub async fn overlay(Query(params): Query<OverlayParams>, mut data: Multipart) -> Result<Response, AppError> {
+    let mut uploads = load_images_from_multipart(&mut data).await?;
+    let mut base = take_upload(&mut uploads, "image")?;
+    let overlay = take_upload(&mut uploads, "overlay")?;
+
+    let options = params.options()?;
+    let bytes = blocking(move || {
+        dynamic_map!(mut base, buf => Compositing::overlay(buf, &overlay, &options))?;
+        Ok(get_image_as_bytes(base)?)
+    })
+    .await?;
+
+    Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response())
+}
+
+#[debug_handler]
+pub async fn caption(Query(params): Query<CaptionParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let mut buf = load_image_from_bytes(field).await?;
+
+        let style = params.style()?;
+        let rect = params.rect(buf.width(), buf.height());
+        let valign = params.valign.as_deref().map(str::parse::<VAlign>).transpose()?.unwrap_or(VAlign::Bottom);
+        let bytes = blocking(move || {
+            dynamic_map!(mut buf, buf => Text::draw(buf, &params.text, &style, rect, valign));
+            Ok(get_image_as_bytes(buf)?)
+        })
+        .await?;
+
+        return Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response());
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+#[debug_handler]
+pub async fn collage(Query(params): Query<CollageParams>, mut data: Multipart) -> Result<Response, AppError> {
+    let uploads = load_images_from_multipart(&mut data).await?;
+
+    let options = params.options()?;
+    let bytes = blocking(move || Ok(get_image_as_bytes(Collage::build(&uploads, &options)?)?)).await?;
+
+    Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response())
+}
+
+#[debug_handler]
+pub async fn perspective(Query(params): Query<PerspectiveParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let buf = load_image_from_bytes(field).await?;
+
+        let corners = params.corners()?;
+        let bytes = blocking(move || {
+            let warped = if params.inverse.unwrap_or(false) {
+                // Without a size the canvas is the bounding box of the quadrilateral, moved to the origin
+                let (corners, width, height) = match (params.width, params.height) {
+                    (None, None) => fit_canvas(corners),
+                    (width, height) => {
+                        let (_, fit_width, fit_height) = fit_canvas(corners);
+                        (corners, width.unwrap_or(fit_width), height.unwrap_or(fit_height))
+                    }
+                };
+                dynamic_map!(ref buf, buf => Perspective::project(buf, corners, width, height).map(DynamicImage::from))?
+            } else {
+                let size = params.width.zip(params.height);
+                dynamic_map!(ref buf, buf => Perspective::correct(buf, corners, size).map(DynamicImage::from))?
+            };
+            Ok(get_image_as_bytes(warped)?)
+        })
+        .await?;
+
+        return Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response());
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+#[debug_handler]
+pub async fn compare(Query(params): Query<CompareParams>, mut data: Multipart) -> Result<Response, AppError> {
+    let mut uploads = load_images_from_multipart(&mut data).await?;
+    let reference = take_upload(&mut uploads, "reference")?;
+    let image = take_upload(&mut uploads, "image")?;
+
+    let options = params.options();
+    blocking(move || {
+        let metrics = Comparison::metrics(&reference, &image, &options)?;
+
+        let rendered = match params.output.as_deref().unwrap_or("json") {
+            "json" => return Ok((StatusCode::OK, Json(metrics)).into_response()),
+            "heatmap" => Comparison::heatmap(&reference, &image, &options)?,
+            "side-by-side" => Comparison::side_by_side(&reference, &image, &options)?,
+            other => return Err(anyhow::anyhow!("unknown output {other}").into()),
+        };
+        let bytes = get_image_as_bytes(rendered)?;
+
+        Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png".to_string()), (METRICS_HEADER, metrics.to_string())], bytes).into_response())
+    })
+    .await
+}
+
+/// Response of `/hash`.
+#[derive(Serialize)]
+pub struct HashResponse {
+    hashes: Hashes,
+    /// registered images close to the uploaded one, closest first
+    duplicates: Vec<Match>,
+    /// id the image was registered under
+    registered: Option<String>,
+}
+
+#[debug_handler]
+pub async fn hash(State(index): State<SharedHashIndex>, Query(params): Query<HashParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let id = params.id.clone().or_else(|| field.file_name().map(str::to_string));
+        let buf = load_image_from_bytes(field).await?;
+
+        let hashes = blocking(move || Ok(Hashing::hashes(&buf))).await?;
+        let kind = params.kind.as_deref().map(str::parse::<HashKind>).transpose()?.unwrap_or(HashKind::Perceptual);
+
+        // Looked up before registering, so that the image does not find itself
+        let mut index = index.lock().map_err(|_| anyhow::anyhow!("hash index is poisoned"))?;
+        let duplicates = index.query(&hashes, kind, params.max_distance.unwrap_or(8));
+
+        let registered = match (params.register.unwrap_or(false), id) {
+            (true, Some(id)) => {
+                index.insert(&id, hashes)?;
+                Some(id)
+            }
+            (true, None) => return Err(anyhow::anyhow!("an id or a file name is needed to register the image").into()),
+            (false, _) => None,
+        };
+
+        return Ok((StatusCode::OK, Json(HashResponse { hashes, duplicates, registered })).into_response());
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+/// Palette of a detected face, in the response of `/palette`.
+#[derive(Serialize)]
+pub struct FacePalette {
+    bbox: Bbox,
+    confidence: f32,
+    palette: Vec<PaletteColor>,
+}
+
+/// Response of `/palette`.
+#[derive(Serialize)]
+pub struct PaletteResponse {
+    palette: Vec<PaletteColor>,
+    /// palettes inside of the detected faces, when asked for
+    faces: Vec<FacePalette>,
+}
+
+#[debug_handler]
+pub async fn palette(State(inferrer): State<NeuralInferrer>, Query(params): Query<PaletteParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let buf = load_image_from_bytes(field).await?;
+
+        let options = params.options()?;
+        let (width, height) = params.swatch_size()?;
+        return blocking(move || {
+            let palette = dynamic_map!(ref buf, buf => Palette::extract(buf, None, &options))?;
+
+            if params.swatch.unwrap_or(false) {
+                let swatch = Palette::render(&palette, width, height);
+                let bytes = get_image_as_bytes(swatch)?;
+                return Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response());
+            }
+
+            let mut faces = Vec::new();
+            if params.faces.unwrap_or(false) {
+                for (bbox, confidence) in inferrer.infer_face(&buf.to_rgb8()) {
+                    let region = pixel_region(&bbox, buf.width(), buf.height());
+                    let palette = dynamic_map!(ref buf, buf => Palette::extract(buf, Some(region), &options))?;
+                    faces.push(FacePalette { bbox, confidence, palette });
+                }
+            }
+
+            Ok((StatusCode::OK, Json(PaletteResponse { palette, faces })).into_response())
+        })
+        .await;
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+#[debug_handler]
+pub async fn retarget(State(inferrer): State<NeuralInferrer>, Query(params): Query<RetargetParams>, mut data: Multipart) -> Result<Response, AppError> {
+    let mut uploads = load_images_from_multipart(&mut data).await?;
+    let buf = take_upload(&mut uploads, "image")?;
+    let (width, height) = (params.width.unwrap_or(buf.width()), params.height.unwrap_or(buf.height()));
+
+    let bytes = blocking(move || {
+        // White areas of the optional mask are protected, stretched to the image if its size differs
+        let mut protect = match take_upload(&mut uploads, "mask") {
+            Ok(mask) => mask.resize_exact(buf.width(), buf.height(), FilterType::Triangle).into_luma8(),
+            Err(_) => protection_mask(buf.width(), buf.height(), &[]),
+        };
+        if params.faces.unwrap_or(false) {
+            let faces: Vec<ContentRect> = inferrer
+                .infer_face(&buf.to_rgb8())
+                .iter()
+                .map(|(bbox, _)| pixel_region(bbox, buf.width(), buf.height()))
+                .collect();
+            let faces = protection_mask(buf.width(), buf.height(), &faces);
+            imageops::overlay(&mut protect, &faces, 0, 0);
+        }
+
+        let retargeted = dynamic_map!(ref buf, buf => Retarget::seam_carve(buf, width, height, Some(&protect)).map(DynamicImage::from))?;
+        Ok(get_image_as_bytes(retargeted)?)
+    })
+    .await?;
+
+    Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response())
+}
+
+/// Thumbnail in the response of `/thumbnail` when more than one size is asked for.
+#[derive(Serialize)]
+pub struct EncodedThumbnail {
+    width: u32,
+    height: u32,
+    /// part of the image the thumbnail shows
+    window: ContentRect,
+    /// base64 encoded PNG
+    png: String,
+}
+
+/// Response of `/thumbnail` when more than one size is asked for.
+#[derive(Serialize)]
+pub struct ThumbnailResponse {
+    /// `faces` or `saliency`, what the thumbnails are centered on
+    focus: &'static str,
+    thumbnails: Vec<EncodedThumbnail>,
+}
+
+#[debug_handler]
+pub async fn thumbnail(State(inferrer): State<NeuralInferrer>, Query(params): Query<ThumbnailParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let buf = load_image_from_bytes(field).await?;
+        let sizes = params.sizes()?;
+        let (width, height) = (buf.width(), buf.height());
+
+        return blocking(move || {
+            let faces: Vec<ContentRect> = match params.faces.unwrap_or(true) {
+                true => inferrer
+                    .infer_face(&buf.to_rgb8())
+                    .iter()
+                    .map(|(bbox, _)| pixel_region(bbox, width, height))
+                    .collect(),
+                false => Vec::new(),
+            };
+            // The saliency is only measured when there are no faces to center on
+            let saliency = params.saliency.as_deref().map(str::parse::<Saliency>).transpose()?.unwrap_or(Saliency::Edges);
+            let saliency = match faces.is_empty() {
+                true => Some(SaliencyMap::new(&buf, saliency)),
+                false => None,
+            };
+
+            let thumbnails = sizes.iter().map(|&(thumbnail_width, thumbnail_height)| {
+                let aspect = thumbnail_width as f32 / thumbnail_height as f32;
+                let window = match (&saliency, Thumbnail::face_focus(width, height, aspect, &faces)) {
+                    (_, Some(focus)) => Thumbnail::window_around(width, height, aspect, &focus),
+                    (Some(saliency), None) => saliency.best_window(width, height, aspect),
+                    (None, None) => Thumbnail::window_around(width, height, aspect, &ContentRect { x: 0, y: 0, width, height }),
+                };
+                (window, Thumbnail::render(&buf, &window, thumbnail_width, thumbnail_height))
+            });
+
+            if sizes.len() == 1 {
+                let (_, thumbnail) = thumbnails.into_iter().next().ok_or_else(|| anyhow::anyhow!("no thumbnail size"))?;
+                let bytes = get_image_as_bytes(thumbnail)?;
+                return Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response());
+            }
+
+            let thumbnails = thumbnails
+                .map(|(window, thumbnail)| {
+                    Ok(EncodedThumbnail {
+                        width: thumbnail.width(),
+                        height: thumbnail.height(),
+                        window,
+                        png: STANDARD.encode(get_image_as_bytes(thumbnail)?),
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let focus = if faces.is_empty() { "saliency" } else { "faces" };
+
+            Ok((StatusCode::OK, Json(ThumbnailResponse { focus, thumbnails })).into_response())
+        })
+        .await;
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+#[debug_handler]
+pub async fn convert(Query(params): Query<ConvertParams>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let name = field.file_name().unwrap_or_default().to_string();
+        let data = field.bytes().await?;
+        let encoding = params.encoding()?;
+        let tone_map = params.tone_map()?;
+        let content_type = encoding.content_type();
+        let bytes = blocking(move || {
+            let (buf, format) = Conversion::decode(&data, Some(&name))?;
+
+            // HDR images are tone mapped unless kept in a float format
+            let buf = match (tone_map, Conversion::is_hdr(format) && encoding.format() != ImageFormat::OpenExr) {
+                (Some(operator), _) => Conversion::tone_map(&buf, operator, params.exposure.unwrap_or(0.0)),
+                (None, true) => Conversion::tone_map(&buf, ToneMap::Reinhard, params.exposure.unwrap_or(0.0)),
+                (None, false) => buf,
+            };
+
+            println!("Converting {format:?} image {name} to {encoding:?}");
+            Ok(Conversion::encode(buf, &encoding)?)
+        })
+        .await?;
+
+        return Ok((StatusCode::OK, [(CONTENT_TYPE, content_type)], bytes).into_response());
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
+}
+
+/// Several crops of one image, given as the JSON array of the parameters of `crop` in the `crops` field and
+/// encoded as for `/convert` (PNG by default), returned as a tar archive of `crop-01.png`, `crop-02.png`...
+#[debug_handler]
+pub async fn crops(Query(params): Query<ConvertParams>, mut data: Multipart) -> Result<Response, AppError> {
+    let (mut image, mut crops) = (None, None);
+    while let Some(field) = data.next_field().await? {
+        match field.name() {
+            Some("crops") => crops = Some(serde_json::from_str::<Vec<CropParams>>(&field.text().await?)?),
+            _ => image = Some(load_image_from_bytes(field).await?),
+        }
+    }
+    let (Some(image), Some(crops)) = (image, crops) else {
+        return Err(anyhow::anyhow!("an image and the `crops` to cut out of it are needed").into());
+    };
+    if crops.is_empty() {
+        return Err(anyhow::anyhow!("no crops were given").into());
+    }
+
+    // All the crops are checked before any of them is encoded
+    let specs = crops.iter().map(CropParams::spec).collect::<anyhow::Result<Vec<_>>>()?;
+    let encoding = params.encoding()?;
+    let extension = encoding.format().extensions_str()[0];
+    let archive = blocking(move || {
+        let files = specs
+            .iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                let cropped = Cropping::crop(&image, spec).map_err(|e| e.context(format!("crop {}", i + 1)))?;
+                Ok((format!("crop-{:02}.{extension}", i + 1), Conversion::encode(cropped, &encoding)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        println!("Cropped {} regions out of a {}x{} image", files.len(), image.width(), image.height());
+        Ok(tar_archive(&files)?)
+    })
+    .await?;
+
+    Ok((StatusCode::OK, [(CONTENT_TYPE, "application/x-tar")], archive).into_response())
+}
+
+/// Time after which the editing sessions nobody used expire.
+pub const SESSION_IDLE: Duration = Duration::from_secs(30 * 60);
+
+#[debug_handler]
+pub async fn create_session(State(sessions): State<SharedSessions>, mut data: Multipart) -> Result<Response, AppError> {
+    if let Some(field) = data.next_field().await? {
+        let buf = load_image_from_bytes(field).await?;
+
+        let mut sessions = lock_sessions(&sessions)?;
+        let id = sessions.create(buf)?;
+        let state = sessions.get(&id)?.state(&id);
+
+        return Ok((StatusCode::CREATED, Json(state)).into_response());
+    }
+
+    Ok((StatusCode::BAD_REQUEST).into_response())
 }
 
+/// Render the current image of a session, encoded as for `/convert` (PNG by default).
+#[debug_handler]
+pub async fn session_image(State(sessions): State<SharedSessions>, Path(id): Path<String>, Query(params): Query<ConvertParams>) -> Result<Response, AppError> {
+    let encoding = params.encoding()?;
+    let image = lock_sessions(&sessions)?.get(&id)?.current().image.clone();
+    let content_type = encoding.content_type();
+    let bytes = blocking(move || Ok(Conversion::encode(image.as_ref().clone(), &encoding)?)).await?;
+
+    Ok((StatusCode::OK, [(CONTENT_TYPE, content_type)], bytes).into_response())
+}
+
+#[debug_handler(state = AppState)]
+pub async fn session_op(
+    State(sessions): State<SharedSessions>,
+    Path(id): Path<String>,
+    State(ops): State<SharedOps>,
+    Query(params): Query<SessionOpParams>,
+    Json(op): Json<PipelineStep>,
+) -> Result<Response, AppError> {
+    let replace = params.replace.unwrap_or(false);
+
+    // The operation runs on its own copy of the image, without holding up the other sessions
+    let base = {
+        let mut sessions = lock_sessions(&sessions)?;
+        let session = sessions.get(&id)?;
+        match replace {
+            true => session.previous().ok_or_else(|| anyhow::anyhow!("the upload cannot be replaced"))?.image.clone(),
+            false => session.current().image.clone(),
+        }
+    };
+    let mut params = Value::Object(op.params);
+    let seed = ops.seed(&op.op, &mut params)?;
+    let (name, image) = (op.op.clone(), base.clone());
+    let image = blocking(move || Ok(ops.apply(&name, &image, &params)?)).await?;
+    let step = Step {
+        op: op.op,
+        image: Arc::new(image),
+    };
+
+    let mut sessions = lock_sessions(&sessions)?;
+    let session = sessions.get(&id)?;
+    let applied_to = if replace { session.previous() } else { Some(session.current()) };
+    if !applied_to.is_some_and(|applied_to| Arc::ptr_eq(&applied_to.image, &base)) {
+        return Err(anyhow::anyhow!("the session changed while the operation ran, apply it again").into());
+    }
+    match replace {
+        true => session.replace(step)?,
+        false => session.push(step),
+    }
+
+    Ok(with_seeds((StatusCode::OK, Json(session.state(&id))).into_response(), seed))
+}
+
+#[debug_handler]
+pub async fn undo_session(State(sessions): State<SharedSessions>, Path(id): Path<String>) -> Result<Response, AppError> {
+    let mut sessions = lock_sessions(&sessions)?;
+    let session = sessions.get(&id)?;
+    session.undo()?;
+
+    Ok((StatusCode::OK, Json(session.state(&id))).into_response())
+}
+
+#[debug_handler]
+pub async fn redo_session(State(sessions): State<SharedSessions>, Path(id): Path<String>) -> Result<Response, AppError> {
+    let mut sessions = lock_sessions(&sessions)?;
+    let session = sessions.get(&id)?;
+    session.redo()?;
+
+    Ok((StatusCode::OK, Json(session.state(&id))).into_response())
+}
+
+#[debug_handler]
+pub async fn delete_session(State(sessions): State<SharedSessions>, Path(id): Path<String>) -> Result<Response, AppError> {
+    lock_sessions(&sessions)?.remove(&id)?;
+
+    Ok((StatusCode::NO_CONTENT).into_response())
+}
+
+fn lock_sessions(sessions: &SharedSessions) -> anyhow::Result<MutexGuard<'_, Sessions>> {
+    sessions.lock().map_err(|_| anyhow::anyhow!("editing sessions are poisoned"))
+}
+
+#[debug_handler]
+pub async fn chromakey(Query(params): Query<ChromaKeyParams>, Query(output): Query<ChromaKeyOutputParams>, mut data: Multipart) -> Result<Response, AppError> {
+    let mut uploads = load_images_from_multipart(&mut data).await?;
+    let mut keyed = with_alpha(take_upload(&mut uploads, "image")?);
+
+    let options = params.options()?;
+    let bytes = blocking(move || {
+        dynamic_map!(mut keyed, buf => Keying::apply(buf, &options));
+
+        if output.mask.unwrap_or(false) {
+            return Ok(get_image_as_bytes(Keying::mask(&keyed))?);
+        }
+
+        // With a background the keyed image is composited over it, stretched to the same size
+        let output = match take_upload(&mut uploads, "background") {
+            Ok(background) => {
+                let mut background = background.resize_exact(keyed.width(), keyed.height(), FilterType::Triangle);
+                dynamic_map!(mut background, buf => Compositing::draw(buf, &keyed.to_rgba32f(), 0, 0, 1.0, BlendMode::Normal));
+                background
+            }
+            Err(_) => keyed,
+        };
+        Ok(get_image_as_bytes(output)?)
+    })
+    .await?;
+
+    Ok((StatusCode::OK, [(CONTENT_TYPE, "image/png")], bytes).into_response())
+}
+
+/// Query parameters of `/convert`, the encoder options apply to the formats named in their comments.
+#[derive(Deserialize)]
+pub struct ConvertParams {
+    /// extension of the output format (`png`, `jpg`, `webp`, `avif`, `gif`, `bmp`, `ico`, `tiff`, `qoi`,
+    /// `tga`, `ppm`, `ff`, `exr`), `png` by default
+    format: Option<String>,
+    /// JPEG (1 - 100, 85 by default), lossy WebP (0 - 100) and AVIF (1 - 100, 80 by default)
+    quality: Option<f32>,
+    /// JPEG, false by default
+    progressive: Option<bool>,
+    /// JPEG chroma subsampling, `444`, `422` or `420` (default)
+    subsampling: Option<String>,
+    /// PNG, `fast`, `default` or `best`
+    compression: Option<String>,
+    /// PNG, `none`, `sub`, `up`, `avg`, `paeth` or `adaptive` (default)
+    filter: Option<String>,
+    /// WebP, lossless unless a quality is given
+    lossless: Option<bool>,
+    /// AVIF, 1 (slowest, smallest) - 10, 6 by default
+    speed: Option<u8>,
+    /// `clamp`, `reinhard` or `aces`, applied to HDR and OpenEXR images (`reinhard` by default unless
+    /// converted to OpenEXR) or on demand to any image
+    tonemap: Option<String>,
+    /// exposure in stops before the tone mapping, 0.0 by default
+    exposure: Option<f32>,
+}
+
+impl ConvertParams {
+    fn encoding(&self) -> anyhow::Result<Encoding> {
+        let extension = self.format.as_deref().unwrap_or("png").trim().to_ascii_lowercase();
+        let format = ImageFormat::from_extension(&extension).ok_or_else(|| anyhow::anyhow!("unknown image format {extension}"))?;
+        if !format.can_write() {
+            anyhow::bail!("images cannot be encoded as {extension}");
+        }
+
+        let encoding = match Encoding::of(format) {
+            Encoding::Jpeg {
+                quality,
+                progressive,
+                subsampling,
+            } => Encoding::Jpeg {
+                quality: self.quality.map_or(quality, |quality| quality.clamp(1.0, 100.0).round() as u8),
+                progressive: self.progressive.unwrap_or(progressive),
+                subsampling: self.subsampling.as_deref().map(str::parse::<Subsampling>).transpose()?.unwrap_or(subsampling),
+            },
+            Encoding::Png { compression, filter } => Encoding::Png {
+                compression: self.compression.as_deref().map(parse_png_compression).transpose()?.unwrap_or(compression),
+                filter: self.filter.as_deref().map(parse_png_filter).transpose()?.unwrap_or(filter),
+            },
+            Encoding::WebP { .. } => match (self.lossless, self.quality) {
+                (Some(true), Some(_)) => anyhow::bail!("lossless WebP has no quality"),
+                (Some(true), None) | (None, None) => Encoding::WebP { quality: None },
+                (_, quality) => Encoding::WebP {
+                    quality: Some(quality.unwrap_or(75.0)),
+                },
+            },
+            Encoding::Avif { quality, speed } => Encoding::Avif {
+                quality: self.quality.unwrap_or(quality),
+                speed: self.speed.unwrap_or(speed),
+            },
+            other => other,
+        };
+
+        Ok(encoding)
+    }
+
+    fn tone_map(&self) -> anyhow::Result<Option<ToneMap>> {
+        self.tonemap.as_deref().map(str::parse).transpose()
+    }
+}
+
+/// Query parameters of `/sessions/:id/ops`, which takes a JSON body with the name of a registered operation
+/// in `op` and its parameters, e.g. `{"op": "rotate", "angle": 90}`.
+#[derive(Deserialize)]
+pub struct SessionOpParams {
+    /// replace the current step instead of adding one after it, for the changes made while a slider moves
+    replace: Option<bool>,
+}
+
+/// Query parameters of `/thumbnail`.
+#[derive(Deserialize)]
+pub struct ThumbnailParams {
+    /// comma separated `WIDTHxHEIGHT` sizes, `256x256` by default. A single size is returned as PNG,
+    /// several as JSON with the PNGs base64 encoded
+    sizes: Option<String>,
+    /// center the thumbnails on the detected faces, true by default
+    faces: Option<bool>,
+    /// `edges` or `entropy`, what the thumbnails of images without faces are centered on, `edges` by default
+    saliency: Option<String>,
+}
+
+impl ThumbnailParams {
+    const MAX_SIZES: usize = 10;
+    const MAX_SIDE: u32 = 4096;
+
+    fn sizes(&self) -> anyhow::Result<Vec<(u32, u32)>> {
+        let sizes = self
+            .sizes
+            .as_deref()
+            .unwrap_or("256x256")
+            .split(',')
+            .map(|size| {
+                let (width, height) = size.trim().split_once('x').ok_or_else(|| anyhow::anyhow!("size {size} is not WIDTHxHEIGHT"))?;
+                let (width, height): (u32, u32) = (width.parse()?, height.parse()?);
+                if width == 0 || height == 0 || width > Self::MAX_SIDE || height > Self::MAX_SIDE {
+                    anyhow::bail!("size {size} is not between 1x1 and {0}x{0}", Self::MAX_SIDE);
+                }
+                Ok((width, height))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if sizes.len() > Self::MAX_SIZES {
+            anyhow::bail!("at most {} sizes are made at once", Self::MAX_SIZES);
+        }
+        Ok(sizes)
+    }
+}
+
+/// Query parameters of `/retarget`, which takes an `image` and optionally a `mask` of the areas to protect in white.
+#[derive(Deserialize)]
+pub struct RetargetParams {
+    /// target size in pixels, by default the size of the image
+    width: Option<u32>,
+    height: Option<u32>,
+    /// protect the detected faces
+    faces: Option<bool>,
+}
+
+// Relative bounding box of a detection in pixels, clamped to the image
+fn pixel_region(bbox: &Bbox, width: u32, height: u32) -> ContentRect {
+    let (w, h) = (width as f32, height as f32);
+    let (x0, y0) = ((bbox[0] * w).clamp(0.0, w) as u32, (bbox[1] * h).clamp(0.0, h) as u32);
+    let (x1, y1) = ((bbox[2] * w).clamp(0.0, w).ceil() as u32, (bbox[3] * h).clamp(0.0, h).ceil() as u32);
+
+    ContentRect {
+        x: x0,
+        y: y0,
+        width: x1.saturating_sub(x0),
+        height: y1.saturating_sub(y0),
+    }
+}
+
+/// Query parameters of the routes of the operations next to their own, see `ImageOp::dry_run`.
+#[derive(Deserialize)]
+pub struct DryRunParams {
+    /// return only what the operation would do as JSON, e.g. the content rectangle of `trim`
+    dry_run: Option<bool>,
+}
+
+impl DryRunParams {
+    /// Names of the fields, told apart from the parameters of the operations in the same query.
+    pub const KEYS: [&'static str; 1] = ["dry_run"];
+}
+
+/// Query parameters of `/chromakey` next to those of the `chromakey` operation. It takes the image in the
+/// `image` field and an optional background in the `background` field (or the first and second field).
+#[derive(Deserialize)]
+pub struct ChromaKeyOutputParams {
+    /// return only the alpha mask as a grayscale image
+    mask: Option<bool>,
+}
+
+/// Query parameters of `/tiled`, which also takes the parameters of `/denoise`.
+#[derive(Deserialize)]
+pub struct TiledParams {
+    /// `invert` or `denoise`
+    op: Option<String>,
+    /// rows processed at a time, 256 by default and at most 4096
+    strip: Option<u32>,
+}
+
+impl TiledParams {
+    // Taller strips would hold most of a large image in memory at once
+    const MAX_STRIP: u32 = 4096;
+}
+
+/// Query parameters of `/overlay`, which takes the base image in the `image` field and the overlay in the
+/// `overlay` field (or the first and second field). See `OverlayOptions` for the defaults.
+#[derive(Deserialize)]
+pub struct OverlayParams {
+    /// `top-left`, `top`, `top-right`, `left`, `center`, `right`, `bottom-left`, `bottom` or `bottom-right`
+    anchor: Option<String>,
+    /// exact position of the top left corner, overrides the anchor
+    x: Option<i64>,
+    y: Option<i64>,
+    margin: Option<u32>,
+    scale: Option<f32>,
+    opacity: Option<f32>,
+    /// degrees
+    rotation: Option<f32>,
+    /// repeat the overlay over the whole image
+    tile: Option<bool>,
+    /// spacing between the tiles in pixels
+    spacing: Option<u32>,
+    /// `normal`, `multiply`, `screen`, `overlay`, `darken`, `lighten`, `add` or `difference`
+    blend: Option<String>,
+}
+
+impl OverlayParams {
+    fn options(&self) -> anyhow::Result<OverlayOptions> {
+        let defaults = OverlayOptions::default();
+
+        let position = match (self.x, self.y, &self.anchor) {
+            (Some(x), Some(y), _) => Position::At(x, y),
+            (None, None, Some(anchor)) => Position::Anchor(anchor.parse::<Anchor>()?),
+            (None, None, None) => defaults.position,
+            _ => anyhow::bail!("both x and y are needed to position the overlay"),
+        };
+
+        Ok(OverlayOptions {
+            position,
+            margin: self.margin.unwrap_or(defaults.margin),
+            scale: self.scale.unwrap_or(defaults.scale),
+            opacity: self.opacity.unwrap_or(defaults.opacity),
+            rotation: self.rotation.unwrap_or(defaults.rotation),
+            tile: self.tile.unwrap_or(false).then(|| self.spacing.unwrap_or(0)),
+            blend: self.blend.as_deref().map(str::parse::<BlendMode>).transpose()?.unwrap_or(defaults.blend),
+        })
+    }
+}
+
+/// Query parameters of `/caption`, the text is wrapped within the rectangle given by `x`, `y`, `w` and `h`,
+/// which is the whole image without a `margin` by default. See `TextStyle` for the defaults.
+#[derive(Deserialize)]
+pub struct CaptionParams {
+    text: String,
+    /// font size in pixels, at most 500
+    size: Option<f32>,
+    /// colors are names, `#rrggbb`, `#rrggbbaa`, `r,g,b` or `r,g,b,a`
+    color: Option<String>,
+    /// outline width in pixels, at most 16
+    outline: Option<u32>,
+    outline_color: Option<String>,
+    /// color of the box behind the text, no box when missing
+    background: Option<String>,
+    /// at most 500 pixels
+    padding: Option<u32>,
+    /// `left`, `center` or `right`
+    align: Option<String>,
+    /// `top`, `middle` or `bottom` (default)
+    valign: Option<String>,
+    x: Option<i64>,
+    y: Option<i64>,
+    w: Option<u32>,
+    h: Option<u32>,
+    /// distance of the default rectangle from the image edges, 20 pixels by default
+    margin: Option<u32>,
+}
+
+impl CaptionParams {
+    fn style(&self) -> anyhow::Result<TextStyle> {
+        let defaults = TextStyle::default();
+
+        Ok(TextStyle {
+            size: self.size.unwrap_or(defaults.size),
+            color: self.color.as_deref().map(parse_color).transpose()?.unwrap_or(defaults.color),
+            outline: self.outline.unwrap_or(defaults.outline),
+            outline_color: self.outline_color.as_deref().map(parse_color).transpose()?.unwrap_or(defaults.outline_color),
+            background: self.background.as_deref().map(parse_color).transpose()?,
+            padding: self.padding.unwrap_or(defaults.padding),
+            align: self.align.as_deref().map(str::parse::<Align>).transpose()?.unwrap_or(defaults.align),
+        })
+    }
+
+    fn rect(&self, width: u32, height: u32) -> TextRect {
+        let margin = self.margin.unwrap_or(20);
+        let x = self.x.unwrap_or(margin as i64);
+        let y = self.y.unwrap_or(margin as i64);
+
+        TextRect {
+            x,
+            y,
+            width: self.w.unwrap_or_else(|| (width as i64 - x - margin as i64).max(1) as u32),
+            height: self.h.unwrap_or_else(|| (height as i64 - y - margin as i64).max(1) as u32),
+        }
+    }
+}
+
+/// Query parameters of `/detect`, see `AnnotationOptions` for the defaults. The output has the resolution
+/// of the upload, unless `width` and/or `height` (keeping the aspect ratio when only one is given) or
+/// `scale` are set.
 #[derive(Deserialize)]
-pub struct CropParams {
-    x: u32,
-    y: u32,
-    w: u32,
-    h: u32,
+pub struct AnnotationParams {
+    /// colors are names, `#rrggbb`, `#rrggbbaa`, `r,g,b` or `r,g,b,a`
+    color: Option<String>,
+    thickness: Option<u32>,
+    /// opacity of the box fill, 0.0 - 1.0
+    fill: Option<f32>,
+    /// corner radius in pixels
+    radius: Option<f32>,
+    /// label template with `{n}`, `{confidence}` and `{score}` placeholders, empty for no labels
+    label: Option<String>,
+    numbering: Option<bool>,
+    label_size: Option<f32>,
+    landmark_color: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    scale: Option<f32>,
+}
+
+impl AnnotationParams {
+    fn options(&self) -> anyhow::Result<AnnotationOptions> {
+        let defaults = AnnotationOptions::default();
+
+        Ok(AnnotationOptions {
+            color: self.color.as_deref().map(parse_color).transpose()?.unwrap_or(defaults.color),
+            thickness: self.thickness.unwrap_or(defaults.thickness),
+            fill_opacity: self.fill.unwrap_or(defaults.fill_opacity),
+            corner_radius: self.radius.unwrap_or(defaults.corner_radius),
+            label: self.label.clone().unwrap_or(defaults.label),
+            numbering: self.numbering.unwrap_or(defaults.numbering),
+            label_size: self.label_size.or(defaults.label_size),
+            landmark_color: self.landmark_color.as_deref().map(parse_color).transpose()?.unwrap_or(defaults.landmark_color),
+        })
+    }
+
+    // Output size of a frame, which for all the `frames` together may not exceed `Animation::MAX_PIXELS`
+    fn size(&self, width: u32, height: u32, frames: usize) -> anyhow::Result<Option<(u32, u32)>> {
+        let aspect = width as f64 / height as f64;
+        let size = match (self.width, self.height, self.scale) {
+            (Some(w), Some(h), _) => (w as f64, h as f64),
+            (Some(w), None, _) => (w as f64, (w as f64 / aspect).round()),
+            (None, Some(h), _) => ((h as f64 * aspect).round(), h as f64),
+            (None, None, Some(scale)) if scale.is_finite() && scale > 0.0 => ((width as f64 * scale as f64).round(), (height as f64 * scale as f64).round()),
+            (None, None, Some(scale)) => anyhow::bail!("scale has to be a positive number, got {scale}"),
+            (None, None, None) => return Ok(None),
+        };
+
+        let (width, height) = (size.0.max(1.0), size.1.max(1.0));
+        if width * height * frames as f64 > Animation::MAX_PIXELS as f64 {
+            anyhow::bail!("frames of {width}x{height} would be larger than {} pixels in all", Animation::MAX_PIXELS);
+        }
+        Ok(Some((width as u32, height as u32)))
+    }
+}
+
+/// Query parameters of `/collage`, which takes any number of images. See `CollageOptions` for the defaults.
+#[derive(Deserialize)]
+pub struct CollageParams {
+    /// `grid`, `masonry`, `feature` (3 images) or `hero`
+    layout: Option<String>,
+    /// columns of the grid (square by default) or masonry (3 by default)
+    columns: Option<u32>,
+    /// cell size in pixels
+    width: Option<u32>,
+    height: Option<u32>,
+    gutter: Option<u32>,
+    /// colors are names, `#rrggbb`, `#rrggbbaa`, `r,g,b` or `r,g,b,a`
+    background: Option<String>,
+    /// `contain`, `cover` or `stretch`
+    fit: Option<String>,
+    /// draw the file names
+    captions: Option<bool>,
+    caption_size: Option<f32>,
+}
+
+impl CollageParams {
+    fn options(&self) -> anyhow::Result<CollageOptions> {
+        let defaults = CollageOptions::default();
+
+        let layout = match self.layout.as_deref().unwrap_or("grid") {
+            "grid" => Layout::Grid { columns: self.columns },
+            "masonry" => Layout::Masonry {
+                columns: self.columns.unwrap_or(3),
+            },
+            "feature" => Layout::Feature,
+            "hero" => Layout::Hero,
+            other => anyhow::bail!("unknown layout {other}"),
+        };
+
+        Ok(CollageOptions {
+            layout,
+            cell_width: self.width.unwrap_or(defaults.cell_width),
+            cell_height: self.height.unwrap_or(defaults.cell_height),
+            gutter: self.gutter.unwrap_or(defaults.gutter),
+            background: self.background.as_deref().map(parse_color).transpose()?.unwrap_or(defaults.background),
+            fit: self.fit.as_deref().map(str::parse::<Fit>).transpose()?.unwrap_or(defaults.fit),
+            captions: self.captions.unwrap_or(defaults.captions),
+            caption_size: self.caption_size.unwrap_or(defaults.caption_size),
+        })
+    }
+}
+
+/// Query parameters of the output of the operations which process every frame of an animation. Animations
+/// are encoded in their own format by default, still images always as PNG.
+#[derive(Deserialize)]
+pub struct AnimationParams {
+    /// `gif` or `apng`, or `text` for the operations drawing the image with characters (the `ascii` filter
+    /// of `stylize`), which answer with the first frame as plain text
+    format: Option<String>,
+    /// 1 - 30, quality of the GIF palettes against the encoding speed, 10 by default
+    speed: Option<i32>,
+}
+
+impl AnimationParams {
+    /// Names of the fields, told apart from the parameters of the operations in the same query.
+    pub const KEYS: [&'static str; 2] = ["format", "speed"];
+
+    fn is_text(&self) -> bool {
+        self.format.as_deref() == Some("text")
+    }
+
+    fn respond(&self, animation: Animation) -> Result<Response, AppError> {
+        let format = self.format.as_deref().map(str::parse::<AnimationFormat>).transpose()?;
+        let (bytes, content_type) = animation.encode(format, self.speed.unwrap_or(10))?;
+
+        Ok((StatusCode::OK, [(CONTENT_TYPE, content_type)], bytes).into_response())
+    }
+}
+
+/// Query parameters of `/frames`.
+#[derive(Deserialize)]
+pub struct FramesParams {
+    /// return this frame (from 0) as PNG, instead of the list of frames as JSON
+    index: Option<usize>,
+}
+
+/// Query parameters of `/autodeskew`. See `DeskewOptions` and `TrimOptions` for the defaults.
+#[derive(Deserialize)]
+pub struct DeskewParams {
+    /// largest skew searched for, in degrees either way, at most 45
+    max_angle: Option<f32>,
+    /// resolution of the search in degrees
+    step: Option<f32>,
+    /// trim the borders after straightening, true by default
+    trim: Option<bool>,
+    /// border color as for `/trim`, `auto` by default
+    color: Option<String>,
+    tolerance: Option<f32>,
+    padding: Option<u32>,
+    /// return only the skew angle and the content rectangle as JSON
+    dry_run: Option<bool>,
+}
+
+impl DeskewParams {
+    fn options(&self) -> anyhow::Result<DeskewOptions> {
+        let defaults = DeskewOptions::default();
+        let trim = defaults.trim.clone().unwrap_or_default();
+
+        let trim = TrimOptions {
+            color: self.color.as_deref().map(str::parse::<BorderColor>).transpose()?.unwrap_or(trim.color),
+            tolerance: self.tolerance.unwrap_or(trim.tolerance),
+            padding: self.padding.unwrap_or(trim.padding),
+            ..trim
+        };
+
+        Ok(DeskewOptions {
+            max_angle: self.max_angle.unwrap_or(defaults.max_angle),
+            step: self.step.unwrap_or(defaults.step),
+            trim: self.trim.unwrap_or(true).then_some(trim),
+        })
+    }
+}
+
+/// Query parameters of `/compare`, which takes the reference image in the `reference` field and the
+/// compared image in the `image` field (or the first and second field). See `CompareOptions` for the defaults.
+#[derive(Deserialize)]
+pub struct CompareParams {
+    /// `json` (the metrics), `heatmap` or `side-by-side`
+    output: Option<String>,
+    /// color distance from which a pixel counts as changed
+    threshold: Option<f32>,
+    /// scale the image to the size of the reference instead of failing
+    resize: Option<bool>,
+}
+
+impl CompareParams {
+    fn options(&self) -> CompareOptions {
+        let defaults = CompareOptions::default();
+
+        CompareOptions {
+            threshold: self.threshold.unwrap_or(defaults.threshold),
+            resize: self.resize.unwrap_or(defaults.resize),
+        }
+    }
+}
+
+/// Query parameters of `/hash`.
+#[derive(Deserialize)]
+pub struct HashParams {
+    /// hash the near-duplicates are searched by, `ahash`, `dhash` or `phash` (default)
+    kind: Option<String>,
+    /// largest Hamming distance of a near-duplicate, 8 by default
+    max_distance: Option<u32>,
+    /// add the image to the index
+    register: Option<bool>,
+    /// id to register the image under, the file name by default
+    id: Option<String>,
+}
+
+/// Query parameters of `/palette`. See `PaletteOptions` for the defaults.
+#[derive(Deserialize)]
+pub struct PaletteParams {
+    /// number of colors
+    colors: Option<usize>,
+    /// `kmeans` or `median-cut`
+    method: Option<String>,
+    seed: Option<u64>,
+    /// return a swatch image of the palette instead of JSON
+    swatch: Option<bool>,
+    /// size of the swatch, 500x100 by default
+    width: Option<u32>,
+    height: Option<u32>,
+    /// also return the palette of every detected face
+    faces: Option<bool>,
+}
+
+impl PaletteParams {
+    const MAX_SIDE: u32 = 4096;
+
+    fn swatch_size(&self) -> anyhow::Result<(u32, u32)> {
+        let (width, height) = (self.width.unwrap_or(500), self.height.unwrap_or(100));
+        if width == 0 || height == 0 || width > Self::MAX_SIDE || height > Self::MAX_SIDE {
+            anyhow::bail!("swatch size {width}x{height} is not between 1x1 and {0}x{0}", Self::MAX_SIDE);
+        }
+        Ok((width, height))
+    }
+
+    fn options(&self) -> anyhow::Result<PaletteOptions> {
+        let defaults = PaletteOptions::default();
+
+        Ok(PaletteOptions {
+            colors: self.colors.unwrap_or(defaults.colors),
+            method: self.method.as_deref().map(str::parse::<Method>).transpose()?.unwrap_or(defaults.method),
+            seed: self.seed.unwrap_or(defaults.seed),
+            ..defaults
+        })
+    }
+}
+
+/// Query parameters of `/perspective`.
+#[derive(Deserialize)]
+pub struct PerspectiveParams {
+    /// the four corners of the quadrilateral as `x1,y1,x2,y2,x3,y3,x4,y4` in pixels, in any order
+    points: String,
+    /// size of the output, by default the size of the straightened quadrilateral or, when inverse,
+    /// the bounding box of the quadrilateral, which is then moved to the top left of the canvas
+    width: Option<u32>,
+    height: Option<u32>,
+    /// map the image onto the quadrilateral instead of straightening the quadrilateral
+    inverse: Option<bool>,
+}
+
+impl PerspectiveParams {
+    fn corners(&self) -> anyhow::Result<[[f32; 2]; 4]> {
+        let values = self.points.split(',').map(|v| v.trim().parse::<f32>()).collect::<Result<Vec<_>, _>>()?;
+        if values.len() != 8 {
+            anyhow::bail!("expected 4 points as 8 comma separated numbers, got {} numbers", values.len());
+        }
+        if values.iter().any(|v| !v.is_finite()) {
+            anyhow::bail!("the points must be finite numbers");
+        }
+
+        Ok([[values[0], values[1]], [values[2], values[3]], [values[4], values[5]], [values[6], values[7]]])
+    }
 }
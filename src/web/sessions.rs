@@ -0,0 +1,263 @@
+use image::DynamicImage;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Editing sessions of the images uploaded once and then changed step by step, each session keeping the
+/// image after every step so that the steps can be undone and redone. Sessions left idle for longer than
+/// `idle` are dropped.
+pub struct Sessions {
+    sessions: HashMap<String, Session>,
+    idle: Duration,
+}
+
+/// Image after one step of a session with the operation which made it, the steps share their images with
+/// the renders and the operations running on them.
+#[derive(Clone)]
+pub struct Step {
+    pub op: String,
+    pub image: Arc<DynamicImage>,
+}
+
+pub struct Session {
+    // the upload first, then the steps applied to it, those after `position` being the undone ones
+    history: Vec<Step>,
+    position: usize,
+    last_used: Instant,
+}
+
+/// State of a session as reported to the clients.
+#[derive(Debug, Serialize)]
+pub struct SessionState {
+    pub id: String,
+    pub width: u32,
+    pub height: u32,
+    /// operations of all the steps, starting with `upload`
+    pub history: Vec<String>,
+    /// index in `history` of the current step
+    pub position: usize,
+    pub can_undo: bool,
+    pub can_redo: bool,
+}
+
+impl Sessions {
+    /// Sessions kept at once, every step holds a full image.
+    pub const MAX_SESSIONS: usize = 64;
+    /// Steps kept in the history of a session, the upload included. Beyond it the oldest steps after the
+    /// upload are forgotten, the upload stays to start over from.
+    pub const MAX_HISTORY: usize = 32;
+
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            idle,
+        }
+    }
+
+    /// Start a session from the uploaded image, returning its id.
+    pub fn create(&mut self, image: DynamicImage) -> anyhow::Result<String> {
+        self.expire();
+        if self.sessions.len() >= Self::MAX_SESSIONS {
+            anyhow::bail!("too many editing sessions, try again later");
+        }
+
+        let id = format!("{:032x}", rand::random::<u128>());
+        let upload = Step {
+            op: "upload".to_string(),
+            image: Arc::new(image),
+        };
+        self.sessions.insert(
+            id.clone(),
+            Session {
+                history: vec![upload],
+                position: 0,
+                last_used: Instant::now(),
+            },
+        );
+
+        println!("Started editing session {id}, {} sessions open", self.sessions.len());
+        Ok(id)
+    }
+
+    /// Session of the id, which counts as a use of it.
+    pub fn get(&mut self, id: &str) -> anyhow::Result<&mut Session> {
+        self.expire();
+        let session = self.sessions.get_mut(id).ok_or_else(|| anyhow::anyhow!("no editing session {id}, it may have expired"))?;
+        session.last_used = Instant::now();
+
+        Ok(session)
+    }
+
+    pub fn remove(&mut self, id: &str) -> anyhow::Result<()> {
+        self.sessions.remove(id).map(|_| ()).ok_or_else(|| anyhow::anyhow!("no editing session {id}, it may have expired"))
+    }
+
+    /// Drop the sessions idle for too long.
+    pub fn expire(&mut self) {
+        let before = self.sessions.len();
+        self.sessions.retain(|_, session| session.last_used.elapsed() < self.idle);
+
+        if self.sessions.len() < before {
+            println!("Expired {} idle editing sessions, {} sessions open", before - self.sessions.len(), self.sessions.len());
+        }
+    }
+}
+
+impl Session {
+    /// The current step.
+    pub fn current(&self) -> &Step {
+        &self.history[self.position]
+    }
+
+    /// The step before the current one, what a step replacing the current one is applied to.
+    pub fn previous(&self) -> Option<&Step> {
+        self.position.checked_sub(1).map(|position| &self.history[position])
+    }
+
+    /// Add a step after the current one, forgetting the undone steps.
+    pub fn push(&mut self, step: Step) {
+        self.history.truncate(self.position + 1);
+        self.history.push(step);
+        if self.history.len() > Sessions::MAX_HISTORY {
+            self.history.remove(1);
+        }
+        self.position = self.history.len() - 1;
+    }
+
+    /// Replace the current step, e.g. while a slider of its operation is being dragged, which keeps the
+    /// history from filling up with every value on the way. The undone steps are forgotten.
+    pub fn replace(&mut self, step: Step) -> anyhow::Result<()> {
+        if self.position == 0 {
+            anyhow::bail!("the upload cannot be replaced");
+        }
+
+        self.history.truncate(self.position);
+        self.history.push(step);
+        Ok(())
+    }
+
+    pub fn undo(&mut self) -> anyhow::Result<()> {
+        if self.position == 0 {
+            anyhow::bail!("nothing to undo");
+        }
+
+        self.position -= 1;
+        Ok(())
+    }
+
+    pub fn redo(&mut self) -> anyhow::Result<()> {
+        if self.position + 1 >= self.history.len() {
+            anyhow::bail!("nothing to redo");
+        }
+
+        self.position += 1;
+        Ok(())
+    }
+
+    pub fn state(&self, id: &str) -> SessionState {
+        let image = &self.current().image;
+
+        SessionState {
+            id: id.to_string(),
+            width: image.width(),
+            height: image.height(),
+            history: self.history.iter().map(|step| step.op.clone()).collect(),
+            position: self.position,
+            can_undo: self.position > 0,
+            can_redo: self.position + 1 < self.history.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+
+    use super::*;
+
+    fn step(op: &str, width: u32) -> Step {
+        Step {
+            op: op.to_string(),
+            image: Arc::new(DynamicImage::ImageRgb8(RgbImage::new(width, 1))),
+        }
+    }
+
+    fn history(session: &Session) -> Vec<String> {
+        session.state("id").history
+    }
+
+    #[test]
+    fn steps_are_undone_and_redone() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let id = sessions.create(DynamicImage::ImageRgb8(RgbImage::new(1, 1))).unwrap();
+        let session = sessions.get(&id).unwrap();
+        assert!(session.undo().is_err());
+
+        session.push(step("invert", 2));
+        session.push(step("rotate", 3));
+        session.undo().unwrap();
+        assert_eq!((session.current().op.as_str(), session.previous().unwrap().op.as_str()), ("invert", "upload"));
+        session.redo().unwrap();
+        assert_eq!(session.current().image.width(), 3);
+        assert!(session.redo().is_err());
+
+        let state = session.state(&id);
+        assert_eq!((state.position, state.can_undo, state.can_redo), (2, true, false));
+    }
+
+    #[test]
+    fn new_step_forgets_the_undone_ones() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let id = sessions.create(DynamicImage::ImageRgb8(RgbImage::new(1, 1))).unwrap();
+        let session = sessions.get(&id).unwrap();
+
+        session.push(step("invert", 2));
+        session.push(step("rotate", 3));
+        session.undo().unwrap();
+        session.push(step("crop", 4));
+
+        assert_eq!(history(session), vec!["upload", "invert", "crop"]);
+        assert!(session.redo().is_err());
+    }
+
+    #[test]
+    fn replacing_keeps_the_history_short() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let id = sessions.create(DynamicImage::ImageRgb8(RgbImage::new(1, 1))).unwrap();
+        let session = sessions.get(&id).unwrap();
+        assert!(session.replace(step("rotate", 2)).is_err());
+
+        session.push(step("rotate", 2));
+        session.replace(step("rotate", 3)).unwrap();
+        assert_eq!(history(session), vec!["upload", "rotate"]);
+        assert_eq!(session.current().image.width(), 3);
+    }
+
+    #[test]
+    fn upload_outlives_the_history_limit() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let id = sessions.create(DynamicImage::ImageRgb8(RgbImage::new(1, 1))).unwrap();
+        let session = sessions.get(&id).unwrap();
+
+        for i in 0..Sessions::MAX_HISTORY + 5 {
+            session.push(step(&format!("step{i}"), 2));
+        }
+
+        let history = history(session);
+        assert_eq!(history.len(), Sessions::MAX_HISTORY);
+        assert_eq!((history[0].as_str(), history[1].as_str()), ("upload", "step6"));
+        assert_eq!(session.current().op, format!("step{}", Sessions::MAX_HISTORY + 4));
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let mut sessions = Sessions::new(Duration::ZERO);
+        let id = sessions.create(DynamicImage::ImageRgb8(RgbImage::new(1, 1))).unwrap();
+
+        assert!(sessions.get(&id).is_err());
+        assert!(sessions.remove(&id).is_err());
+    }
+}